@@ -15,12 +15,10 @@ main() {
             ;;
     esac
 
-    test -f Cargo.lock || cargo generate-lockfile
-
     # Build artifacts
     ./build.py web
 
-    cross rustc --bin transfer --target $TARGET --release -- -C lto
+    cross rustc --locked --bin transfer --target $TARGET --release -- -C lto
     mkdir -p bin
     cp target/$TARGET/release/transfer bin/
 
@@ -22,6 +22,14 @@ main() {
            --git japaric/cross \
            --tag $tag \
            --target $target
+
+    # wait for the postgres service used by the integration tests
+    for _ in $(seq 30); do
+        psql -U postgres -c 'select 1' > /dev/null && return
+        sleep 1
+    done
+    echo "postgres isn't accepting connections" >&2
+    return 1
 }
 
 main
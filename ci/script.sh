@@ -11,8 +11,10 @@ main() {
     #    return
     #fi
 
-    #cross test --target $TARGET
-    cross test --target $TARGET --release
+    # the integration tests (`tests/`) need the postgres service at TEST_DATABASE_URL,
+    # which isn't reachable from cross's containers, so they run on the host
+    cargo test --locked
+    cross test --locked --target $TARGET --release --lib --bins
 
     #cross run --target $TARGET
     #cross run --target $TARGET --release
@@ -0,0 +1,5 @@
+alter table init_upload
+  drop column date_modified;
+
+alter table init_upload
+  drop column uploaded_bytes;
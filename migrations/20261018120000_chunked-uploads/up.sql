@@ -0,0 +1,5 @@
+alter table init_upload
+  add column uploaded_bytes bigint not null default 0;
+
+alter table init_upload
+  add column date_modified timestamp with time zone not null default now();
@@ -0,0 +1,5 @@
+alter table init_download
+  drop column date_accessed;
+
+alter table init_download
+  drop column download;
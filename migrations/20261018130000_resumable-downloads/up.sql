@@ -0,0 +1,5 @@
+alter table init_download
+  add column download integer references "download" ("id") on delete set null;
+
+alter table init_download
+  add column date_accessed timestamp with time zone not null default now();
@@ -0,0 +1,2 @@
+alter table upload
+  drop column file_hash;
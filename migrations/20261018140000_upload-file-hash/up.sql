@@ -0,0 +1,2 @@
+alter table upload
+  add column file_hash bytea;
@@ -0,0 +1,23 @@
+delete from init_upload where bundle is not null;
+delete from upload where bundle is not null;
+
+alter table upload
+  drop column bundle_index;
+
+alter table upload
+  drop column bundle;
+
+alter table upload
+  alter column access_password set not null;
+
+alter table init_upload
+  drop column bundle_index;
+
+alter table init_upload
+  drop column bundle;
+
+alter table init_upload
+  alter column access_password set not null;
+
+drop table bundle_download;
+drop table bundle;
@@ -0,0 +1,34 @@
+create table bundle (
+    id                  serial primary key,
+    uuid_               uuid unique not null,
+    access_password     integer not null unique references "auth" ("id") on delete cascade,
+    deletion_password   integer unique references "auth" ("id") on delete cascade,
+    download_limit      integer,
+    expire_date         timestamp with time zone not null,
+    deleted             boolean not null default false,
+    date_created        timestamp with time zone not null default now()
+);
+
+create table bundle_download (
+    id                  serial primary key,
+    bundle              integer not null references "bundle" ("id") on delete cascade,
+    date_created        timestamp with time zone not null default now()
+);
+
+alter table init_upload
+  alter column access_password drop not null;
+
+alter table init_upload
+  add column bundle integer references "bundle" ("id") on delete cascade;
+
+alter table init_upload
+  add column bundle_index integer;
+
+alter table upload
+  alter column access_password drop not null;
+
+alter table upload
+  add column bundle integer references "bundle" ("id") on delete cascade;
+
+alter table upload
+  add column bundle_index integer;
@@ -0,0 +1,5 @@
+alter table upload
+  drop column encrypted_data_key;
+
+alter table upload
+  drop column encryption_key_id;
@@ -0,0 +1,5 @@
+alter table upload
+  add column encryption_key_id integer;
+
+alter table upload
+  add column encrypted_data_key bytea;
@@ -0,0 +1,5 @@
+alter table bundle
+  drop column failed_attempts;
+
+alter table upload
+  drop column failed_attempts;
@@ -0,0 +1,5 @@
+alter table upload
+  add column failed_attempts integer not null default 0;
+
+alter table bundle
+  add column failed_attempts integer not null default 0;
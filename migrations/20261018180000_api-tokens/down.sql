@@ -0,0 +1,7 @@
+alter table upload
+  drop column api_token;
+
+alter table init_upload
+  drop column api_token;
+
+drop table api_token;
@@ -0,0 +1,19 @@
+create table api_token (
+    id                  serial primary key,
+    name                text unique not null,
+    token_hash          bytea unique not null,
+    quota_bytes         bigint,
+    max_file_bytes      bigint,
+    max_lifespan_secs   bigint,
+    max_download_limit  integer,
+    upload_count        bigint not null default 0,
+    total_bytes         bigint not null default 0,
+    revoked             boolean not null default false,
+    date_created        timestamp with time zone not null default now()
+);
+
+alter table init_upload
+  add column api_token integer references "api_token" ("id") on delete set null;
+
+alter table upload
+  add column api_token integer references "api_token" ("id") on delete set null;
@@ -0,0 +1,13 @@
+drop table webhook_event;
+
+alter table upload
+  drop column webhook_secret;
+
+alter table upload
+  drop column webhook_url;
+
+alter table init_upload
+  drop column webhook_secret;
+
+alter table init_upload
+  drop column webhook_url;
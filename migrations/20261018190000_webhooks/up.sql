@@ -0,0 +1,28 @@
+alter table init_upload
+  add column webhook_url text;
+
+alter table init_upload
+  add column webhook_secret bytea;
+
+alter table upload
+  add column webhook_url text;
+
+alter table upload
+  add column webhook_secret bytea;
+
+create table webhook_event (
+    id                  serial primary key,
+    url                 text not null,
+    secret              bytea not null,
+    event               text not null,
+    payload             text not null,
+    attempts            integer not null default 0,
+    next_attempt        timestamp with time zone not null default now(),
+    last_error          text,
+    delivered           boolean not null default false,
+    failed              boolean not null default false,
+    date_created        timestamp with time zone not null default now()
+);
+
+create index webhook_event_pending_idx on webhook_event (next_attempt)
+  where delivered = false and failed = false;
@@ -0,0 +1,7 @@
+drop trigger audit_event_no_truncate on audit_event;
+
+drop trigger audit_event_no_modify on audit_event;
+
+drop function audit_event_append_only();
+
+drop table audit_event;
@@ -0,0 +1,29 @@
+create table audit_event (
+    id                  bigserial primary key,
+    event               text not null,
+    outcome             text not null,
+    upload              integer,
+    bundle              integer,
+    uuid_               uuid,
+    client_ip           text,
+    user_agent          text,
+    date_created        timestamp with time zone not null default now()
+);
+
+create index audit_event_date_created_idx on audit_event (date_created);
+
+create index audit_event_uuid_idx on audit_event (uuid_);
+
+create function audit_event_append_only() returns trigger as $$
+begin
+    raise exception 'audit_event is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_event_no_modify
+  before update or delete on audit_event
+  for each row execute procedure audit_event_append_only();
+
+create trigger audit_event_no_truncate
+  before truncate on audit_event
+  for each statement execute procedure audit_event_append_only();
@@ -0,0 +1,6 @@
+-- hashes made with anything other than bcrypt (cost 10) will no longer verify
+alter table auth
+  drop column params;
+
+alter table auth
+  drop column algorithm;
@@ -0,0 +1,12 @@
+-- existing hashes are bcrypt (cost 10) of the password's sha256 hash
+alter table auth
+  add column algorithm text not null default 'bcrypt';
+
+alter table auth
+  add column params text not null default 'cost=10';
+
+alter table auth
+  alter column algorithm drop default;
+
+alter table auth
+  alter column params drop default;
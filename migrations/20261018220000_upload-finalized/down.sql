@@ -0,0 +1,2 @@
+alter table upload
+  drop column finalized;
@@ -0,0 +1,6 @@
+-- existing uploads were counted towards the status totals when they were created
+alter table upload
+  add column finalized boolean not null default true;
+
+alter table upload
+  alter column finalized drop default;
@@ -1,15 +1,28 @@
 /*!
 General Admin commands
 */
+use tokio::task;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::envelope::{self, KeyRing};
 use crate::error::{self, Result};
-use crate::models::CONFIG;
+use crate::models;
+use crate::storage;
 use crate::sweep;
 
 /// Cleanup files that no longer have an associated record in the database
-pub fn sweep_files() -> Result<()> {
-    let upload_dir = CONFIG.upload_dir()?;
+pub async fn sweep_files(config: &Config) -> Result<()> {
+    let upload_dir = config.upload_dir()?;
     if upload_dir.is_dir() && upload_dir.exists() {
-        let n = sweep::sweep_fs(&upload_dir)?;
+        let storage = {
+            let config = config.clone();
+            task::spawn_blocking(move || storage::init(&config)).await??
+        };
+        let n = sweep::sweep_fs(config, &storage).await?;
         info!("** Cleaned up {} orphaned files **", n);
     } else {
         return Err(error::helpers::internal(format!(
@@ -19,3 +32,133 @@ pub fn sweep_files() -> Result<()> {
     }
     Ok(())
 }
+
+/// Verify stored upload files against the hashes recorded when they were uploaded,
+/// returning the `uuid`s of uploads whose files are missing or don't match
+pub async fn verify_files(config: &Config) -> Result<Vec<Uuid>> {
+    let conn = db::init_conn().await?;
+    let uploads = models::Upload::select_hashed(&conn).await?;
+    let config = config.clone();
+    // reading and hashing every stored file blocks
+    task::spawn_blocking(move || verify_uploads(&config, uploads)).await?
+}
+
+/// Verify the stored files of `uploads` against their recorded hashes. Blocks.
+fn verify_uploads(config: &Config, uploads: Vec<models::Upload>) -> Result<Vec<Uuid>> {
+    let storage = storage::init(config)?;
+    let keys = load_keys(config)?;
+    let mut failed = vec![];
+    for upload in uploads.into_iter() {
+        let expected = upload.file_hash.as_ref().expect("file_hash missing");
+        let hash = envelope::open(&*storage, keys.as_ref(), &upload, 0, None)
+            .and_then(auth::sha256_reader);
+        match hash {
+            Ok(ref hash) if auth::eq(hash, expected).is_ok() => (),
+            Ok(_) => {
+                error!("Hash mismatch for upload file {}", upload.file_path);
+                failed.push(upload.uuid);
+            }
+            Err(e) => {
+                error!("Error reading upload file {}, {}", upload.file_path, e);
+                failed.push(upload.uuid);
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Load the configured encryption keys, if encryption at rest is enabled
+fn load_keys(config: &Config) -> Result<Option<KeyRing>> {
+    match config.encryption_key_path()? {
+        None => Ok(None),
+        Some(path) => Ok(Some(KeyRing::load(path)?)),
+    }
+}
+
+/// Add a new encryption key, creating the key file if necessary, and re-wrap the
+/// data keys of all existing encrypted uploads with it. Returns the new key id
+/// and the number of uploads re-wrapped.
+pub async fn rotate_keys(config: &Config) -> Result<(i32, usize)> {
+    let path = config
+        .encryption_key_path()?
+        .ok_or_else(|| error::helpers::bad_request("No `encryption_key_file` configured"))?;
+    let key_id = KeyRing::add_key(&path)?;
+    let keys = KeyRing::load(&path)?;
+    let new_key = keys.get(key_id)?;
+
+    let mut conn = db::init_conn().await?;
+    let trans = conn.transaction().await?;
+    let uploads = models::Upload::select_encrypted(&trans).await?;
+    let mut count = 0;
+    for upload in uploads.into_iter() {
+        let old_id = upload.encryption_key_id.expect("encryption_key_id missing");
+        if old_id == key_id {
+            continue;
+        }
+        let wrapped = upload
+            .encrypted_data_key
+            .as_ref()
+            .expect("encrypted_data_key missing");
+        let data_key = envelope::unwrap_key(&keys.get(old_id)?, &upload.uuid, wrapped)?;
+        let rewrapped = envelope::wrap_key(&new_key, &upload.uuid, &data_key)?;
+        upload.set_encryption(&trans, key_id, &rewrapped).await?;
+        count += 1;
+    }
+    trans.commit().await?;
+    Ok((key_id, count))
+}
+
+/// Remove encryption keys that are no longer referenced by any upload,
+/// returning the number of keys removed. The newest key is always kept.
+pub async fn prune_keys(config: &Config) -> Result<usize> {
+    let path = config
+        .encryption_key_path()?
+        .ok_or_else(|| error::helpers::bad_request("No `encryption_key_file` configured"))?;
+    let conn = db::init_conn().await?;
+    let mut in_use = models::Upload::select_encrypted(&conn)
+        .await?
+        .into_iter()
+        .filter_map(|upload| upload.encryption_key_id)
+        .collect::<Vec<_>>();
+    in_use.sort();
+    in_use.dedup();
+    KeyRing::prune(&path, &in_use)
+}
+
+/// Create a new api token with the given limits, returning the
+/// token record and the hex encoded token to hand to its user
+pub async fn create_token(token: models::NewApiToken) -> Result<(models::ApiToken, String)> {
+    let conn = db::init_conn().await?;
+    let token_bytes = auth::new_api_token()?;
+    let token = models::NewApiToken {
+        token_hash: auth::sha256(&token_bytes),
+        ..token
+    }
+    .insert(&conn)
+    .await?;
+    Ok((token, hex::encode(&token_bytes)))
+}
+
+/// List all api tokens and their usage
+pub async fn list_tokens() -> Result<Vec<models::ApiToken>> {
+    let conn = db::init_conn().await?;
+    models::ApiToken::select_all(&conn).await
+}
+
+/// Revoke the api token with the given `name`, so it can no longer be used
+/// to initialize uploads
+pub async fn revoke_token(name: &str) -> Result<()> {
+    let conn = db::init_conn().await?;
+    let token = models::ApiToken::find_by_name(&conn, name).await?;
+    token.revoke(&conn).await?;
+    Ok(())
+}
+
+/// Write the audit events matching `filter` to stdout as JSON lines,
+/// returning the number written
+pub async fn export_audit(filter: &models::AuditFilter) -> Result<usize> {
+    let conn = db::init_conn().await?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    audit::export(&conn, filter, &mut out).await
+}
@@ -0,0 +1,147 @@
+/*!
+Audit log of security relevant actions
+
+Password checks, downloads, deletions, and sweeper deletions are appended to the
+`audit_event` table, in the same transaction as the action, so failed attempts are
+kept along with the failure counts they increment. The table rejects updates and
+deletes. Export it with `transfer admin audit`.
+*/
+use std::io::Write;
+use std::net::IpAddr;
+
+use deadpool_postgres::GenericClient;
+
+use crate::error::{ErrorKind, Result};
+use crate::models::{self, Lockable};
+
+/// Audited actions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Access password checked to initialize an upload's download
+    DownloadInit,
+    /// Access password checked to download an upload's bytes
+    Download,
+    /// Decrypted content hash checked to reveal an upload's file name
+    DownloadConfirm,
+    /// Deletion password checked to change an upload's limits
+    UploadUpdate,
+    /// Deletion password checked to delete an upload
+    UploadDelete,
+    /// Access password checked to initialize a bundle's download
+    BundleDownloadInit,
+    /// Deletion password checked to delete a bundle
+    BundleDelete,
+    /// Sweeper deleted an expired upload or bundle
+    Expired,
+}
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Event::DownloadInit => "download_init",
+            Event::Download => "download",
+            Event::DownloadConfirm => "download_confirm",
+            Event::UploadUpdate => "upload_update",
+            Event::UploadDelete => "upload_delete",
+            Event::BundleDownloadInit => "bundle_download_init",
+            Event::BundleDelete => "bundle_delete",
+            Event::Expired => "expired",
+        }
+    }
+}
+
+/// Result of an audited action
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Success,
+    /// Wrong password or content hash
+    Failure,
+    /// Refused after too many failed attempts
+    Locked,
+    /// Refused by the per upload/bundle rate limit
+    RateLimited,
+    /// Not checked, because of a server error or the server being busy
+    Error,
+}
+impl Outcome {
+    /// Classify the result of a password check
+    pub fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Success,
+            Err(e) => match e.kind() {
+                ErrorKind::TooManyRequests(_) => Outcome::RateLimited,
+                // records are expired instead of locked with `AuthFailureAction::Delete`
+                ErrorKind::Locked(_) | ErrorKind::DoesNotExist(_) => Outcome::Locked,
+                ErrorKind::InvalidAuth(_) | ErrorKind::S(_) => Outcome::Failure,
+                _ => Outcome::Error,
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Locked => "locked",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// The client making a request. Actions taken by the server itself have no client.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// Append an event for an action on `record`, an `Upload` or `Bundle`
+pub async fn record<T: GenericClient, L: Lockable>(
+    conn: &T,
+    event: Event,
+    outcome: Outcome,
+    record: &L,
+    client: &Client,
+) -> Result<()> {
+    let (upload, bundle) = match L::table_name() {
+        "bundle" => (None, Some(record.id())),
+        _ => (Some(record.id()), None),
+    };
+    models::NewAuditEvent {
+        event: event.as_str().to_string(),
+        outcome: outcome.as_str().to_string(),
+        upload,
+        bundle,
+        uuid: Some(record.uuid()),
+        client_ip: client.ip.map(|ip| ip.to_string()),
+        user_agent: client.user_agent.clone(),
+    }
+    .insert(conn)
+    .await?;
+    Ok(())
+}
+
+/// Write events matching `filter` to `out` as JSON lines, returning the number written
+pub async fn export<T: GenericClient, W: Write>(
+    conn: &T,
+    filter: &models::AuditFilter,
+    out: &mut W,
+) -> Result<usize> {
+    let events = models::AuditEvent::select(conn, filter).await?;
+    for event in &events {
+        let line = json!({
+            "id": event.id,
+            "date": event.date_created.to_rfc3339(),
+            "event": &event.event,
+            "outcome": &event.outcome,
+            "upload": event.upload,
+            "bundle": event.bundle,
+            "key": event.uuid.map(|uuid| hex::encode(uuid.as_bytes())),
+            "client_ip": &event.client_ip,
+            "user_agent": &event.user_agent,
+        });
+        serde_json::to_writer(&mut *out, &line)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(events.len())
+}
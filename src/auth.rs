@@ -4,10 +4,140 @@ Authorization/crypto things
 */
 use crate::crypto::bcrypt;
 use crate::error;
+use argon2;
 use ring::rand::{self, SecureRandom};
-use ring::{constant_time, digest};
+use ring::{constant_time, digest, hmac};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
 
-/// Generate a new 16-byte salt for use with `bcrypt`
+/// Password hashing algorithm and cost parameters, selected in `config.ron` with the
+/// `password_hashing` key. The algorithm and parameters are stored with each `auth`
+/// record, so hashes made with previous settings can still be verified.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PasswordHashing {
+    /// Argon2id of the password, using `memory_kib` KiB of memory, `iterations`
+    /// passes over it, and `parallelism` lanes
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// bcrypt of the password's SHA256 hash, with `2^cost` rounds
+    Bcrypt { cost: u32 },
+}
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        PasswordHashing::Argon2id {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+impl PasswordHashing {
+    /// Algorithm name, as stored in `auth.algorithm`
+    pub fn algorithm(&self) -> &'static str {
+        match *self {
+            PasswordHashing::Argon2id { .. } => "argon2id",
+            PasswordHashing::Bcrypt { .. } => "bcrypt",
+        }
+    }
+
+    /// Cost parameters, as stored in `auth.params`
+    pub fn params(&self) -> String {
+        match *self {
+            PasswordHashing::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => format!("m={},t={},p={}", memory_kib, iterations, parallelism),
+            PasswordHashing::Bcrypt { cost } => format!("cost={}", cost),
+        }
+    }
+
+    /// Parse a stored `algorithm` and its `params`
+    pub fn from_stored(algorithm: &str, params: &str) -> error::Result<Self> {
+        let invalid = || {
+            error::helpers::internal(format!(
+                "Invalid password hash params: {} {}",
+                algorithm, params
+            ))
+        };
+        let param = |name: &str| -> error::Result<u32> {
+            params
+                .split(',')
+                .filter_map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(key), Some(value)) if key == name => Some(value),
+                        _ => None,
+                    }
+                })
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(invalid)
+        };
+        match algorithm {
+            "argon2id" => Ok(PasswordHashing::Argon2id {
+                memory_kib: param("m")?,
+                iterations: param("t")?,
+                parallelism: param("p")?,
+            }),
+            "bcrypt" => Ok(PasswordHashing::Bcrypt {
+                cost: param("cost")?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Describe any invalid cost parameters
+    pub fn check(&self) -> std::result::Result<(), String> {
+        match *self {
+            PasswordHashing::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                if !(1..=64).contains(&parallelism) {
+                    return Err(String::from(
+                        "Argon2id `parallelism` must be between 1 and 64",
+                    ));
+                }
+                if memory_kib < 8 * parallelism {
+                    return Err(String::from(
+                        "Argon2id `memory_kib` must be at least 8 times `parallelism`",
+                    ));
+                }
+                if iterations == 0 {
+                    return Err(String::from(
+                        "Argon2id `iterations` must be greater than zero",
+                    ));
+                }
+            }
+            PasswordHashing::Bcrypt { cost } => {
+                if !(4..=31).contains(&cost) {
+                    return Err(String::from("bcrypt `cost` must be between 4 and 31"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash `pass` with `salt`
+    pub fn hash(&self, pass: &[u8], salt: &[u8]) -> error::Result<Vec<u8>> {
+        match *self {
+            PasswordHashing::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => argon2id_hash(pass, salt, memory_kib, iterations, parallelism),
+            PasswordHashing::Bcrypt { cost } => bcrypt_hash(cost, &sha256(pass), salt),
+        }
+    }
+}
+
+/// Generate a new 16-byte salt for use with `bcrypt` or `argon2id`
 pub fn new_salt() -> error::Result<Vec<u8>> {
     const SALT_SIZE: usize = 16;
     let mut salt = vec![0u8; SALT_SIZE];
@@ -16,6 +146,15 @@ pub fn new_salt() -> error::Result<Vec<u8>> {
     Ok(salt)
 }
 
+/// Generate a new random 32-byte api token
+pub fn new_api_token() -> error::Result<Vec<u8>> {
+    const TOKEN_SIZE: usize = 32;
+    let mut token = vec![0u8; TOKEN_SIZE];
+    let rng = rand::SystemRandom::new();
+    rng.fill(&mut token)?;
+    Ok(token)
+}
+
 /// Return the SHA256 hash of `bytes`
 pub fn sha256(bytes: &[u8]) -> Vec<u8> {
     let alg = &digest::SHA256;
@@ -23,8 +162,57 @@ pub fn sha256(bytes: &[u8]) -> Vec<u8> {
     Vec::from(digest.as_ref())
 }
 
-/// Calculate the `bcrypt` hash of `bytes` and `salt`
-pub fn bcrypt_hash(bytes: &[u8], salt: &[u8]) -> error::Result<Vec<u8>> {
+/// Return the HMAC-SHA256 signature of `bytes` using `key`
+pub fn hmac_sha256(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    Vec::from(hmac::sign(&key, bytes).as_ref())
+}
+
+/// Return the SHA256 hash of the contents of the file at `path`
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> error::Result<Vec<u8>> {
+    sha256_reader(fs::File::open(path)?)
+}
+
+/// Return the SHA256 hash of all bytes read from `reader`
+pub fn sha256_reader<R: Read>(mut reader: R) -> error::Result<Vec<u8>> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ctx.update(&buf[..n]);
+    }
+    Ok(Vec::from(ctx.finish().as_ref()))
+}
+
+/// Calculate the `argon2id` hash of `bytes` and `salt`
+pub fn argon2id_hash(
+    bytes: &[u8],
+    salt: &[u8],
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> error::Result<Vec<u8>> {
+    const OUTPUT_SIZE: u32 = 32;
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: memory_kib,
+        time_cost: iterations,
+        lanes: parallelism,
+        thread_mode: argon2::ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: OUTPUT_SIZE,
+    };
+    argon2::hash_raw(bytes, salt, &config)
+        .map_err(|e| error::helpers::internal(format!("argon2id error: {}", e)))
+}
+
+/// Calculate the `bcrypt` hash of `bytes` and `salt`, with `2^cost` rounds
+pub fn bcrypt_hash(cost: u32, bytes: &[u8], salt: &[u8]) -> error::Result<Vec<u8>> {
     let slen = salt.len();
     let blen = bytes.len();
     if !(slen == 16 && (0 < blen && blen <= 72)) {
@@ -34,10 +222,9 @@ pub fn bcrypt_hash(bytes: &[u8], salt: &[u8]) -> error::Result<Vec<u8>> {
             bytes.len(),
         )));
     }
-    const COST: u32 = 10;
     const OUTPUT_SIZE: usize = 24;
     let mut hash = vec![0u8; OUTPUT_SIZE];
-    bcrypt::bcrypt(COST, salt, bytes, &mut hash);
+    bcrypt::bcrypt(cost, salt, bytes, &mut hash);
     Ok(hash)
 }
 
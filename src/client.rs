@@ -0,0 +1,292 @@
+/*!
+Client for a transfer server
+
+Implements the same protocol and encryption as the web client (`web/src/utils/crypto.js`):
+file contents are encrypted with AES-GCM-256 using the SHA-256 hash of the
+encryption password as the key, and a random 12 byte nonce.
+*/
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use hex;
+use reqwest;
+use ring::aead;
+use ring::digest;
+use ring::rand::{self, SecureRandom};
+use uuid::Uuid;
+
+use crate::error::{self, ErrorCode, Result};
+use crate::handlers::{DownloadKeyAccessPost, DownloadKeyHash, UploadInitPost, UploadKey};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Return the SHA-256 hash of `bytes`
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, bytes).as_ref().to_vec()
+}
+
+/// Encrypt `data` with AES-GCM-256, using the SHA-256 hash of `pass` as the key
+pub fn encrypt(data: &[u8], nonce: &[u8], pass: &[u8]) -> Result<Vec<u8>> {
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, &sha256(pass))?;
+    let mut in_out = data.to_vec();
+    in_out.extend_from_slice(&[0u8; TAG_LEN]);
+    let n = aead::seal_in_place(&key, nonce, &[], &mut in_out, TAG_LEN)?;
+    in_out.truncate(n);
+    Ok(in_out)
+}
+
+/// Decrypt `data` previously encrypted with `encrypt`
+pub fn decrypt(data: &[u8], nonce: &[u8], pass: &[u8]) -> Result<Vec<u8>> {
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, &sha256(pass))?;
+    let mut in_out = data.to_vec();
+    let n = aead::open_in_place(&key, nonce, &[], 0, &mut in_out)
+        .map_err(|_| error::helpers::invalid_auth("Decryption failed"))?
+        .len();
+    in_out.truncate(n);
+    Ok(in_out)
+}
+
+/// Build the key shared with downloaders, containing the upload key and
+/// the url-safe base64 encoded file name, e.g. `<upload-key>_<file-name>`
+pub fn share_key(upload_key: &str, file_name: &str) -> String {
+    format!(
+        "{}_{}",
+        upload_key,
+        base64::encode_config(file_name, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Split a key built by `share_key` into its upload key and file name
+pub fn parse_share_key(key: &str) -> Result<(String, String)> {
+    let mut parts = key.splitn(2, '_');
+    let upload_key = parts.next().unwrap_or("");
+    if Uuid::from_str(upload_key).is_err() {
+        return Err(error::helpers::bad_request("Invalid download key"));
+    }
+    let file_name = parts
+        .next()
+        .ok_or_else(|| error::helpers::bad_request("Download key is missing a file name"))?;
+    let file_name = base64::decode_config(file_name.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| error::helpers::bad_request("Invalid download key file name"))?;
+    let file_name = String::from_utf8(file_name)
+        .map_err(|_| error::helpers::bad_request("Invalid download key file name"))?;
+    Ok((upload_key.to_string(), file_name))
+}
+
+/// Upload settings
+pub struct UploadOptions {
+    pub access_password: String,
+    pub encrypt_password: String,
+    pub deletion_password: Option<String>,
+    pub download_limit: Option<u32>,
+    pub lifespan: Option<i64>,
+    /// Api token to upload with, see `transfer admin tokens`
+    pub api_token: Option<String>,
+}
+
+/// A completed upload
+pub struct Uploaded {
+    /// Key to share with downloaders
+    pub key: String,
+    /// Key of the upload itself, used for deletion
+    pub upload_key: String,
+    pub size: u64,
+}
+
+/// A completed and verified download
+pub struct Downloaded {
+    pub file_name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct UploadInitResp {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct DownloadInitResp {
+    nonce: String,
+    download_key: String,
+    confirm_key: String,
+}
+
+#[derive(Deserialize)]
+struct DownloadConfirmResp {
+    file_name_hash: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResp {
+    error: String,
+    code: Option<String>,
+    retry_after: Option<u64>,
+}
+
+/// Transfer server client
+pub struct Client {
+    url: String,
+    http: reqwest::blocking::Client,
+}
+impl Client {
+    /// Create a client for the server at `url`, e.g. `https://transfer.example.com`
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    /// Convert unsuccessful responses into the error kind the server responded with.
+    /// Servers not returning an error `code` are handled by status.
+    fn check(resp: reqwest::blocking::Response) -> Result<reqwest::blocking::Response> {
+        use reqwest::StatusCode as S;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let (msg, code, retry_after) = match resp.json::<ErrorResp>() {
+            Ok(e) => (
+                e.error,
+                e.code.and_then(|c| ErrorCode::parse(&c)),
+                e.retry_after,
+            ),
+            Err(_) => (status.to_string(), None, None),
+        };
+        if let Some(code) = code {
+            let err = code.into_error(msg);
+            return Err(match retry_after {
+                Some(secs) => err.with_retry_after(secs),
+                None => err,
+            });
+        }
+        Err(match status {
+            S::BAD_REQUEST => error::helpers::bad_request(msg),
+            S::UNAUTHORIZED => error::helpers::invalid_auth(msg),
+            S::NOT_FOUND => error::helpers::does_not_exist(msg),
+            S::PAYLOAD_TOO_LARGE => error::helpers::too_large(msg),
+            S::RANGE_NOT_SATISFIABLE => error::helpers::range_not_satisfiable(msg),
+            S::SERVICE_UNAVAILABLE => error::helpers::out_of_space(msg),
+            _ => error::helpers::internal(format!("Server error: {}", msg)),
+        })
+    }
+
+    /// Encrypt and upload the file at `path`
+    pub fn upload_file<P: AsRef<Path>>(&self, path: P, opts: &UploadOptions) -> Result<Uploaded> {
+        let path = path.as_ref();
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| error::helpers::bad_request(format!("Invalid file name: {:?}", path)))?;
+        let mut data = vec![];
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        self.upload(file_name, &data, opts)
+    }
+
+    /// Encrypt and upload `data` with the given `file_name`
+    pub fn upload(&self, file_name: &str, data: &[u8], opts: &UploadOptions) -> Result<Uploaded> {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rand::SystemRandom::new().fill(&mut nonce)?;
+        let encrypted = encrypt(data, &nonce, opts.encrypt_password.as_bytes())?;
+
+        let init = UploadInitPost {
+            nonce: hex::encode(&nonce),
+            file_name_hash: hex::encode(sha256(file_name.as_bytes())),
+            size: encrypted.len() as u64,
+            content_hash: hex::encode(sha256(data)),
+            access_password: hex::encode(opts.access_password.as_bytes()),
+            deletion_password: opts
+                .deletion_password
+                .as_ref()
+                .map(|pass| hex::encode(pass.as_bytes())),
+            download_limit: opts.download_limit,
+            lifespan: opts.lifespan,
+            webhook_url: None,
+            webhook_secret: None,
+        };
+        let mut req = self.http.post(self.endpoint("/api/upload/init"));
+        if let Some(ref token) = opts.api_token {
+            req = req.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let resp = req.json(&init).send()?;
+        let upload_key = Self::check(resp)?.json::<UploadInitResp>()?.key;
+
+        let size = encrypted.len() as u64;
+        let resp = self
+            .http
+            .post(self.endpoint("/api/upload"))
+            .query(&UploadKey {
+                key: upload_key.clone(),
+            })
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(encrypted)
+            .send()?;
+        Self::check(resp)?;
+
+        Ok(Uploaded {
+            key: share_key(&upload_key, file_name),
+            upload_key,
+            size,
+        })
+    }
+
+    /// Download and decrypt the upload identified by a `share_key`, verifying the
+    /// decrypted contents and file name with the server
+    pub fn download(
+        &self,
+        key: &str,
+        access_password: &str,
+        encrypt_password: &str,
+    ) -> Result<Downloaded> {
+        let (upload_key, file_name) = parse_share_key(key)?;
+        let access_password = hex::encode(access_password.as_bytes());
+
+        let resp = self
+            .http
+            .post(self.endpoint("/api/download/init"))
+            .json(&DownloadKeyAccessPost {
+                key: upload_key,
+                access_password: access_password.clone(),
+            })
+            .send()?;
+        let init = Self::check(resp)?.json::<DownloadInitResp>()?;
+
+        let resp = self
+            .http
+            .post(self.endpoint("/api/download"))
+            .json(&DownloadKeyAccessPost {
+                key: init.download_key,
+                access_password,
+            })
+            .send()?;
+        let mut encrypted = vec![];
+        Self::check(resp)?.copy_to(&mut encrypted)?;
+
+        let nonce = hex::decode(&init.nonce)?;
+        let data = decrypt(&encrypted, &nonce, encrypt_password.as_bytes())?;
+
+        let resp = self
+            .http
+            .post(self.endpoint("/api/download/confirm"))
+            .json(&DownloadKeyHash {
+                key: init.confirm_key,
+                hash: hex::encode(sha256(&data)),
+            })
+            .send()?;
+        let confirm = Self::check(resp)?.json::<DownloadConfirmResp>()?;
+        if confirm.file_name_hash != hex::encode(sha256(file_name.as_bytes())) {
+            return Err(error::helpers::bad_request(format!(
+                "Download integrity error: file name hash mismatch for {:?}",
+                file_name
+            )));
+        }
+        Ok(Downloaded { file_name, data })
+    }
+}
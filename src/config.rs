@@ -0,0 +1,674 @@
+/*!
+Configuration
+
+Configuration is layered, each layer overriding settings of the previous one:
+
+1. Defaults
+2. `config.ron`, from the config directory or the running directory, or a file given with `--config`
+3. `TRANSFER_<SETTING>` environment variables, e.g. `TRANSFER_UPLOAD_LIMIT_BYTES=1000`
+4. Command line `--set <setting>=<value>` flags
+
+The resulting `Config` is validated and held by a `LiveConfig` in the request `Ctx`.
+While serving, `LiveConfig::reload` (triggered by `SIGHUP`) re-reads all layers and
+atomically swaps in the new configuration for subsequent requests. Requests in
+progress keep the configuration they started with.
+*/
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use ron;
+use serde_json;
+
+use crate::auth::PasswordHashing;
+use crate::config_dir;
+use crate::error::{self, Result};
+use crate::hashing;
+use crate::storage::StorageConfig;
+use crate::webhooks;
+
+/// Settings that can be set by name with environment variables and `--set`
+pub const SETTINGS: &[&str] = &[
+    "upload_limit_bytes",
+    "upload_timeout_secs",
+    "upload_lifespan_secs_default",
+    "upload_lifespan_secs_min",
+    "upload_lifespan_secs_max",
+    "max_combined_upload_bytes",
+    "download_timeout_secs",
+    "download_limit_default",
+    "download_limit_max",
+    "allow_unlimited_downloads",
+    "expired_cleanup_interval_secs",
+    "shutdown_timeout_secs",
+    "hash_uploads",
+    "auth_rate_limit_per_minute",
+    "trust_forwarded_for",
+    "max_auth_failures",
+    "auth_failure_action",
+    "require_api_token",
+    "password_hashing",
+    "hash_threads",
+    "hash_queue_depth",
+    "hash_timeout_ms",
+    "hash_route_timeouts_ms",
+    "webhook_url",
+    "webhook_secret",
+    "allow_upload_webhooks",
+    "webhook_allowed_hosts",
+    "webhook_max_attempts",
+    "webhook_interval_secs",
+    "upload_directory",
+    "storage",
+    "encryption_key_file",
+    "host",
+    "port",
+];
+
+/// Settings that are only read on startup. Changes to these are ignored
+/// when reloading and require a restart.
+pub const STARTUP_SETTINGS: &[&str] = &[
+    "hash_threads",
+    "hash_queue_depth",
+    "upload_directory",
+    "storage",
+    "encryption_key_file",
+    "host",
+    "port",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub upload_limit_bytes: i64,
+    pub upload_timeout_secs: i64,
+    pub upload_lifespan_secs_default: i64,
+    pub upload_lifespan_secs_min: i64,
+    pub upload_lifespan_secs_max: i64,
+    pub max_combined_upload_bytes: i64,
+    pub download_timeout_secs: i64,
+    pub download_limit_default: Option<i32>,
+    pub download_limit_max: Option<i32>,
+    pub allow_unlimited_downloads: bool,
+    pub expired_cleanup_interval_secs: u64,
+    pub shutdown_timeout_secs: u64,
+    pub hash_uploads: bool,
+    pub auth_rate_limit_per_minute: Option<u32>,
+    pub trust_forwarded_for: bool,
+    pub max_auth_failures: Option<i32>,
+    pub auth_failure_action: AuthFailureAction,
+    pub require_api_token: bool,
+    pub password_hashing: PasswordHashing,
+    pub hash_threads: usize,
+    pub hash_queue_depth: usize,
+    pub hash_timeout_ms: u64,
+    pub hash_route_timeouts_ms: BTreeMap<String, u64>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub allow_upload_webhooks: bool,
+    pub webhook_allowed_hosts: Vec<String>,
+    pub webhook_max_attempts: i32,
+    pub webhook_interval_secs: u64,
+    pub upload_directory: String,
+    pub storage: StorageConfig,
+    pub encryption_key_file: Option<String>,
+    pub host: String,
+    pub port: u16,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            upload_limit_bytes: 300_000_000,
+            upload_timeout_secs: 500,
+            upload_lifespan_secs_default: 86400,
+            upload_lifespan_secs_min: 60,
+            upload_lifespan_secs_max: 2_592_000,
+            max_combined_upload_bytes: 5_000_000_000,
+            download_timeout_secs: 500,
+            download_limit_default: None,
+            download_limit_max: None,
+            allow_unlimited_downloads: true,
+            expired_cleanup_interval_secs: 120,
+            shutdown_timeout_secs: 30,
+            hash_uploads: false,
+            auth_rate_limit_per_minute: Some(30),
+            trust_forwarded_for: false,
+            max_auth_failures: Some(10),
+            auth_failure_action: AuthFailureAction::Lock,
+            require_api_token: false,
+            password_hashing: PasswordHashing::default(),
+            hash_threads: 2,
+            hash_queue_depth: 32,
+            hash_timeout_ms: 5000,
+            hash_route_timeouts_ms: BTreeMap::new(),
+            webhook_url: None,
+            webhook_secret: None,
+            allow_upload_webhooks: false,
+            webhook_allowed_hosts: vec![],
+            webhook_max_attempts: 10,
+            webhook_interval_secs: 10,
+            upload_directory: String::from("uploads"),
+            storage: StorageConfig::default(),
+            encryption_key_file: None,
+            host: String::from("0.0.0.0"),
+            port: 3300,
+        }
+    }
+}
+
+/// What happens to an upload or bundle once `max_auth_failures` wrong passwords are tried
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AuthFailureAction {
+    /// Refuse all further password attempts, including correct ones
+    Lock,
+    /// Expire it, so it's deleted by the sweeper
+    Delete,
+}
+impl FromStr for AuthFailureAction {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "Lock" | "lock" => Ok(AuthFailureAction::Lock),
+            "Delete" | "delete" => Ok(AuthFailureAction::Delete),
+            _ => Err(String::from("expected `Lock` or `Delete`")),
+        }
+    }
+}
+
+/// Configuration layers applied on top of the defaults
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    /// Config file to use instead of searching for `config.ron`
+    pub file: Option<PathBuf>,
+    /// `(setting, value)` pairs, e.g. from the command line
+    pub settings: Vec<(String, String)>,
+}
+
+fn parse<T>(setting: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.trim().parse::<T>().map_err(|e| {
+        error::helpers::config(format!(
+            "Invalid value {:?} for `{}`: {}",
+            value, setting, e
+        ))
+    })
+}
+
+fn parse_opt<T>(setting: &str, value: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match value.trim() {
+        "" | "none" | "None" => Ok(None),
+        value => Ok(Some(parse(setting, value)?)),
+    }
+}
+
+impl Config {
+    /// Load the layered configuration and validate it
+    pub fn load(overrides: &Overrides) -> Result<Self> {
+        let config = Self::load_unvalidated(overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the layered configuration without validating it
+    pub fn load_unvalidated(overrides: &Overrides) -> Result<Self> {
+        let file = match overrides.file {
+            Some(ref file) => Some(file.clone()),
+            None => Self::find_file()?,
+        };
+        let mut config = match file {
+            Some(file) => Self::from_file(&file)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        for (setting, value) in &overrides.settings {
+            config.set(setting, value)?;
+        }
+        Ok(config)
+    }
+
+    /// Look for a `config.ron` in the config directory, then the running directory
+    pub fn find_file() -> Result<Option<PathBuf>> {
+        let candidates = [
+            config_dir()?.join("config.ron"),
+            env::current_dir()?.join("config.ron"),
+        ];
+        Ok(candidates.iter().find(|path| path.is_file()).cloned())
+    }
+
+    /// Load a config file. Settings missing from the file are set to their defaults.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let f = fs::File::open(path).map_err(|e| {
+            error::helpers::config(format!("Unable to open config file {:?}: {}", path, e))
+        })?;
+        ron::de::from_reader(f).map_err(|e| {
+            error::helpers::config(format!("Unable to parse config file {:?}: {}", path, e))
+        })
+    }
+
+    /// Apply `TRANSFER_<SETTING>` environment variables. The `PORT` environment
+    /// variable is also supported, with `TRANSFER_PORT` taking precedence.
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Ok(port) = env::var("PORT") {
+            if !port.is_empty() {
+                self.set("port", &port)?;
+            }
+        }
+        for setting in SETTINGS {
+            let var = format!("TRANSFER_{}", setting.to_uppercase());
+            if let Ok(value) = env::var(&var) {
+                self.set(setting, &value).map_err(|e| match e.kind() {
+                    error::ErrorKind::Config(ref s) => {
+                        error::helpers::config(format!("{}: {}", var, s))
+                    }
+                    _ => e,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set a single setting by name, parsing its `value`. `storage`, `password_hashing`, and
+    /// `hash_route_timeouts_ms` values are parsed as RON, e.g. `Sharded(depth: 2)` or
+    /// `{"/api/download": 2000}`, and `webhook_allowed_hosts`
+    /// as a comma separated list
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<()> {
+        match setting {
+            "upload_limit_bytes" => self.upload_limit_bytes = parse(setting, value)?,
+            "upload_timeout_secs" => self.upload_timeout_secs = parse(setting, value)?,
+            "upload_lifespan_secs_default" => {
+                self.upload_lifespan_secs_default = parse(setting, value)?
+            }
+            "upload_lifespan_secs_min" => self.upload_lifespan_secs_min = parse(setting, value)?,
+            "upload_lifespan_secs_max" => self.upload_lifespan_secs_max = parse(setting, value)?,
+            "max_combined_upload_bytes" => self.max_combined_upload_bytes = parse(setting, value)?,
+            "download_timeout_secs" => self.download_timeout_secs = parse(setting, value)?,
+            "download_limit_default" => self.download_limit_default = parse_opt(setting, value)?,
+            "download_limit_max" => self.download_limit_max = parse_opt(setting, value)?,
+            "allow_unlimited_downloads" => self.allow_unlimited_downloads = parse(setting, value)?,
+            "expired_cleanup_interval_secs" => {
+                self.expired_cleanup_interval_secs = parse(setting, value)?
+            }
+            "shutdown_timeout_secs" => self.shutdown_timeout_secs = parse(setting, value)?,
+            "hash_uploads" => self.hash_uploads = parse(setting, value)?,
+            "hash_threads" => self.hash_threads = parse(setting, value)?,
+            "hash_queue_depth" => self.hash_queue_depth = parse(setting, value)?,
+            "hash_timeout_ms" => self.hash_timeout_ms = parse(setting, value)?,
+            "hash_route_timeouts_ms" => {
+                self.hash_route_timeouts_ms = ron::de::from_str(value).map_err(|e| {
+                    error::helpers::config(format!(
+                        "Invalid value for `hash_route_timeouts_ms`: {}",
+                        e
+                    ))
+                })?
+            }
+            "auth_rate_limit_per_minute" => {
+                self.auth_rate_limit_per_minute = parse_opt(setting, value)?
+            }
+            "trust_forwarded_for" => self.trust_forwarded_for = parse(setting, value)?,
+            "max_auth_failures" => self.max_auth_failures = parse_opt(setting, value)?,
+            "auth_failure_action" => self.auth_failure_action = parse(setting, value)?,
+            "require_api_token" => self.require_api_token = parse(setting, value)?,
+            "password_hashing" => {
+                self.password_hashing = ron::de::from_str(value).map_err(|e| {
+                    error::helpers::config(format!("Invalid value for `password_hashing`: {}", e))
+                })?
+            }
+            "webhook_url" => self.webhook_url = parse_opt(setting, value)?,
+            "webhook_secret" => self.webhook_secret = parse_opt(setting, value)?,
+            "allow_upload_webhooks" => self.allow_upload_webhooks = parse(setting, value)?,
+            "webhook_allowed_hosts" => {
+                self.webhook_allowed_hosts = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "webhook_max_attempts" => self.webhook_max_attempts = parse(setting, value)?,
+            "webhook_interval_secs" => self.webhook_interval_secs = parse(setting, value)?,
+            "upload_directory" => self.upload_directory = value.to_string(),
+            "storage" => {
+                self.storage = ron::de::from_str(value).map_err(|e| {
+                    error::helpers::config(format!("Invalid value for `storage`: {}", e))
+                })?
+            }
+            "encryption_key_file" => self.encryption_key_file = parse_opt(setting, value)?,
+            "host" => self.host = value.to_string(),
+            "port" => self.port = parse(setting, value)?,
+            _ => {
+                return Err(error::helpers::config(format!(
+                    "Unknown setting `{}`, expected one of: {}",
+                    setting,
+                    SETTINGS.join(", ")
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Check settings are sensible and the upload directory is writable (creating it
+    /// if needed), returning an error describing every problem found
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = self.problems();
+        match self.upload_dir() {
+            Ok(dir) => {
+                if let Err(e) = check_writable(&dir) {
+                    problems.push(format!(
+                        "`upload_directory` {:?} is not writable: {}",
+                        dir, e
+                    ));
+                }
+            }
+            Err(e) => problems.push(format!("Invalid `upload_directory`: {}", e)),
+        }
+        Self::report(problems)
+    }
+
+    /// Check settings are sensible without touching the filesystem, for reloads
+    /// keeping the running `upload_directory`
+    fn validate_reload(&self) -> Result<()> {
+        Self::report(self.problems())
+    }
+
+    /// Describe every problem found with the settings
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let positive = [
+            ("upload_limit_bytes", self.upload_limit_bytes),
+            ("upload_timeout_secs", self.upload_timeout_secs),
+            (
+                "upload_lifespan_secs_default",
+                self.upload_lifespan_secs_default,
+            ),
+            ("upload_lifespan_secs_min", self.upload_lifespan_secs_min),
+            ("upload_lifespan_secs_max", self.upload_lifespan_secs_max),
+            ("max_combined_upload_bytes", self.max_combined_upload_bytes),
+            ("download_timeout_secs", self.download_timeout_secs),
+            (
+                "expired_cleanup_interval_secs",
+                self.expired_cleanup_interval_secs as i64,
+            ),
+            ("hash_threads", self.hash_threads as i64),
+            ("hash_queue_depth", self.hash_queue_depth as i64),
+            ("hash_timeout_ms", self.hash_timeout_ms as i64),
+            ("webhook_max_attempts", i64::from(self.webhook_max_attempts)),
+            ("webhook_interval_secs", self.webhook_interval_secs as i64),
+        ];
+        for (setting, value) in positive.iter() {
+            if *value <= 0 {
+                problems.push(format!("`{}` must be greater than zero", setting));
+            }
+        }
+        if self.upload_limit_bytes > self.max_combined_upload_bytes {
+            problems.push(format!(
+                "`upload_limit_bytes` ({}) must not be larger than `max_combined_upload_bytes` ({})",
+                self.upload_limit_bytes, self.max_combined_upload_bytes
+            ));
+        }
+        if self.upload_lifespan_secs_default < self.upload_lifespan_secs_min
+            || self.upload_lifespan_secs_default > self.upload_lifespan_secs_max
+        {
+            problems.push(format!(
+                "`upload_lifespan_secs_default` ({}) must be between `upload_lifespan_secs_min` ({}) \
+                 and `upload_lifespan_secs_max` ({})",
+                self.upload_lifespan_secs_default,
+                self.upload_lifespan_secs_min,
+                self.upload_lifespan_secs_max
+            ));
+        }
+        if let Some(limit) = self.download_limit_default {
+            if limit <= 0 {
+                problems.push(String::from(
+                    "`download_limit_default` must be greater than zero",
+                ));
+            }
+        }
+        if let Some(max) = self.download_limit_max {
+            if max <= 0 {
+                problems.push(String::from(
+                    "`download_limit_max` must be greater than zero, or `None` for no max",
+                ));
+            }
+            if self.download_limit_default.is_some_and(|limit| limit > max) {
+                problems.push(format!(
+                    "`download_limit_default` must not be larger than `download_limit_max` ({})",
+                    max
+                ));
+            }
+        }
+        if !self.allow_unlimited_downloads && self.download_limit_default.is_none() {
+            problems.push(String::from(
+                "`download_limit_default` is required when `allow_unlimited_downloads` is false",
+            ));
+        }
+        if self.auth_rate_limit_per_minute == Some(0) {
+            problems.push(String::from(
+                "`auth_rate_limit_per_minute` must be greater than zero, or `None` to disable",
+            ));
+        }
+        if let Some(n) = self.max_auth_failures {
+            if n <= 0 {
+                problems.push(String::from(
+                    "`max_auth_failures` must be greater than zero, or `None` to disable",
+                ));
+            }
+        }
+        if let Err(e) = self.password_hashing.check() {
+            problems.push(format!("Invalid `password_hashing`: {}", e));
+        }
+        for (route, timeout_ms) in &self.hash_route_timeouts_ms {
+            if !hashing::ROUTES.contains(&route.as_str()) {
+                problems.push(format!(
+                    "Unknown `hash_route_timeouts_ms` route `{}`, expected one of: {}",
+                    route,
+                    hashing::ROUTES.join(", ")
+                ));
+            } else if *timeout_ms == 0 {
+                problems.push(format!(
+                    "`hash_route_timeouts_ms` for `{}` must be greater than zero",
+                    route
+                ));
+            }
+        }
+        if let Some(ref url) = self.webhook_url {
+            if let Err(e) = webhooks::check_url(url) {
+                problems.push(format!("Invalid `webhook_url`: {}", e));
+            }
+            if self.webhook_secret.as_ref().is_none_or(String::is_empty) {
+                problems.push(String::from(
+                    "`webhook_secret` is required when `webhook_url` is set",
+                ));
+            }
+        }
+        match self.storage {
+            StorageConfig::Local => (),
+            StorageConfig::Sharded { depth } => {
+                if depth == 0 || depth > 16 {
+                    problems.push(String::from(
+                        "`storage` sharding `depth` must be between 1 and 16",
+                    ));
+                }
+            }
+            StorageConfig::S3 {
+                ref endpoint,
+                ref bucket,
+                ..
+            } => {
+                if endpoint.is_empty() || bucket.is_empty() {
+                    problems.push(String::from(
+                        "`storage` S3 `endpoint` and `bucket` are required",
+                    ));
+                }
+            }
+        }
+        if let Err(e) = self.addr() {
+            problems.push(format!("Invalid `host`/`port`: {}", e));
+        }
+        problems
+    }
+
+    /// Combine `problems` into a single error, if there are any
+    fn report(problems: Vec<String>) -> Result<()> {
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(error::helpers::config(format!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
+    }
+
+    /// Absolute path of the upload directory, relative paths are
+    /// resolved from the running directory
+    pub fn upload_dir(&self) -> Result<PathBuf> {
+        let path = PathBuf::from(&self.upload_directory);
+        if path.is_absolute() {
+            Ok(path)
+        } else {
+            Ok(env::current_dir()?.join(&self.upload_directory))
+        }
+    }
+
+    /// Path of the key file used for encryption at rest, if configured
+    pub fn encryption_key_path(&self) -> Result<Option<PathBuf>> {
+        Ok(match self.encryption_key_file {
+            None => None,
+            Some(ref file) => {
+                let path = PathBuf::from(file);
+                if path.is_absolute() {
+                    Some(path)
+                } else {
+                    Some(env::current_dir()?.join(file))
+                }
+            }
+        })
+    }
+
+    /// Address to serve on
+    pub fn addr(&self) -> Result<SocketAddr> {
+        Ok(format!("{}:{}", self.host, self.port).parse::<SocketAddr>()?)
+    }
+
+    /// Copy of this config with secrets redacted
+    fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if let StorageConfig::S3 {
+            ref mut secret_key, ..
+        } = config.storage
+        {
+            *secret_key = String::from("<redacted>");
+        }
+        if config.webhook_secret.is_some() {
+            config.webhook_secret = Some(String::from("<redacted>"));
+        }
+        config
+    }
+
+    /// Format as RON, with secrets redacted
+    pub fn to_ron(&self) -> Result<String> {
+        ron::ser::to_string_pretty(&self.redacted(), ron::ser::PrettyConfig::default())
+            .map_err(|e| error::helpers::internal(format!("Error formatting config: {}", e)))
+    }
+
+    /// List the settings that differ in `other`. Displayed values have secrets redacted.
+    pub fn diff(&self, other: &Config) -> Result<Vec<Change>> {
+        let values = |config: &Config| -> Result<serde_json::Value> {
+            serde_json::to_value(config)
+                .map_err(|e| error::helpers::internal(format!("Error formatting config: {}", e)))
+        };
+        let (old, new) = (values(self)?, values(other)?);
+        let (old_shown, new_shown) = (values(&self.redacted())?, values(&other.redacted())?);
+        Ok(SETTINGS
+            .iter()
+            .filter(|setting| old[**setting] != new[**setting])
+            .map(|setting| Change {
+                setting,
+                old: old_shown[*setting].to_string(),
+                new: new_shown[*setting].to_string(),
+            })
+            .collect())
+    }
+}
+
+/// A changed setting
+#[derive(Debug)]
+pub struct Change {
+    pub setting: &'static str,
+    pub old: String,
+    pub new: String,
+}
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.setting, self.old, self.new)
+    }
+}
+
+/// The running configuration, along with the layers needed to reload it
+pub struct LiveConfig {
+    overrides: Overrides,
+    current: RwLock<Arc<Config>>,
+}
+impl LiveConfig {
+    /// Wrap an already loaded and validated `config`. `overrides` are the
+    /// layers it was loaded with and are re-applied on every reload.
+    pub fn new(config: Config, overrides: Overrides) -> Self {
+        Self {
+            overrides,
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    /// Snapshot of the current configuration
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().expect("config lock poisoned").clone()
+    }
+
+    /// Re-read and validate all configuration layers, and swap in the result,
+    /// returning the settings that changed. Changes to `STARTUP_SETTINGS` are
+    /// logged and ignored, and validated against their running values. If loading
+    /// or validation fails, the current configuration is kept and the error is returned.
+    pub fn reload(&self) -> Result<Vec<Change>> {
+        let mut config = Config::load_unvalidated(&self.overrides)?;
+        let mut current = self.current.write().expect("config lock poisoned");
+        for change in current.diff(&config)? {
+            if STARTUP_SETTINGS.contains(&change.setting) {
+                warn!("Ignoring config change, requires a restart: {}", change);
+            }
+        }
+        config.hash_threads = current.hash_threads;
+        config.hash_queue_depth = current.hash_queue_depth;
+        config.upload_directory = current.upload_directory.clone();
+        config.storage = current.storage.clone();
+        config.encryption_key_file = current.encryption_key_file.clone();
+        config.host = current.host.clone();
+        config.port = current.port;
+        config.validate_reload()?;
+
+        let changes = current.diff(&config)?;
+        *current = Arc::new(config);
+        Ok(changes)
+    }
+}
+
+/// Make sure `dir` exists and files can be created in it
+pub(crate) fn check_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".transfer-write-check");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
@@ -1,48 +1,104 @@
 /*!
 Database connection stuff
 */
+use std::time::Duration;
+
 use crate::config_dir;
+use deadpool_postgres::{GenericClient, Manager, ManagerConfig, RecyclingMethod, Runtime};
 use migrant_lib;
-use postgres;
-use r2d2;
-use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+use tokio_postgres::NoTls;
 
-use crate::error::Result;
+use crate::error::{self, Result};
 
-/// Postgres r2d2 pool
-pub type Pool = r2d2::Pool<PostgresConnectionManager>;
+/// Postgres connection pool
+pub type Pool = deadpool_postgres::Pool;
 
-/// Postgres r2d2 pooled connection (wrapped for `rocket`)
-pub type DbConn = r2d2::PooledConnection<PostgresConnectionManager>;
+/// Postgres pooled connection
+pub type DbConn = deadpool_postgres::Object;
 
-/// Try to get the current db connection string
-pub fn connect_str() -> Result<String> {
+/// Max time to wait for a pooled connection
+const POOL_TIMEOUT_SECS: u64 = 30;
+
+/// Load the `Migrant.toml` from the config directory
+fn migrant_config() -> Result<migrant_lib::Config> {
     let config_dir_ = config_dir()?;
     let config_path = migrant_lib::search_for_settings_file(&config_dir_)
         .ok_or("Unable to find `Migrant.toml` config file")?;
-    let config = migrant_lib::Config::from_settings_file(&config_path)
+    let mut config = migrant_lib::Config::from_settings_file(&config_path)
         .map_err(|_| "Failed loading `Migrant.toml`")?;
+    config.use_cli_compatible_tags(true);
+    Ok(config)
+}
+
+/// Try to get the current db connection string
+pub fn connect_str() -> Result<String> {
+    let config = migrant_config()?;
     Ok(config
         .connect_string()
         .map_err(|_| "Failed creating a connection string")?)
 }
 
-/// Initialize a new r2d2 postgres connection pool
-pub fn init_pool(n: u32) -> Pool {
+/// Return the tags of migrations in the migration directory that haven't been
+/// applied to the database, using an existing (pooled) connection.
+///
+/// migrant_lib only exposes the applied migrations through `Config::reload`, which
+/// opens a new connection per call, so they're read from its table here instead.
+pub async fn pending_migrations<T: GenericClient>(conn: &T) -> Result<Vec<String>> {
+    let applied = conn
+        .query("select tag from __migrant_migrations", &[])
+        .await
+        .map_err(|e| error::helpers::internal(format!("Failed loading applied migrations: {}", e)))?
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    let config = migrant_config()?;
+    let location = config.migration_location().map_err(|e| {
+        error::helpers::internal(format!("Failed finding migration directory: {}", e))
+    })?;
+    let mut pending = vec![];
+    for entry in std::fs::read_dir(location)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let tag = entry.file_name().to_string_lossy().into_owned();
+        if !applied.contains(&tag) {
+            pending.push(tag);
+        }
+    }
+    pending.sort();
+    Ok(pending)
+}
+
+/// Initialize a new postgres connection pool of at most `n` connections
+pub fn init_pool(n: usize) -> Pool {
     let conn_str = connect_str().expect("Failed to build connection string");
-    let manager =
-        PostgresConnectionManager::new(conn_str, TlsMode::None).expect("Failed to connect to db");
-    r2d2::Pool::builder()
-        .min_idle(Some(n))
-        .build(manager)
+    init_pool_from(&conn_str, n)
+}
+
+/// Initialize a new postgres connection pool of at most `n` connections for the given
+/// connection string. Connections are opened as they're needed.
+pub fn init_pool_from(conn_str: &str, n: usize) -> Pool {
+    let pg_config = conn_str
+        .parse::<tokio_postgres::Config>()
+        .expect("Invalid db connection string");
+    let manager = Manager::from_config(
+        pg_config,
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    Pool::builder(manager)
+        .max_size(n)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(Duration::from_secs(POOL_TIMEOUT_SECS)))
+        .build()
         .expect("Failed to create db pool")
 }
 
-/// Initialize a single `postgres::Connection`
-pub fn init_conn() -> Result<postgres::Connection> {
-    let conn_str = connect_str()?;
-    Ok(postgres::Connection::connect(
-        conn_str,
-        postgres::TlsMode::None,
-    )?)
+/// Open a single connection, outside of the server's pool. Must be called from
+/// within a tokio runtime, which drives the connection.
+pub async fn init_conn() -> Result<DbConn> {
+    Ok(init_pool(1).get().await?)
 }
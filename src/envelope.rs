@@ -0,0 +1,506 @@
+/*!
+Server-side encryption at rest
+
+When an `encryption_key_file` is configured, every completed upload is encrypted
+with its own random data key before being handed off to storage. The data key is
+wrapped (encrypted) with the newest key-encryption key from the key file, and the
+wrapped data key and key id are recorded on the `upload` record.
+
+Blobs are encrypted with AES-256-GCM in fixed size segments so that byte ranges
+can be read without decrypting the whole blob.
+*/
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use chrono::Utc;
+use ring::aead;
+use ring::rand::{self, SecureRandom};
+use uuid::Uuid;
+
+use crate::error::{self, Result};
+use crate::models;
+use crate::storage::Storage;
+
+/// Number of plaintext bytes in each encrypted segment
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// Key file entry (in storage formatting)
+#[derive(Serialize, Deserialize)]
+struct KeyEntry {
+    id: i32,
+    key: String,
+    date_created: String,
+}
+
+/// Key-encryption keys loaded from the configured key file
+///
+/// The key file is reloaded whenever it's modified, so keys added by
+/// `transfer admin keys rotate` are picked up by a running server.
+pub struct KeyRing {
+    path: PathBuf,
+    state: RwLock<KeyRingState>,
+}
+
+/// The key file's modification time, and its keys by id
+type KeyRingState = (Option<SystemTime>, Vec<(i32, Vec<u8>)>);
+impl KeyRing {
+    /// Load the key file at `path`, which must contain at least one key
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let ring = Self {
+            path: path.as_ref().to_path_buf(),
+            state: RwLock::new((None, vec![])),
+        };
+        ring.refresh()?;
+        if ring.keys()?.is_empty() {
+            return Err(error::helpers::internal(format!(
+                "No encryption keys found in {:?}, see `transfer admin keys rotate`",
+                ring.path
+            )));
+        }
+        Ok(ring)
+    }
+
+    fn read_keys(path: &Path) -> Result<Vec<(i32, Vec<u8>)>> {
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let entries: Vec<KeyEntry> = serde_json::from_reader(fs::File::open(path)?)?;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let key = hex::decode(&entry.key)?;
+                if key.len() != KEY_LEN {
+                    return Err(error::helpers::internal(format!(
+                        "Invalid encryption key length for key id={}",
+                        entry.id
+                    )));
+                }
+                Ok((entry.id, key))
+            })
+            .collect()
+    }
+
+    fn write_keys(path: &Path, keys: &[(i32, Vec<u8>)]) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let existing: Vec<KeyEntry> = if path.exists() {
+            serde_json::from_reader(fs::File::open(path)?)?
+        } else {
+            vec![]
+        };
+        let entries = keys
+            .iter()
+            .map(|(id, key)| KeyEntry {
+                id: *id,
+                key: hex::encode(key),
+                date_created: existing
+                    .iter()
+                    .find(|entry| entry.id == *id)
+                    .map(|entry| entry.date_created.clone())
+                    .unwrap_or_else(|| now.clone()),
+            })
+            .collect::<Vec<_>>();
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+            serde_json::to_writer_pretty(&mut file, &entries)?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Reload keys if the key file has been modified since it was last read
+    fn refresh(&self) -> Result<()> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        {
+            let state = self.state.read().expect("key ring lock poisoned");
+            if modified.is_some() && state.0 == modified {
+                return Ok(());
+            }
+        }
+        let keys = Self::read_keys(&self.path)?;
+        let mut state = self.state.write().expect("key ring lock poisoned");
+        *state = (modified, keys);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<(i32, Vec<u8>)>> {
+        self.refresh()?;
+        Ok(self.state.read().expect("key ring lock poisoned").1.clone())
+    }
+
+    /// Return the newest key and its id
+    pub fn current(&self) -> Result<(i32, Vec<u8>)> {
+        self.keys()?
+            .into_iter()
+            .max_by_key(|(id, _)| *id)
+            .ok_or_else(|| error::helpers::internal("No encryption keys available"))
+    }
+
+    /// Return the key with the given `id`
+    pub fn get(&self, id: i32) -> Result<Vec<u8>> {
+        self.keys()?
+            .into_iter()
+            .find(|(key_id, _)| *key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| error::helpers::internal(format!("Unknown encryption key id={}", id)))
+    }
+
+    /// Add a new random key to the key file at `path`, creating it if necessary,
+    /// returning the new key's id
+    pub fn add_key<P: AsRef<Path>>(path: P) -> Result<i32> {
+        let path = path.as_ref();
+        let mut keys = Self::read_keys(path)?;
+        let id = keys.iter().map(|(id, _)| *id).max().unwrap_or(0) + 1;
+        keys.push((id, random_bytes(KEY_LEN)?));
+        Self::write_keys(path, &keys)?;
+        Ok(id)
+    }
+
+    /// Remove all keys from the key file at `path` except the newest key and
+    /// those in `keep`, returning the number of keys removed
+    pub fn prune<P: AsRef<Path>>(path: P, keep: &[i32]) -> Result<usize> {
+        let path = path.as_ref();
+        let keys = Self::read_keys(path)?;
+        let newest = keys.iter().map(|(id, _)| *id).max();
+        let n = keys.len();
+        let keys = keys
+            .into_iter()
+            .filter(|(id, _)| Some(*id) == newest || keep.contains(id))
+            .collect::<Vec<_>>();
+        let removed = n - keys.len();
+        Self::write_keys(path, &keys)?;
+        Ok(removed)
+    }
+}
+
+fn random_bytes(n: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; n];
+    rand::SystemRandom::new().fill(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Nonce of the `n`th segment of a blob. Every blob has its own data key,
+/// so segment indices are never reused with the same key.
+fn segment_nonce(n: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[4..].copy_from_slice(&n.to_be_bytes());
+    nonce
+}
+
+/// Read into `buf` until it's full or `reader` is exhausted, returning the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Wrap (encrypt) a data key with a key-encryption key, binding it to an upload's `uuid`
+pub fn wrap_key(kek: &[u8], uuid: &Uuid, data_key: &[u8]) -> Result<Vec<u8>> {
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, kek)?;
+    let nonce = random_bytes(NONCE_LEN)?;
+    let mut in_out = data_key.to_vec();
+    in_out.extend_from_slice(&[0u8; TAG_LEN]);
+    let n = aead::seal_in_place(&key, &nonce, uuid.as_bytes(), &mut in_out, TAG_LEN)?;
+    let mut wrapped = nonce;
+    wrapped.extend_from_slice(&in_out[..n]);
+    Ok(wrapped)
+}
+
+/// Unwrap (decrypt) a data key previously wrapped with `wrap_key`
+pub fn unwrap_key(kek: &[u8], uuid: &Uuid, wrapped: &[u8]) -> Result<Vec<u8>> {
+    if wrapped.len() < NONCE_LEN + TAG_LEN {
+        return Err(error::helpers::internal("Invalid wrapped data key"));
+    }
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, kek)?;
+    let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+    let mut in_out = sealed.to_vec();
+    let data_key = aead::open_in_place(&key, nonce, uuid.as_bytes(), 0, &mut in_out)?;
+    Ok(data_key.to_vec())
+}
+
+/// Encrypt the file at `src` into a new file at `dest` using `data_key`
+fn encrypt_file(data_key: &[u8], src: &Path, dest: &Path) -> Result<()> {
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, data_key)?;
+    let mut reader = fs::File::open(src)?;
+    let mut writer = io::BufWriter::new(fs::File::create(dest)?);
+    let mut buf = vec![0u8; SEGMENT_SIZE + TAG_LEN];
+    let mut segment = 0;
+    loop {
+        let n = read_full(&mut reader, &mut buf[..SEGMENT_SIZE])?;
+        if n == 0 {
+            break;
+        }
+        let nonce = segment_nonce(segment);
+        let sealed = aead::seal_in_place(&key, &nonce, &[], &mut buf[..n + TAG_LEN], TAG_LEN)?;
+        writer.write_all(&buf[..sealed])?;
+        segment += 1;
+        if n < SEGMENT_SIZE {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hand off a completed upload, staged at `staging_path`, to `storage` under `storage_key`.
+///
+/// When a key ring is present the upload is encrypted first, returning the id of the
+/// key-encryption key used and the wrapped data key to record on the `upload`.
+pub fn store(
+    storage: &dyn Storage,
+    keys: Option<&KeyRing>,
+    uuid: &Uuid,
+    storage_key: &str,
+    staging_path: &Path,
+) -> Result<Option<(i32, Vec<u8>)>> {
+    let keys = match keys {
+        None => {
+            storage.put(storage_key, staging_path)?;
+            return Ok(None);
+        }
+        Some(keys) => keys,
+    };
+    let (key_id, kek) = keys.current()?;
+    let data_key = random_bytes(KEY_LEN)?;
+    let encrypted_path = staging_path.with_extension("enc");
+    encrypt_file(&data_key, staging_path, &encrypted_path)?;
+    fs::remove_file(staging_path)?;
+    storage.put(storage_key, &encrypted_path)?;
+    Ok(Some((key_id, wrap_key(&kek, uuid, &data_key)?)))
+}
+
+/// Open the stored bytes of `upload`, starting at byte `offset` and reading `len` bytes
+/// when a length is specified. Encrypted uploads are transparently decrypted.
+pub fn open(
+    storage: &dyn Storage,
+    keys: Option<&KeyRing>,
+    upload: &models::Upload,
+    offset: u64,
+    len: Option<u64>,
+) -> Result<Box<dyn Read + Send>> {
+    let (key_id, wrapped) = match (upload.encryption_key_id, &upload.encrypted_data_key) {
+        (Some(key_id), Some(wrapped)) => (key_id, wrapped),
+        _ => return storage.get(&upload.file_path, offset, len),
+    };
+    let keys = keys.ok_or_else(|| {
+        error::helpers::internal("Encrypted upload found, but no `encryption_key_file` configured")
+    })?;
+    let data_key = unwrap_key(&keys.get(key_id)?, &upload.uuid, wrapped)?;
+    let len = len.unwrap_or_else(|| (upload.size as u64).saturating_sub(offset));
+    decrypt_range(&data_key, offset, len, |encrypted_offset, encrypted_len| {
+        storage.get(&upload.file_path, encrypted_offset, Some(encrypted_len))
+    })
+}
+
+/// Decrypt `len` bytes starting at plaintext `offset` of a blob encrypted with `data_key`,
+/// reading the encrypted segments covering them with `get(encrypted_offset, encrypted_len)`
+fn decrypt_range<F>(data_key: &[u8], offset: u64, len: u64, get: F) -> Result<Box<dyn Read + Send>>
+where
+    F: FnOnce(u64, u64) -> Result<Box<dyn Read + Send>>,
+{
+    if len == 0 {
+        return Ok(Box::new(io::empty()));
+    }
+    let segment = SEGMENT_SIZE as u64;
+    let first = offset / segment;
+    let last = (offset + len - 1) / segment;
+    let encrypted_offset = first * (segment + TAG_LEN as u64);
+    let encrypted_len = (last - first + 1) * (segment + TAG_LEN as u64);
+    let inner = get(encrypted_offset, encrypted_len)?;
+    Ok(Box::new(DecryptingReader {
+        inner,
+        key: aead::OpeningKey::new(&aead::AES_256_GCM, data_key)?,
+        segment: first,
+        skip: (offset - first * segment) as usize,
+        remaining: len,
+        buf: vec![],
+        pos: 0,
+    }))
+}
+
+/// Decrypts a stream of encrypted segments
+struct DecryptingReader {
+    inner: Box<dyn Read + Send>,
+    key: aead::OpeningKey,
+    segment: u64,
+    skip: usize,
+    remaining: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+impl Read for DecryptingReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || out.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.buf.len() {
+            let mut sealed = vec![0u8; SEGMENT_SIZE + TAG_LEN];
+            let n = read_full(&mut self.inner, &mut sealed)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "encrypted upload ended early",
+                ));
+            }
+            let nonce = segment_nonce(self.segment);
+            let plain = aead::open_in_place(&self.key, &nonce, &[], 0, &mut sealed[..n])
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+            self.buf = plain.to_vec();
+            self.pos = self.skip.min(self.buf.len());
+            self.skip = 0;
+            self.segment += 1;
+        }
+        let available = (self.buf.len() - self.pos) as u64;
+        let n = (out.len() as u64).min(available).min(self.remaining) as usize;
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Encrypt `plain` with `data_key`, returning the encrypted blob
+    fn encrypt(data_key: &[u8], plain: &[u8]) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let name = hex::encode(Uuid::new_v4().as_bytes());
+        let (src, dest) = (dir.join(&name), dir.join(format!("{}.enc", name)));
+        fs::write(&src, plain).unwrap();
+        encrypt_file(data_key, &src, &dest).unwrap();
+        let encrypted = fs::read(&dest).unwrap();
+        fs::remove_file(&src).unwrap();
+        fs::remove_file(&dest).unwrap();
+        encrypted
+    }
+
+    /// Decrypt `len` bytes of `encrypted` starting at plaintext `offset`
+    fn decrypt(data_key: &[u8], encrypted: &[u8], offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut reader = decrypt_range(data_key, offset, len, |start, len| {
+            let start = (start as usize).min(encrypted.len());
+            let end = (start + len as usize).min(encrypted.len());
+            Ok(Box::new(Cursor::new(encrypted[start..end].to_vec())))
+        })
+        .unwrap();
+        let mut plain = vec![];
+        reader.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    fn plain(len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n % 251) as u8).collect()
+    }
+
+    #[test]
+    fn wrap_unwrap_key() {
+        let kek = random_bytes(KEY_LEN).unwrap();
+        let data_key = random_bytes(KEY_LEN).unwrap();
+        let uuid = Uuid::new_v4();
+        let wrapped = wrap_key(&kek, &uuid, &data_key).unwrap();
+        assert_eq!(wrapped.len(), NONCE_LEN + KEY_LEN + TAG_LEN);
+        assert_eq!(unwrap_key(&kek, &uuid, &wrapped).unwrap(), data_key);
+
+        // wrapped keys are bound to their kek and upload
+        let other_kek = random_bytes(KEY_LEN).unwrap();
+        assert!(unwrap_key(&other_kek, &uuid, &wrapped).is_err());
+        assert!(unwrap_key(&kek, &Uuid::new_v4(), &wrapped).is_err());
+        assert!(unwrap_key(&kek, &uuid, &wrapped[..NONCE_LEN + TAG_LEN - 1]).is_err());
+        let mut tampered = wrapped.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(unwrap_key(&kek, &uuid, &tampered).is_err());
+    }
+
+    #[test]
+    fn encrypt_segments_round_trip() {
+        let data_key = random_bytes(KEY_LEN).unwrap();
+        for &len in &[
+            0,
+            1,
+            SEGMENT_SIZE - 1,
+            SEGMENT_SIZE,
+            SEGMENT_SIZE + 1,
+            3 * SEGMENT_SIZE + 100,
+        ] {
+            let plain = plain(len);
+            let encrypted = encrypt(&data_key, &plain);
+            let segments = len.div_ceil(SEGMENT_SIZE);
+            assert_eq!(encrypted.len(), len + segments * TAG_LEN, "len {}", len);
+            assert_eq!(
+                decrypt(&data_key, &encrypted, 0, len as u64).unwrap(),
+                plain,
+                "len {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn decrypt_ranges_across_segments() {
+        let data_key = random_bytes(KEY_LEN).unwrap();
+        let len = 3 * SEGMENT_SIZE + 100;
+        let plain = plain(len);
+        let encrypted = encrypt(&data_key, &plain);
+        let segment = SEGMENT_SIZE;
+        let ranges = [
+            (0, 10),
+            (segment - 5, 10),
+            (segment, segment),
+            (segment - 1, segment + 2),
+            (2 * segment + 7, segment + 93),
+            (len - 1, 1),
+            (5, len - 5),
+        ];
+        for &(offset, n) in &ranges {
+            assert_eq!(
+                decrypt(&data_key, &encrypted, offset as u64, n as u64).unwrap(),
+                &plain[offset..offset + n],
+                "range {}+{}",
+                offset,
+                n
+            );
+        }
+        assert!(decrypt(&data_key, &encrypted, 3, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decrypt_rejects_tampering() {
+        let data_key = random_bytes(KEY_LEN).unwrap();
+        let plain = plain(2 * SEGMENT_SIZE);
+        let mut encrypted = encrypt(&data_key, &plain);
+        encrypted[SEGMENT_SIZE + TAG_LEN + 1] ^= 1;
+        // the first segment is intact, the second isn't
+        assert_eq!(decrypt(&data_key, &encrypted, 0, 10).unwrap(), &plain[..10]);
+        let err = decrypt(&data_key, &encrypted, SEGMENT_SIZE as u64, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let other_key = random_bytes(KEY_LEN).unwrap();
+        assert!(decrypt(&other_key, &encrypted, 0, 10).is_err());
+        let truncated = &encrypted[..SEGMENT_SIZE];
+        let err = decrypt(&data_key, truncated, 0, 2 * SEGMENT_SIZE as u64).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -12,6 +12,10 @@ pub mod helpers {
         Error::from_kind(ErrorKind::Internal(s.as_ref().to_string()))
     }
 
+    pub fn config<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::Config(s.as_ref().to_string()))
+    }
+
     pub fn bad_request<T: AsRef<str>>(s: T) -> self::Error {
         Error::from_kind(ErrorKind::BadRequest(s.as_ref().to_string()))
     }
@@ -28,6 +32,38 @@ pub mod helpers {
         Error::from_kind(ErrorKind::OutOfSpace(s.as_ref().to_string()))
     }
 
+    pub fn too_many_requests<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::TooManyRequests(s.as_ref().to_string()))
+    }
+
+    pub fn locked<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::Locked(s.as_ref().to_string()))
+    }
+
+    pub fn busy<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::Busy(s.as_ref().to_string()))
+    }
+
+    pub fn shutting_down<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::ShuttingDown(s.as_ref().to_string()))
+    }
+
+    pub fn range_not_satisfiable<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::RangeNotSatisfiable(s.as_ref().to_string()))
+    }
+
+    pub fn upload_too_late<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::UploadTooLate(s.as_ref().to_string()))
+    }
+
+    pub fn expired<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::Expired(s.as_ref().to_string()))
+    }
+
+    pub fn download_limit_reached<T: AsRef<str>>(s: T) -> self::Error {
+        Error::from_kind(ErrorKind::DownloadLimitReached(s.as_ref().to_string()))
+    }
+
     pub fn does_not_exist<T: AsRef<str>>(s: T) -> self::Error {
         Error::from_kind(ErrorKind::DoesNotExist(s.as_ref().to_string()))
     }
@@ -63,6 +99,8 @@ impl<T, E: Into<Error>> Rejectable<T, E> for std::result::Result<T, E> {
 #[derive(Debug)]
 pub struct Error {
     kind: Box<ErrorKind>,
+    retry_after: Option<u64>,
+    complete_length: Option<u64>,
 }
 impl Error {
     pub fn kind(&self) -> &ErrorKind {
@@ -72,12 +110,218 @@ impl Error {
     pub fn from_kind(kind: ErrorKind) -> Self {
         Self {
             kind: Box::new(kind),
+            retry_after: None,
+            complete_length: None,
         }
     }
 
+    /// Suggest clients retry after `secs` seconds
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+
+    /// Size of the resource a range could not be satisfied against,
+    /// sent back as `Content-Range: bytes */<size>`
+    pub fn with_complete_length(mut self, size: u64) -> Self {
+        self.complete_length = Some(size);
+        self
+    }
+
+    pub fn complete_length(&self) -> Option<u64> {
+        self.complete_length
+    }
+
     pub fn is_does_not_exist(&self) -> bool {
         matches!(self.kind(), self::ErrorKind::DoesNotExist(_))
     }
+
+    /// The code returned to clients for this error
+    pub fn code(&self) -> ErrorCode {
+        use self::ErrorKind::*;
+        match *self.kind() {
+            BadRequest(_) => ErrorCode::BadRequest,
+            UploadTooLate(_) => ErrorCode::UploadTooLate,
+            InvalidAuth(_) => ErrorCode::WrongPassword,
+            DoesNotExist(_) => ErrorCode::NotFound,
+            Expired(_) => ErrorCode::Expired,
+            DownloadLimitReached(_) => ErrorCode::DownloadLimitReached,
+            UploadTooLarge(_) => ErrorCode::UploadTooLarge,
+            RangeNotSatisfiable(_) => ErrorCode::RangeNotSatisfiable,
+            Locked(_) => ErrorCode::Locked,
+            TooManyRequests(_) => ErrorCode::RateLimited,
+            OutOfSpace(_) => ErrorCode::OutOfSpace,
+            Busy(_) => ErrorCode::Busy,
+            ShuttingDown(_) => ErrorCode::ShuttingDown,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The message returned to clients for this error. Internal errors aren't described.
+    pub fn public_message(&self) -> String {
+        use self::ErrorKind::*;
+        match *self.kind() {
+            BadRequest(ref s)
+            | UploadTooLate(ref s)
+            | InvalidAuth(ref s)
+            | DoesNotExist(ref s)
+            | Expired(ref s)
+            | DownloadLimitReached(ref s)
+            | UploadTooLarge(ref s)
+            | RangeNotSatisfiable(ref s)
+            | Locked(ref s)
+            | TooManyRequests(ref s)
+            | OutOfSpace(ref s)
+            | Busy(ref s)
+            | ShuttingDown(ref s) => s.clone(),
+            _ => "something went wrong".to_string(),
+        }
+    }
+}
+
+/// Stable, machine readable error codes, returned in error responses as
+/// `{"code": "<code>", "error": "<message>"}`, along with `"retry_after": <seconds>`
+/// (and a matching `Retry-After` header) when retrying later may succeed.
+///
+/// Some cases are deliberately indistinguishable, so a key can't be probed without
+/// its password:
+///     - Unknown keys, deleted uploads, uploads removed by the sweeper, bundle members
+///       requested directly, and uploads expired by `AuthFailureAction::Delete` are all
+///       `not_found`.
+///     - `expired` and `download_limit_reached` are only returned once the access
+///       password has been verified. Checks made before verification return `not_found`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// Malformed or invalid request (400)
+    BadRequest,
+    /// An upload's bytes arrived after the upload timeout (400)
+    UploadTooLate,
+    /// Wrong password or content hash (401)
+    WrongPassword,
+    /// No such upload or bundle, or it can't be revealed to be expired (404)
+    NotFound,
+    /// The upload or bundle has expired (404)
+    Expired,
+    /// The upload or bundle's download limit has been reached (404)
+    DownloadLimitReached,
+    /// Upload exceeds the size limit, or its declared size (413)
+    UploadTooLarge,
+    /// Unsatisfiable `Range` header (416)
+    RangeNotSatisfiable,
+    /// Locked after too many failed password attempts (423)
+    Locked,
+    /// Too many password attempts, retry after `retry_after` seconds (429)
+    RateLimited,
+    /// Not enough storage space (503)
+    OutOfSpace,
+    /// Too many requests in progress, retry after `retry_after` seconds (503)
+    Busy,
+    /// The server is shutting down (503)
+    ShuttingDown,
+    /// Unexpected server error (500)
+    Internal,
+}
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::BadRequest,
+        ErrorCode::UploadTooLate,
+        ErrorCode::WrongPassword,
+        ErrorCode::NotFound,
+        ErrorCode::Expired,
+        ErrorCode::DownloadLimitReached,
+        ErrorCode::UploadTooLarge,
+        ErrorCode::RangeNotSatisfiable,
+        ErrorCode::Locked,
+        ErrorCode::RateLimited,
+        ErrorCode::OutOfSpace,
+        ErrorCode::Busy,
+        ErrorCode::ShuttingDown,
+        ErrorCode::Internal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        use self::ErrorCode::*;
+        match *self {
+            BadRequest => "bad_request",
+            UploadTooLate => "upload_too_late",
+            WrongPassword => "wrong_password",
+            NotFound => "not_found",
+            Expired => "expired",
+            DownloadLimitReached => "download_limit_reached",
+            UploadTooLarge => "upload_too_large",
+            RangeNotSatisfiable => "range_not_satisfiable",
+            Locked => "locked",
+            RateLimited => "rate_limited",
+            OutOfSpace => "out_of_space",
+            Busy => "busy",
+            ShuttingDown => "shutting_down",
+            Internal => "internal",
+        }
+    }
+
+    /// Parse a code returned by the server, `None` if it's unknown
+    pub fn parse(code: &str) -> Option<Self> {
+        use self::ErrorCode::*;
+        Some(match code {
+            "bad_request" => BadRequest,
+            "upload_too_late" => UploadTooLate,
+            "wrong_password" => WrongPassword,
+            "not_found" => NotFound,
+            "expired" => Expired,
+            "download_limit_reached" => DownloadLimitReached,
+            "upload_too_large" => UploadTooLarge,
+            "range_not_satisfiable" => RangeNotSatisfiable,
+            "locked" => Locked,
+            "rate_limited" => RateLimited,
+            "out_of_space" => OutOfSpace,
+            "busy" => Busy,
+            "shutting_down" => ShuttingDown,
+            "internal" => Internal,
+            _ => return None,
+        })
+    }
+
+    /// An error of the kind this code is generated from, with message `s`
+    pub fn into_error<T: AsRef<str>>(self, s: T) -> Error {
+        use self::ErrorCode::*;
+        let s = s.as_ref().to_string();
+        Error::from_kind(match self {
+            BadRequest => ErrorKind::BadRequest(s),
+            UploadTooLate => ErrorKind::UploadTooLate(s),
+            WrongPassword => ErrorKind::InvalidAuth(s),
+            NotFound => ErrorKind::DoesNotExist(s),
+            Expired => ErrorKind::Expired(s),
+            DownloadLimitReached => ErrorKind::DownloadLimitReached(s),
+            UploadTooLarge => ErrorKind::UploadTooLarge(s),
+            RangeNotSatisfiable => ErrorKind::RangeNotSatisfiable(s),
+            Locked => ErrorKind::Locked(s),
+            RateLimited => ErrorKind::TooManyRequests(s),
+            OutOfSpace => ErrorKind::OutOfSpace(s),
+            Busy => ErrorKind::Busy(s),
+            ShuttingDown => ErrorKind::ShuttingDown(s),
+            Internal => ErrorKind::Internal(format!("Server error: {}", s)),
+        })
+    }
+
+    pub fn status(&self) -> warp::http::StatusCode {
+        use self::ErrorCode::*;
+        use warp::http::StatusCode as S;
+        match *self {
+            BadRequest | UploadTooLate => S::BAD_REQUEST,
+            WrongPassword => S::UNAUTHORIZED,
+            NotFound | Expired | DownloadLimitReached => S::NOT_FOUND,
+            UploadTooLarge => S::PAYLOAD_TOO_LARGE,
+            RangeNotSatisfiable => S::RANGE_NOT_SATISFIABLE,
+            Locked => S::LOCKED,
+            RateLimited => S::TOO_MANY_REQUESTS,
+            OutOfSpace | Busy | ShuttingDown => S::SERVICE_UNAVAILABLE,
+            Internal => S::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -86,10 +330,19 @@ impl std::fmt::Display for Error {
         match *self.kind() {
             S(ref s) => write!(f, "{}", s),
             Internal(ref s) => write!(f, "InternalError: {}", s),
+            Config(ref s) => write!(f, "ConfigError: {}", s),
             BadRequest(ref s) => write!(f, "BadRequest: {}", s),
             UploadTooLarge(ref s) => write!(f, "UploadTooLarge: {}", s),
             OutOfSpace(ref s) => write!(f, "OutOfSpace: {}", s),
+            ShuttingDown(ref s) => write!(f, "ShuttingDown: {}", s),
+            Busy(ref s) => write!(f, "Busy: {}", s),
+            TooManyRequests(ref s) => write!(f, "TooManyRequests: {}", s),
+            Locked(ref s) => write!(f, "Locked: {}", s),
+            RangeNotSatisfiable(ref s) => write!(f, "RangeNotSatisfiable: {}", s),
             DoesNotExist(ref s) => write!(f, "DoesNotExist: {}", s),
+            Expired(ref s) => write!(f, "Expired: {}", s),
+            DownloadLimitReached(ref s) => write!(f, "DownloadLimitReached: {}", s),
+            UploadTooLate(ref s) => write!(f, "UploadTooLate: {}", s),
             MultipleRecords(ref s) => write!(f, "MultipleRecords: {}", s),
             InvalidAuth(ref s) => write!(f, "InvalidAuth: {}", s),
 
@@ -99,7 +352,9 @@ impl std::fmt::Display for Error {
             Uuid(ref e) => write!(f, "UuidError: {}", e),
             Hex(ref e) => write!(f, "HexError: {}", e),
             Postgres(ref e) => write!(f, "PostgresError: {}", e),
+            Reqwest(ref e) => write!(f, "ReqwestError: {}", e),
             ConnError(ref e) => write!(f, "ConnError: {}", e),
+            Join(ref e) => write!(f, "JoinError: {}", e),
             RingUnspecified(ref e) => write!(f, "RingUnspecified: {}", e),
             Xdg(ref e) => write!(f, "Xdg: {}", e),
             ParseAddr(ref e) => write!(f, "ParseAddr: {}", e),
@@ -107,6 +362,8 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl warp::reject::Reject for Error {}
+
 impl std::error::Error for Error {
     fn description(&self) -> &str {
         "transfer error"
@@ -121,7 +378,9 @@ impl std::error::Error for Error {
             Uuid(ref e) => e,
             Hex(ref e) => e,
             Postgres(ref e) => e,
+            Reqwest(ref e) => e,
             ConnError(ref e) => e,
+            Join(ref e) => e,
             RingUnspecified(ref e) => e,
             Xdg(ref e) => e,
             ParseAddr(ref e) => e,
@@ -134,20 +393,31 @@ impl std::error::Error for Error {
 pub enum ErrorKind {
     S(String),
     Internal(String),
+    Config(String),
     BadRequest(String),
     UploadTooLarge(String),
     OutOfSpace(String),
+    ShuttingDown(String),
+    Busy(String),
+    TooManyRequests(String),
+    Locked(String),
+    RangeNotSatisfiable(String),
     DoesNotExist(String),
+    Expired(String),
+    DownloadLimitReached(String),
+    UploadTooLate(String),
     MultipleRecords(String),
     InvalidAuth(String),
 
     Io(io::Error),
     Warp(warp::Error),
     Json(serde_json::Error),
-    Uuid(uuid::ParseError),
+    Uuid(uuid::Error),
     Hex(hex::FromHexError),
-    Postgres(postgres::error::Error),
-    ConnError(r2d2::Error),
+    Postgres(tokio_postgres::Error),
+    Reqwest(reqwest::Error),
+    ConnError(deadpool_postgres::PoolError),
+    Join(tokio::task::JoinError),
     RingUnspecified(ring::error::Unspecified),
     Xdg(xdg::BaseDirectoriesError),
     ParseAddr(net::AddrParseError),
@@ -155,88 +425,78 @@ pub enum ErrorKind {
 
 impl From<&str> for Error {
     fn from(s: &str) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::S(s.into())),
-        }
+        Error::from_kind(ErrorKind::S(s.into()))
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Io(e)),
-        }
+        Error::from_kind(ErrorKind::Io(e))
     }
 }
 
 impl From<warp::Error> for Error {
     fn from(e: warp::Error) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Warp(e)),
-        }
+        Error::from_kind(ErrorKind::Warp(e))
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Json(e)),
-        }
+        Error::from_kind(ErrorKind::Json(e))
     }
 }
 
-impl From<uuid::ParseError> for Error {
-    fn from(e: uuid::ParseError) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Uuid(e)),
-        }
+impl From<uuid::Error> for Error {
+    fn from(e: uuid::Error) -> Error {
+        Error::from_kind(ErrorKind::Uuid(e))
     }
 }
 
 impl From<hex::FromHexError> for Error {
     fn from(e: hex::FromHexError) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Hex(e)),
-        }
+        Error::from_kind(ErrorKind::Hex(e))
     }
 }
 
-impl From<postgres::error::Error> for Error {
-    fn from(e: postgres::error::Error) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Postgres(e)),
-        }
+impl From<tokio_postgres::Error> for Error {
+    fn from(e: tokio_postgres::Error) -> Error {
+        Error::from_kind(ErrorKind::Postgres(e))
     }
 }
 
-impl From<r2d2::Error> for Error {
-    fn from(e: r2d2::Error) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::ConnError(e)),
-        }
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::from_kind(ErrorKind::Reqwest(e))
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for Error {
+    fn from(e: deadpool_postgres::PoolError) -> Error {
+        Error::from_kind(ErrorKind::ConnError(e))
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Error {
+        Error::from_kind(ErrorKind::Join(e))
     }
 }
 
 impl From<ring::error::Unspecified> for Error {
     fn from(e: ring::error::Unspecified) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::RingUnspecified(e)),
-        }
+        Error::from_kind(ErrorKind::RingUnspecified(e))
     }
 }
 
 impl From<xdg::BaseDirectoriesError> for Error {
     fn from(e: xdg::BaseDirectoriesError) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::Xdg(e)),
-        }
+        Error::from_kind(ErrorKind::Xdg(e))
     }
 }
 
 impl From<net::AddrParseError> for Error {
     fn from(e: net::AddrParseError) -> Error {
-        Error {
-            kind: Box::new(ErrorKind::ParseAddr(e)),
-        }
+        Error::from_kind(ErrorKind::ParseAddr(e))
     }
 }
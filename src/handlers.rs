@@ -1,52 +1,362 @@
 /*!
 Route handlers
 */
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use chrono::{DateTime, Duration, Utc};
-use futures::{self, Future};
+use deadpool_postgres::GenericClient;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use hex::FromHex;
-use hyper;
-use tokio;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task;
 use uuid::Uuid;
 use warp;
 
+use crate::audit;
 use crate::auth;
+use crate::config::{AuthFailureAction, Config};
+use crate::db;
+use crate::envelope;
 use crate::error;
-use crate::models::{self, CONFIG};
-use crate::service::Ctx;
+use crate::hashing::{Hasher, Verification};
+use crate::health;
+use crate::metrics;
+use crate::models::{self, Lockable};
+use crate::ratelimit::RateLimiter;
+use crate::service::{self, Ctx};
+use crate::shutdown::Transfer;
+use crate::storage;
+use crate::webhooks;
+
+/// Resolve a handler's outcome, rejecting errors so they're rendered by `service::handle_error`
+async fn respond<R, F>(handler: F) -> Result<R, warp::Rejection>
+where
+    F: Future<Output = error::Result<R>>,
+{
+    handler.await.map_err(error::helpers::reject)
+}
+
+api_type! {
+    /// Greeting
+    #[derive(Serialize, Deserialize)]
+    pub struct HelloResp {
+        pub message: String,
+    }
+}
+
+/// Greet api clients
+pub fn api_hello() -> impl warp::Reply {
+    warp::reply::json(&HelloResp {
+        message: String::from("hello!"),
+    })
+}
+
+api_type! {
+    /// Configurable upload constraints
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadDefaultsResp {
+        pub upload_limit_bytes: i64,
+        pub upload_lifespan_secs_default: i64,
+        pub upload_lifespan_secs_min: i64,
+        pub upload_lifespan_secs_max: i64,
+        pub download_limit_default: Option<i32>,
+        pub download_limit_max: Option<i32>,
+        pub allow_unlimited_downloads: bool,
+    }
+}
 
 /// Return the default configurable upload constraints
-pub fn api_upload_defaults() -> impl warp::Reply {
-    let defaults = json!({
-        "upload_limit_bytes": CONFIG.upload_limit_bytes,
-        "upload_lifespan_secs_default": CONFIG.upload_lifespan_secs_default,
-        "download_limit_default": CONFIG.download_limit_default,
-    });
-    warp::reply::json(&defaults)
-}
-
-/// Upload Initialize post info (in transport formatting)
-#[derive(Deserialize)]
-pub struct UploadInitPost {
-    nonce: String,
-    file_name_hash: String,
-    size: u64,
-    content_hash: String,
-    access_password: String,
-    deletion_password: Option<String>,
-    download_limit: Option<u32>,
+pub fn api_upload_defaults(ctx: Ctx) -> impl warp::Reply {
+    let config = ctx.config.get();
+    warp::reply::json(&UploadDefaultsResp {
+        upload_limit_bytes: config.upload_limit_bytes,
+        upload_lifespan_secs_default: config.upload_lifespan_secs_default,
+        upload_lifespan_secs_min: config.upload_lifespan_secs_min,
+        upload_lifespan_secs_max: config.upload_lifespan_secs_max,
+        download_limit_default: config.download_limit_default,
+        download_limit_max: config.download_limit_max,
+        allow_unlimited_downloads: config.allow_unlimited_downloads,
+    })
+}
+
+api_type! {
+    /// Service status and version
+    #[derive(Serialize, Deserialize)]
+    pub struct StatusResp {
+        pub status: String,
+        pub version: String,
+    }
+}
+
+/// Status and version, also serving as the liveness check
+pub fn status() -> impl warp::Reply {
+    warp::reply::json(&StatusResp {
+        status: String::from("ok"),
+        version: service::COMMIT_HASH.clone(),
+    })
+}
+
+api_type! {
+    /// Outcome of a single readiness check, see `health::Check`
+    #[derive(Serialize, Deserialize)]
+    pub struct ReadinessCheck {
+        pub ok: bool,
+        pub details: serde_json::Value,
+    }
+}
+
+api_type! {
+    /// Readiness checks by name, `status` is `ok` when all pass, otherwise `unavailable`
+    #[derive(Serialize, Deserialize)]
+    pub struct ReadinessResp {
+        pub status: String,
+        pub version: String,
+        pub checks: BTreeMap<String, ReadinessCheck>,
+    }
+}
+
+/// Readiness, checking the database, migrations, storage backend, and sweeper.
+/// Responds with a `503` describing the failed checks when any check fails.
+pub async fn status_ready(ctx: Ctx) -> Result<impl warp::Reply, warp::Rejection> {
+    let checks = health::readiness(&ctx).await;
+    let ready = checks.iter().all(|check| check.ok);
+    let body = ReadinessResp {
+        status: String::from(if ready { "ok" } else { "unavailable" }),
+        version: service::COMMIT_HASH.clone(),
+        checks: checks
+            .into_iter()
+            .map(|check| {
+                let result = ReadinessCheck {
+                    ok: check.ok,
+                    details: check.details,
+                };
+                (check.name.to_string(), result)
+            })
+            .collect(),
+    };
+    let status = if ready {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+/// Prometheus metrics, in the text exposition format
+pub async fn metrics(ctx: Ctx) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let body = metrics::render(&ctx).await?;
+        Ok(warp::http::Response::builder()
+            .header(warp::http::header::CONTENT_TYPE, metrics::CONTENT_TYPE)
+            .body(body))
+    })
+    .await
+}
+
+api_type! {
+    /// Key of an initialized upload, to upload its bytes to
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadInitResp {
+        pub key: String,
+    }
+}
+
+api_type! {
+    /// Upload Initialize post info (in transport formatting)
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadInitPost {
+        pub nonce: String,
+        pub file_name_hash: String,
+        pub size: u64,
+        pub content_hash: String,
+        pub access_password: String,
+        pub deletion_password: Option<String>,
+        pub download_limit: Option<u32>,
+        pub lifespan: Option<i64>,
+        pub webhook_url: Option<String>,
+        pub webhook_secret: Option<String>,
+    }
+}
+/// Return the expiration date of an upload with the given `lifespan` in seconds
+fn lifespan_expire_date(lifespan: i64) -> error::Result<DateTime<Utc>> {
+    Utc::now()
+        .checked_add_signed(Duration::seconds(lifespan))
+        .ok_or_else(|| error::helpers::bad_request("lifespan too large"))
+}
+
+/// Check client requested limits against the configured bounds and any `token` maximums
+fn check_requested_limits(
+    config: &Config,
+    token: Option<&models::ApiToken>,
+    lifespan: Option<i64>,
+    download_limit: Option<i32>,
+) -> error::Result<()> {
+    if let Some(lifespan) = lifespan {
+        if lifespan < config.upload_lifespan_secs_min {
+            return Err(error::helpers::bad_request(format!(
+                "lifespan too small, min secs: {}",
+                config.upload_lifespan_secs_min
+            )));
+        }
+        let max = token
+            .and_then(|token| token.max_lifespan_secs)
+            .map_or(config.upload_lifespan_secs_max, |max| {
+                max.min(config.upload_lifespan_secs_max)
+            });
+        if lifespan > max {
+            return Err(error::helpers::bad_request(format!(
+                "lifespan too large, max secs: {}",
+                max
+            )));
+        }
+    }
+    if let Some(limit) = download_limit {
+        if limit <= 0 {
+            return Err(error::helpers::bad_request(
+                "download limit must be greater than zero",
+            ));
+        }
+        let max = match (
+            config.download_limit_max,
+            token.and_then(|token| token.max_download_limit),
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(max) = max {
+            if limit > max {
+                return Err(error::helpers::bad_request(format!(
+                    "download limit too large, max: {}",
+                    max
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolve the expiration date and download limit of a new upload or bundle, falling
+/// back to `config.upload_lifespan_secs_default` and `config.download_limit_default`.
+///
+/// Requested values outside the configured bounds or over a `token`'s maximums are
+/// rejected, as are missing download limits when `config.allow_unlimited_downloads`
+/// is false. Defaults over a token's maximums are lowered to the maximum.
+fn resolve_limits(
+    config: &Config,
+    token: Option<&models::ApiToken>,
     lifespan: Option<i64>,
+    download_limit: Option<i32>,
+) -> error::Result<(DateTime<Utc>, Option<i32>)> {
+    if download_limit.is_none() && !config.allow_unlimited_downloads {
+        return Err(error::helpers::bad_request(
+            "download limit required, unlimited downloads aren't allowed",
+        ));
+    }
+    check_requested_limits(config, token, lifespan, download_limit)?;
+    let mut resolved_lifespan = lifespan.unwrap_or(config.upload_lifespan_secs_default);
+    let mut resolved_download_limit = download_limit.or(config.download_limit_default);
+    if let Some(token) = token {
+        if let Some(max) = token.max_lifespan_secs {
+            resolved_lifespan = resolved_lifespan.min(max);
+        }
+        if let Some(max) = token.max_download_limit {
+            resolved_download_limit = Some(resolved_download_limit.map_or(max, |n| n.min(max)));
+        }
+    }
+    Ok((
+        lifespan_expire_date(resolved_lifespan)?,
+        resolved_download_limit,
+    ))
 }
+
+/// Find the api token presented in an `Authorization: Bearer <token>` header. Returns
+/// `ErrorKind::InvalidAuth` for unknown or revoked tokens, and when no token is presented
+/// but `config.require_api_token` is set.
+async fn find_api_token<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    authorization: Option<&str>,
+) -> error::Result<Option<models::ApiToken>> {
+    let header = match authorization {
+        Some(header) => header,
+        None if config.require_api_token => {
+            return Err(error::helpers::invalid_auth("api token required"))
+        }
+        None => return Ok(None),
+    };
+    let token = header.trim().trim_start_matches("Bearer ").trim();
+    let token =
+        Vec::from_hex(token).map_err(|_| error::helpers::invalid_auth("invalid api token"))?;
+    match models::ApiToken::find_by_hash(conn, &auth::sha256(&token)).await {
+        Ok(token) => Ok(Some(token)),
+        Err(ref e) if e.is_does_not_exist() => {
+            Err(error::helpers::invalid_auth("invalid api token"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Check the sizes of new uploads against a `token`'s max file size and remaining quota
+fn check_token_sizes(token: &models::ApiToken, sizes: &[i64]) -> error::Result<()> {
+    if let Some(max) = token.max_file_bytes {
+        if sizes.iter().any(|size| *size > max) {
+            return Err(error::helpers::too_large(format!(
+                "Upload too large, max bytes: {}",
+                max
+            )));
+        }
+    }
+    if !token.can_fit(sizes.iter().sum()) {
+        return Err(error::helpers::too_large(format!(
+            "Upload quota exceeded, quota bytes: {}, used bytes: {}",
+            token.quota_bytes.unwrap_or_default(),
+            token.total_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// Check a finished upload's `size` still fits in its api token's quota,
+/// since other uploads with the token may have finished in the meantime.
+/// The token is locked until the end of the transaction, so uploads finishing
+/// concurrently are checked one after another.
+async fn check_api_token_quota<T: GenericClient>(
+    conn: &T,
+    api_token: Option<i32>,
+    size: i64,
+) -> error::Result<()> {
+    if let Some(ref id) = api_token {
+        let token = models::ApiToken::find_by_id_for_update(conn, id).await?;
+        check_token_sizes(&token, &[size])?;
+    }
+    Ok(())
+}
+
+/// Check a finished upload's `size` fits in the server's storage and its api token's quota.
+/// The `status` record is locked until the end of the transaction, so uploads finishing
+/// concurrently can't together exceed `max_combined_upload_bytes`.
+async fn check_room<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    api_token: Option<i32>,
+    size: i64,
+) -> error::Result<()> {
+    if !models::Status::load_for_update(conn)
+        .await?
+        .has_room(config, size)
+    {
+        return Err(error::helpers::out_of_space("Server out of storage space"));
+    }
+    check_api_token_quota(conn, api_token, size).await
+}
+
 impl UploadInitPost {
     fn decode_hex(&self) -> error::Result<UploadInit> {
-        let lifespan = Duration::seconds(
-            self.lifespan
-                .unwrap_or(models::CONFIG.upload_lifespan_secs_default),
-        );
-        let expire_date = Utc::now()
-            .checked_add_signed(lifespan)
-            .ok_or("lifespan too large")?;
         let deletion_password = match self.deletion_password {
             Some(ref hex) => Some(Vec::from_hex(hex)?),
             None => None,
@@ -58,11 +368,10 @@ impl UploadInitPost {
             content_hash: Vec::from_hex(&self.content_hash)?,
             access_password: Vec::from_hex(&self.access_password)?,
             deletion_password,
-            download_limit: self
-                .download_limit
-                .map(|n| n as i32)
-                .or(CONFIG.download_limit_default),
-            expire_date,
+            download_limit: self.download_limit.map(|n| n as i32),
+            lifespan: self.lifespan,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.as_ref().map(|s| s.as_bytes().to_vec()),
         })
     }
 }
@@ -77,7 +386,28 @@ struct UploadInit {
     access_password: Vec<u8>,
     deletion_password: Option<Vec<u8>>,
     download_limit: Option<i32>,
-    expire_date: DateTime<Utc>,
+    lifespan: Option<i64>,
+    webhook_url: Option<String>,
+    webhook_secret: Option<Vec<u8>>,
+}
+
+/// Check an upload's requested webhook is allowed and complete. Its host is resolved
+/// and checked by `webhooks::check_upload_url` later, off the runtime.
+fn check_upload_webhook(config: &Config, info: &UploadInit) -> error::Result<()> {
+    if info.webhook_url.is_none() && info.webhook_secret.is_none() {
+        return Ok(());
+    }
+    if !config.allow_upload_webhooks {
+        return Err(error::helpers::bad_request(
+            "upload webhooks are not allowed",
+        ));
+    }
+    match (&info.webhook_url, &info.webhook_secret) {
+        (Some(ref url), Some(ref secret)) if !secret.is_empty() => webhooks::check_url(url),
+        _ => Err(error::helpers::bad_request(
+            "webhook_url and webhook_secret must be provided together",
+        )),
+    }
 }
 
 /// Initialize a new upload
@@ -86,45 +416,59 @@ struct UploadInit {
 /// e.g.)
 ///   format!("{}/api/upload?key={}", "http://localhost:3000", "...long-key...")
 ///
-pub fn api_upload_init(
+/// An api token may be presented in an `Authorization: Bearer <token>` header,
+/// holding the upload to the token's limits and quota.
+///
+/// When `allow_upload_webhooks` is enabled, a `webhook_url` and `webhook_secret` may be
+/// supplied to be notified of the upload's downloads and deletion, see `webhooks`.
+pub async fn api_upload_init(
     ctx: Ctx,
+    authorization: Option<String>,
     info: UploadInitPost,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
-    let info =
-        futures::future::result::<UploadInit, error::Error>(|| -> error::Result<UploadInit> {
-            let info = info
-                .decode_hex()
-                .map_err(|_| error::helpers::bad_request("malformed info"))?;
-            if info.size > models::CONFIG.upload_limit_bytes {
-                return Err(error::helpers::too_large(format!(
-                    "Upload too large, max bytes: {}",
-                    models::CONFIG.upload_limit_bytes
-                )));
-            }
-            Ok(info)
-        }());
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let info = info
+            .decode_hex()
+            .map_err(|_| error::helpers::bad_request("malformed info"))?;
+        if info.size > config.upload_limit_bytes {
+            return Err(error::helpers::too_large(format!(
+                "Upload too large, max bytes: {}",
+                config.upload_limit_bytes
+            )));
+        }
+        check_upload_webhook(&config, &info)?;
 
-    let db = ctx.db;
-    let cpu = ctx.cpu;
-    info.and_then(move |info: UploadInit| {
-        cpu.spawn_fn(move || -> error::Result<String> {
-            let uuid = Uuid::new_v4();
-            let uuid_hex = hex::encode(uuid.as_bytes());
+        // hash before taking a db connection
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/upload/init");
+        let (access_auth, deletion_auth) = tokio::try_join!(
+            hasher.new_auth(&info.access_password),
+            new_optional_auth(&hasher, info.deletion_password.as_deref()),
+        )?;
 
-            let conn = db.get()?;
-            let trans = conn.transaction()?;
-            trans.set_commit();
+        // resolving the webhook host blocks, so it's checked off the runtime
+        if let Some(ref url) = info.webhook_url {
+            let (config, url) = (config.clone(), url.clone());
+            task::spawn_blocking(move || webhooks::check_upload_url(&config, &url)).await??;
+        }
+        let uuid = Uuid::new_v4();
+        let uuid_hex = hex::encode(uuid.as_bytes());
 
-            if !models::Status::can_fit(&trans, info.size)? {
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let inserted: error::Result<()> = async {
+            let token = find_api_token(&trans, &config, authorization.as_deref()).await?;
+            if let Some(ref token) = token {
+                check_token_sizes(token, &[info.size])?;
+            }
+            let (expire_date, download_limit) =
+                resolve_limits(&config, token.as_ref(), info.lifespan, info.download_limit)?;
+            if !models::Status::can_fit(&trans, &config, info.size).await? {
                 return Err(error::helpers::out_of_space("Server out of storage space"));
             }
-            let access_auth =
-                models::NewAuth::from_pass_bytes(&info.access_password)?.insert(&trans)?;
-            let deletion_auth = match info.deletion_password {
-                Some(ref bytes) => {
-                    let auth = models::NewAuth::from_pass_bytes(bytes)?.insert(&trans)?;
-                    Some(auth.id)
-                }
+            let access_auth = access_auth.insert(&trans).await?;
+            let deletion_auth = match deletion_auth {
+                Some(auth) => Some(auth.insert(&trans).await?.id),
                 None => None,
             };
             let new_init_upload = models::NewInitUpload {
@@ -133,26 +477,400 @@ pub fn api_upload_init(
                 content_hash: info.content_hash,
                 size: info.size,
                 nonce: info.nonce,
-                access_password: access_auth.id,
+                access_password: Some(access_auth.id),
                 deletion_password: deletion_auth,
-                download_limit: info.download_limit,
-                expire_date: info.expire_date,
+                download_limit,
+                expire_date,
+                bundle: None,
+                bundle_index: None,
+                api_token: token.map(|token| token.id),
+                webhook_url: info.webhook_url,
+                webhook_secret: info.webhook_secret,
             };
-            new_init_upload.insert(&trans)?;
-            Ok(uuid_hex)
+            new_init_upload.insert(&trans).await?;
+            Ok(())
+        }
+        .await;
+        trans.commit().await?;
+        inserted?;
+        Ok(warp::reply::json(&UploadInitResp { key: uuid_hex }))
+    })
+    .await
+}
+
+/// Hash an optional password with a new salt, see `Hasher::new_auth`
+async fn new_optional_auth(
+    hasher: &Hasher,
+    pass: Option<&[u8]>,
+) -> error::Result<Option<models::NewAuth>> {
+    match pass {
+        Some(pass) => Ok(Some(hasher.new_auth(pass).await?)),
+        None => Ok(None),
+    }
+}
+
+api_type! {
+    /// Bundle member info (in transport formatting)
+    #[derive(Deserialize)]
+    pub struct BundleMemberPost {
+        nonce: String,
+        file_name_hash: String,
+        size: u64,
+        content_hash: String,
+    }
+}
+
+api_type! {
+    /// Key of an initialized bundle, and the upload keys of its members, in order
+    #[derive(Serialize, Deserialize)]
+    pub struct BundleInitResp {
+        pub key: String,
+        pub members: Vec<String>,
+    }
+}
+
+api_type! {
+    /// Bundle Initialize post info (in transport formatting)
+    #[derive(Deserialize)]
+    pub struct BundleInitPost {
+        access_password: String,
+        deletion_password: Option<String>,
+        download_limit: Option<u32>,
+        lifespan: Option<i64>,
+        members: Vec<BundleMemberPost>,
+    }
+}
+impl BundleInitPost {
+    fn decode_hex(&self) -> error::Result<BundleInit> {
+        let deletion_password = match self.deletion_password {
+            Some(ref hex) => Some(Vec::from_hex(hex)?),
+            None => None,
+        };
+        let members = self
+            .members
+            .iter()
+            .map(|member| {
+                Ok(BundleMember {
+                    nonce: Vec::from_hex(&member.nonce)?,
+                    file_name_hash: Vec::from_hex(&member.file_name_hash)?,
+                    size: member.size as i64,
+                    content_hash: Vec::from_hex(&member.content_hash)?,
+                })
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        Ok(BundleInit {
+            access_password: Vec::from_hex(&self.access_password)?,
+            deletion_password,
+            download_limit: self.download_limit.map(|n| n as i32),
+            lifespan: self.lifespan,
+            members,
         })
+    }
+}
+
+/// Bundle member info converted/decoded
+#[derive(Debug)]
+struct BundleMember {
+    nonce: Vec<u8>,
+    file_name_hash: Vec<u8>,
+    size: i64,
+    content_hash: Vec<u8>,
+}
+
+/// Bundle post info converted/decoded
+#[derive(Debug)]
+struct BundleInit {
+    access_password: Vec<u8>,
+    deletion_password: Option<Vec<u8>>,
+    download_limit: Option<i32>,
+    lifespan: Option<i64>,
+    members: Vec<BundleMember>,
+}
+
+/// Initialize a new bundle of uploads
+///
+/// Supply the bundle's access meta-data and the meta-data of each member. Returns the
+/// bundle's key and an upload key for each member (in the order they were supplied).
+/// Member bytes are uploaded using the member upload keys, exactly like single uploads.
+/// An api token may be presented like with `api_upload_init`, applying to all members.
+pub async fn api_bundle_init(
+    ctx: Ctx,
+    authorization: Option<String>,
+    info: BundleInitPost,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let info = info
+            .decode_hex()
+            .map_err(|_| error::helpers::bad_request("malformed info"))?;
+        if info.members.is_empty() {
+            return Err(error::helpers::bad_request("bundle has no members"));
+        }
+        if info
+            .members
+            .iter()
+            .any(|member| member.size > config.upload_limit_bytes)
+        {
+            return Err(error::helpers::too_large(format!(
+                "Upload too large, max bytes: {}",
+                config.upload_limit_bytes
+            )));
+        }
+
+        // hash before taking a db connection
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/bundle/init");
+        let (access_auth, deletion_auth) = tokio::try_join!(
+            hasher.new_auth(&info.access_password),
+            new_optional_auth(&hasher, info.deletion_password.as_deref()),
+        )?;
+
+        let uuid = Uuid::new_v4();
+        let uuid_hex = hex::encode(uuid.as_bytes());
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let inserted: error::Result<Vec<String>> = async {
+            let token = find_api_token(&trans, &config, authorization.as_deref()).await?;
+            if let Some(ref token) = token {
+                let sizes = info
+                    .members
+                    .iter()
+                    .map(|member| member.size)
+                    .collect::<Vec<_>>();
+                check_token_sizes(token, &sizes)?;
+            }
+            let (expire_date, download_limit) =
+                resolve_limits(&config, token.as_ref(), info.lifespan, info.download_limit)?;
+            let api_token = token.map(|token| token.id);
+            let total_size = info.members.iter().map(|member| member.size).sum();
+            if !models::Status::can_fit(&trans, &config, total_size).await? {
+                return Err(error::helpers::out_of_space("Server out of storage space"));
+            }
+            let access_auth = access_auth.insert(&trans).await?;
+            let deletion_auth = match deletion_auth {
+                Some(auth) => Some(auth.insert(&trans).await?.id),
+                None => None,
+            };
+            let bundle = models::NewBundle {
+                uuid,
+                access_password: access_auth.id,
+                deletion_password: deletion_auth,
+                download_limit,
+                expire_date,
+            }
+            .insert(&trans)
+            .await?;
+
+            let mut member_keys = Vec::with_capacity(info.members.len());
+            for (index, member) in info.members.into_iter().enumerate() {
+                let member_uuid = Uuid::new_v4();
+                models::NewInitUpload {
+                    uuid: member_uuid,
+                    file_name_hash: member.file_name_hash,
+                    content_hash: member.content_hash,
+                    size: member.size,
+                    nonce: member.nonce,
+                    access_password: None,
+                    deletion_password: None,
+                    download_limit: None,
+                    expire_date: bundle.expire_date,
+                    bundle: Some(bundle.id),
+                    bundle_index: Some(index as i32),
+                    api_token,
+                    webhook_url: None,
+                    webhook_secret: None,
+                }
+                .insert(&trans)
+                .await?;
+                member_keys.push(hex::encode(member_uuid.as_bytes()));
+            }
+            Ok(member_keys)
+        }
+        .await;
+        trans.commit().await?;
+        let members = inserted?;
+        Ok(warp::reply::json(&BundleInitResp {
+            key: uuid_hex,
+            members,
+        }))
     })
-    .map(move |uuid_hex| {
-        let resp = json!({ "key": &uuid_hex });
-        warp::reply::json(&resp)
+    .await
+}
+
+api_type! {
+    /// Upload identifier
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct UploadKey {
+        pub key: String,
+    }
+}
+
+/// Check a password protecting a record before opening the record's transaction, so
+/// no db connection is held while the password is hashed. `load` finds the record and
+/// its `auth` on a pooled connection, which it releases before hashing.
+///
+/// Attempts are limited per record and locked records are refused before hashing. The
+/// outcome is applied with `verify_password`, and failures are returned as `Ok(Err(..))`
+/// so they're audited there along with wrong passwords.
+async fn check_password<L, F, Fut>(
+    db: &db::Pool,
+    config: &Config,
+    limiter: &RateLimiter,
+    hasher: &Hasher,
+    pass: &[u8],
+    load: F,
+) -> error::Result<error::Result<Verification>>
+where
+    L: Lockable,
+    F: FnOnce(db::DbConn) -> Fut,
+    Fut: Future<Output = error::Result<(L, models::Auth)>>,
+{
+    let (record, auth) = load(db.get().await?).await?;
+    let checked = config
+        .auth_rate_limit_per_minute
+        .map_or(Ok(()), |limit| {
+            limiter.check(&format!("{}:{}", L::table_name(), record.id()), limit)
+        })
+        .and_then(|_| check_locked(config, &record));
+    Ok(match checked {
+        Ok(_) => hasher.check(&auth, pass).await,
+        Err(e) => Err(e),
     })
-    .map_err(error::helpers::reject)
 }
 
-/// Upload identifier
-#[derive(Deserialize, Debug)]
-pub struct UploadKey {
-    key: String,
+/// Refuse locked records, once `config.max_auth_failures` is reached
+fn check_locked<L: Lockable>(config: &Config, record: &L) -> error::Result<()> {
+    if let Some(max) = config.max_auth_failures {
+        if record.failed_attempts() >= max {
+            return Err(match config.auth_failure_action {
+                AuthFailureAction::Lock => {
+                    error::helpers::locked("Locked after too many failed password attempts")
+                }
+                AuthFailureAction::Delete => error::helpers::does_not_exist("upload not found"),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Verify a password protecting `record`, applying the outcome of `check_password` and
+/// counting failures. Once `config.max_auth_failures` is reached the record is either
+/// locked or expired, depending on `config.auth_failure_action`.
+async fn verify_password<T: GenericClient, L: Lockable>(
+    conn: &T,
+    config: &Config,
+    record: &L,
+    auth: &models::Auth,
+    check: error::Result<Verification>,
+) -> error::Result<()> {
+    let verification = check?;
+    // failures may have been recorded while the password was hashed
+    check_locked(config, record)?;
+    if let Err(e) = auth.verify(conn, &verification).await {
+        // only wrong passwords count as failures, not errors re-hashing correct ones
+        if !matches!(e.kind(), error::ErrorKind::InvalidAuth(_)) {
+            return Err(e);
+        }
+        let failed = record.record_failed_attempt(conn).await?;
+        if let Some(max) = config.max_auth_failures {
+            if failed >= max {
+                warn!(
+                    "{} id={} reached {} failed password attempts",
+                    L::table_name(),
+                    record.id(),
+                    failed
+                );
+                if config.auth_failure_action == AuthFailureAction::Delete {
+                    record.expire_now(conn).await?;
+                }
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Verify the size on disk of an uploaded file matches the `declared` size, returning
+/// the size and, when `config.hash_uploads` is set, the SHA256 hash of the file's contents.
+/// Blocks.
+fn verify_upload_file<P: AsRef<Path>>(
+    config: &Config,
+    file_path: P,
+    declared: i64,
+) -> error::Result<(i64, Option<Vec<u8>>)> {
+    let size = std::fs::metadata(file_path.as_ref())?.len() as i64;
+    if size != declared {
+        return Err(error::helpers::bad_request(format!(
+            "Upload size mismatch, expected {} bytes, received {}",
+            declared, size
+        )));
+    }
+    let file_hash = if config.hash_uploads {
+        Some(auth::sha256_file(file_path)?)
+    } else {
+        None
+    };
+    Ok((size, file_hash))
+}
+
+/// Write `body` to `file`, failing with `too_large_msg` once more than `max_bytes`
+/// are received. Resolves to the number of bytes written.
+async fn write_body<S, B>(
+    body: S,
+    mut file: tokio::fs::File,
+    max_bytes: usize,
+    too_large_msg: &'static str,
+) -> error::Result<i64>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: warp::Buf,
+{
+    futures::pin_mut!(body);
+    let mut byte_count = 0;
+    while let Some(buf) = body.next().await {
+        let mut buf = buf?;
+        while buf.has_remaining() {
+            let bytes = buf.chunk();
+            let len = bytes.len();
+            byte_count += len;
+            if byte_count > max_bytes {
+                return Err(error::helpers::too_large(too_large_msg));
+            }
+            file.write_all(bytes).await?;
+            buf.advance(len);
+        }
+    }
+    file.flush().await?;
+    Ok(byte_count as i64)
+}
+
+/// Mark a failed upload deleted and delete any staged or stored bytes
+async fn remove_failed_upload(
+    db: &db::Pool,
+    config: &Config,
+    storage: &Arc<dyn storage::Storage>,
+    upload: &models::Upload,
+) -> error::Result<()> {
+    let conn = db.get().await?;
+    upload.delete(&conn).await?;
+    let staging_path = models::Upload::new_file_path(config, &upload.uuid)?;
+    for path in &[staging_path.clone(), staging_path.with_extension("enc")] {
+        match tokio::fs::remove_file(path).await {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+    }
+    let file_path = upload.file_path.clone();
+    storage::blocking(storage, move |storage| storage.delete(&file_path)).await
+}
+
+api_type! {
+    /// Number of bytes stored by a completed upload
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadFileResp {
+        pub ok: String,
+        pub bytes: i64,
+    }
 }
 
 /// Upload encrypted bytes to a specified upload-key
@@ -161,136 +879,484 @@ pub struct UploadKey {
 ///     - Make sure the server has enough space available (using the previously reported file size).
 ///     - Make sure the upload came within the upload time-out
 ///     - While reading the uploaded bytes, keep count and make sure the number of bytes <= state size
-pub fn api_upload_file(
+///
+/// After receiving all bytes, make sure the number of bytes written matches the stated size.
+/// Failed uploads are marked deleted and their files are removed.
+pub async fn api_upload_file<S, B>(
     ctx: Ctx,
     upload_key: UploadKey,
-    body: warp::body::BodyStream,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
+    body: S,
+) -> Result<impl warp::Reply, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: warp::Buf + Send + 'static,
+{
     info!("upload started, key: {}", upload_key.key);
-    struct Info {
-        uuid: Uuid,
-        now: DateTime<Utc>,
-        upload: Option<models::Upload>,
-    }
-
-    let info = futures::future::result::<Info, error::Error>(move || -> error::Result<Info> {
-        let now = Utc::now();
+    respond(async move {
+        let transfer = ctx.shutdown.begin()?;
         let uuid = Uuid::from_str(&upload_key.key)
             .map_err(|_| error::helpers::does_not_exist("upload not found"))?;
-        Ok(Info {
-            uuid,
-            now,
-            upload: None,
-        })
-    }());
-
-    let db_create = ctx.db.clone();
-    let cpu_create = ctx.cpu.clone();
-    let db_delete = ctx.db.clone();
-    let cpu_delete = ctx.cpu;
-
-    info.and_then(move |mut info: Info| {
-        cpu_create.spawn_fn(move || -> error::Result<Info> {
-            let conn = db_create.get()?;
-            let trans = conn.transaction()?;
-            trans.set_commit();
+        transfer.upload(uuid);
+        let config = ctx.config.get();
+        let now = Utc::now();
 
-            let init_upload = models::InitUpload::find(&trans, &info.uuid)?;
-            if !models::Status::can_fit(&trans, init_upload.size)? {
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let created: error::Result<models::Upload> = async {
+            let init_upload = models::InitUpload::find(&trans, &uuid).await?;
+            // held until the `init_upload` is replaced by the upload, see `api_upload_chunk`
+            let _lock = ctx
+                .upload_locks
+                .try_lock(init_upload.id)
+                .ok_or_else(|| error::helpers::bad_request("Chunked upload already in progress"))?;
+            // reload now that chunk writers are excluded
+            let init_upload = models::InitUpload::find(&trans, &uuid).await?;
+            if init_upload.uploaded_bytes > 0 {
+                return Err(error::helpers::bad_request(
+                    "Chunked upload already in progress",
+                ));
+            }
+            if !models::Status::can_fit(&trans, &config, init_upload.size).await? {
                 return Err(error::helpers::out_of_space("Server out of storage space"));
             }
 
-            models::Status::inc_upload(&trans, init_upload.size)?;
-            let file_path = models::Upload::new_file_path(&init_upload.uuid)?;
-            init_upload.delete(&trans)?;
-            if !init_upload.still_valid(&info.now) {
-                return Err(error::helpers::bad_request("Upload request came too late"));
+            let storage_key = models::Upload::storage_key(&init_upload.uuid);
+            init_upload.delete(&trans).await?;
+            if !init_upload.still_valid(&config, &now) {
+                return Err(error::helpers::upload_too_late(
+                    "Upload request came too late",
+                ));
             }
-            let new_upload = init_upload.into_upload(&file_path)?;
-            let upload = new_upload.insert(&trans)?;
-            info.upload = Some(upload);
-            Ok(info)
-        })
+            let new_upload = init_upload.into_upload(&storage_key)?;
+            new_upload.insert(&trans).await
+        }
+        .await;
+        trans.commit().await?;
+        drop(conn);
+        let upload = created?;
+
+        // received on its own task, so failed uploads are cleaned up
+        // even when the client goes away
+        let bytes = task::spawn(receive_upload(ctx, config, transfer, upload, body)).await??;
+        Ok(warp::reply::json(&UploadFileResp {
+            ok: String::from("ok"),
+            bytes,
+        }))
     })
-    // Convert error to match the next future's unnameable return type.
-    // The tuple Error is required so the error branch can cleanup oversized uploads
-    .map_err(|e| (e, None))
-    .and_then(move |info: Info| {
-        // -> `impl Future<Item=usize, Error=(error::Error, Option<models::Upload>)>`
-        use warp::Buf;
-        use warp::Stream;
-
-        let max_bytes = models::CONFIG.upload_limit_bytes as usize;
-        let upload = info.upload.expect("No upload present");
-
-        tokio::fs::File::create(upload.file_path.clone())
-            .map_err(|e| (error::Error::from(e), None))
-            .and_then(move |file| {
-                body.map_err(|e| (error::Error::from(e), None))
-                    .fold((file, 0), move |(file, byte_count), buf| {
-                        futures::future::result::<Vec<u8>, (error::Error, Option<models::Upload>)>(
-                            {
-                                let bytes = buf.bytes();
-                                let size = byte_count + bytes.len();
-                                if size > max_bytes {
-                                    Err((
-                                        error::helpers::too_large("upload too large"),
-                                        Some(upload.clone()),
-                                    ))
-                                } else {
-                                    Ok(bytes.to_vec())
-                                }
-                            },
-                        )
-                        .and_then(move |bytes| {
-                            tokio::io::write_all(file, bytes)
-                                .map(move |(file, bytes_written)| {
-                                    (file, byte_count + bytes_written.len())
-                                })
-                                .map_err(|e| (error::Error::from(e), None))
-                        })
-                    })
-                    .map(|(_file, size)| size)
+    .await
+}
+
+/// Write an upload's bytes and finalize it, returning the number of bytes stored.
+/// Once the upload exists, any failure must clean it up.
+async fn receive_upload<S, B>(
+    ctx: Ctx,
+    config: Arc<Config>,
+    transfer: Transfer,
+    upload: models::Upload,
+    body: S,
+) -> error::Result<i64>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: warp::Buf,
+{
+    let _transfer = transfer;
+    let received: error::Result<i64> = async {
+        let staging_path = models::Upload::new_file_path(&config, &upload.uuid)?;
+        let file = tokio::fs::File::create(&staging_path).await?;
+        let n_bytes = write_body(
+            body,
+            file,
+            upload.size as usize,
+            "upload larger than the declared size",
+        )
+        .await?;
+        if n_bytes != upload.size {
+            return Err(error::helpers::bad_request(format!(
+                "Upload size mismatch, expected {} bytes, received {}",
+                upload.size, n_bytes
+            )));
+        }
+        let (size, file_hash) = {
+            let (config, staging_path, declared) =
+                (config.clone(), staging_path.clone(), upload.size);
+            task::spawn_blocking(move || verify_upload_file(&config, &staging_path, declared))
+                .await??
+        };
+        check_room(&ctx.db.get().await?, &config, upload.api_token, size).await?;
+
+        // stored before the transaction, storage backends may be slow. Failures
+        // from here on delete the stored bytes along with the upload
+        let encryption = {
+            let (keys, uuid, key) = (ctx.keys.clone(), upload.uuid, upload.file_path.clone());
+            storage::blocking(&ctx.storage, move |storage| {
+                envelope::store(storage, keys.as_deref(), &uuid, &key, &staging_path)
             })
+            .await?
+        };
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let finalized: error::Result<()> = async {
+            check_room(&trans, &config, upload.api_token, size).await?;
+            // deleted or swept while its bytes were streaming
+            if upload.finalize(&trans).await? == 0 {
+                return Err(error::helpers::does_not_exist("upload not found"));
+            }
+            models::Status::inc_upload(&trans, size).await?;
+            if let Some(ref token) = upload.api_token {
+                models::ApiToken::inc_upload(&trans, token, size).await?;
+            }
+            if let Some(ref file_hash) = file_hash {
+                upload.set_file_hash(&trans, file_hash).await?;
+            }
+            if let Some((key_id, ref encrypted_data_key)) = encryption {
+                upload
+                    .set_encryption(&trans, key_id, encrypted_data_key)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        trans.commit().await?;
+        finalized?;
+        metrics::UPLOADED_BYTES.inc_by(size);
+        Ok(size)
+    }
+    .await;
+    if received.is_err() {
+        if let Err(e) = remove_failed_upload(&ctx.db, &config, &ctx.storage, &upload).await {
+            error!("Error cleaning up failed upload, {}", e);
+        }
+    }
+    received
+}
+
+api_type! {
+    /// Upload identifier and the byte offset a chunk starts at
+    #[derive(Deserialize, Debug)]
+    pub struct UploadChunkKey {
+        key: String,
+        offset: u64,
+    }
+}
+
+api_type! {
+    /// Number of bytes committed after a chunk, and whether the upload is complete
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadChunkResp {
+        pub ok: String,
+        pub offset: i64,
+        pub complete: bool,
+    }
+}
+
+/// Upload a chunk of encrypted bytes to a specified upload-key
+///
+/// Before accepting the chunk:
+///     - Make sure the upload is still active (bytes were last received within the upload time-out)
+///     - Make sure the chunk `offset` matches the number of bytes already committed. Bytes left
+///       past the committed offset by an interrupted chunk are discarded.
+///     - Make sure the server has enough space available when the first chunk arrives
+///     - While reading the uploaded bytes, keep count and make sure the total number
+///       of bytes <= stated size
+///
+/// Once the committed byte count matches the size stated at initialization, the upload
+/// is finalized and becomes available for download.
+pub async fn api_upload_chunk<S, B>(
+    ctx: Ctx,
+    chunk_key: UploadChunkKey,
+    body: S,
+) -> Result<impl warp::Reply, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: warp::Buf + Send + 'static,
+{
+    info!(
+        "upload chunk started, key: {}, offset: {}",
+        chunk_key.key, chunk_key.offset
+    );
+    respond(async move {
+        let now = Utc::now();
+        let transfer = ctx.shutdown.begin()?;
+        let uuid = Uuid::from_str(&chunk_key.key)
+            .map_err(|_| error::helpers::does_not_exist("upload not found"))?;
+        let offset = chunk_key.offset as i64;
+
+        // received on its own task, like `api_upload_file`, so handed off
+        // chunks are finalized or cleaned up even when the client goes away
+        let (offset, complete) =
+            task::spawn(receive_chunk(ctx, transfer, uuid, offset, now, body)).await??;
+        Ok(warp::reply::json(&UploadChunkResp {
+            ok: String::from("ok"),
+            offset,
+            complete,
+        }))
     })
-    .map(move |size| {
-        let resp = json!({"ok": "ok", "bytes": size});
-        warp::reply::json(&resp)
-    })
-    .or_else(move |(err, maybe_upload)| {
-        // mark the upload deleted and pass along the upload-file_path to delete
-        cpu_delete
-            .spawn_fn(move || -> error::Result<String> {
-                if let error::ErrorKind::UploadTooLarge(_) = err.kind() {
-                    match maybe_upload {
-                        Some(upload) => {
-                            let conn = db_delete.get()?;
-                            upload.delete(&*conn)?;
-                            return Ok(upload.file_path);
-                        }
-                        _ => unreachable!("Found UploadTooLarge error, but no Upload"),
-                    }
+    .await
+}
+
+/// Write and commit a chunk of the upload `uuid` starting at `offset`, finalizing the upload
+/// once all its bytes are committed. Returns the committed offset and whether the upload
+/// is complete.
+async fn receive_chunk<S, B>(
+    ctx: Ctx,
+    transfer: Transfer,
+    uuid: Uuid,
+    offset: i64,
+    now: DateTime<Utc>,
+    body: S,
+) -> error::Result<(i64, bool)>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: warp::Buf,
+{
+    let _transfer = transfer;
+    let config = ctx.config.get();
+
+    // the chunk is written and committed while holding the upload's write lock,
+    // so concurrent chunks can't interleave their writes to the staging file.
+    // db connections are only taken for each transaction, not while streaming the chunk.
+    let conflict = || error::helpers::bad_request("Upload chunk conflict, query upload progress");
+    let (init_upload, _lock) = {
+        let conn = ctx.db.get().await?;
+        let init_upload = models::InitUpload::find(&conn, &uuid).await?;
+        let lock = ctx
+            .upload_locks
+            .try_lock(init_upload.id)
+            .ok_or_else(conflict)?;
+        // reload now that other writers are excluded
+        let init_upload = models::InitUpload::find(&conn, &uuid).await?;
+        if !init_upload.still_valid(&config, &now) {
+            return Err(error::helpers::upload_too_late(
+                "Upload request came too late",
+            ));
+        }
+        if offset != init_upload.uploaded_bytes {
+            return Err(error::helpers::bad_request(format!(
+                "Invalid chunk offset, expected: {}",
+                init_upload.uploaded_bytes
+            )));
+        }
+        if init_upload.uploaded_bytes == 0
+            && !models::Status::can_fit(&conn, &config, init_upload.size).await?
+        {
+            return Err(error::helpers::out_of_space("Server out of storage space"));
+        }
+        (init_upload, lock)
+    };
+
+    let file_path = models::Upload::new_file_path(&config, &init_upload.uuid)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&file_path)
+        .await?;
+    file.set_len(init_upload.uploaded_bytes as u64).await?;
+    file.seek(SeekFrom::Start(init_upload.uploaded_bytes as u64))
+        .await?;
+    let max_bytes = (init_upload.size - init_upload.uploaded_bytes) as usize;
+    let n_bytes = write_body(
+        body,
+        file,
+        max_bytes,
+        "chunk exceeds the declared upload size",
+    )
+    .await?;
+
+    let init_upload = {
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let committed: error::Result<models::InitUpload> = async {
+            if init_upload.uploaded_bytes + n_bytes == init_upload.size {
+                // refuse the last chunk while there's no room, so it can be retried
+                check_room(&trans, &config, init_upload.api_token, init_upload.size).await?;
+            }
+            init_upload
+                .commit_bytes(&trans, n_bytes)
+                .await
+                .map_err(|e| if e.is_does_not_exist() { conflict() } else { e })
+        }
+        .await;
+        trans.commit().await?;
+        committed?
+    };
+    let committed = init_upload.uploaded_bytes;
+    metrics::UPLOADED_BYTES.inc_by(n_bytes);
+    if committed < init_upload.size {
+        return Ok((committed, false));
+    }
+
+    let staging_path = models::Upload::new_file_path(&config, &init_upload.uuid)?;
+    let (size, file_hash) = {
+        let (config, staging_path, declared) =
+            (config.clone(), staging_path.clone(), init_upload.size);
+        task::spawn_blocking(move || verify_upload_file(&config, &staging_path, declared)).await??
+    };
+    // stored before the transaction, storage backends may be slow
+    let storage_key = models::Upload::storage_key(&init_upload.uuid);
+    let encryption = {
+        let (keys, uuid, key) = (ctx.keys.clone(), init_upload.uuid, storage_key.clone());
+        storage::blocking(&ctx.storage, move |storage| {
+            envelope::store(storage, keys.as_deref(), &uuid, &key, &staging_path)
+        })
+        .await?
+    };
+
+    let finalized: error::Result<()> = async {
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let inserted: error::Result<()> = async {
+            check_room(&trans, &config, init_upload.api_token, size).await?;
+            models::Status::inc_upload(&trans, size).await?;
+            if let Some(ref token) = init_upload.api_token {
+                models::ApiToken::inc_upload(&trans, token, size).await?;
+            }
+            init_upload.delete(&trans).await?;
+            let mut new_upload = init_upload.clone().into_upload(&storage_key)?;
+            new_upload.file_hash = file_hash;
+            new_upload.finalized = true;
+            if let Some((key_id, encrypted_data_key)) = encryption {
+                new_upload.encryption_key_id = Some(key_id);
+                new_upload.encrypted_data_key = Some(encrypted_data_key);
+            }
+            new_upload.insert(&trans).await?;
+            Ok(())
+        }
+        .await;
+        trans.commit().await?;
+        inserted
+    }
+    .await;
+    if let Err(e) = finalized {
+        // the staged bytes were handed off, so retrying can't finish the upload
+        let cleanup: error::Result<()> = async {
+            let conn = ctx.db.get().await?;
+            init_upload.delete(&conn).await?;
+            let key = storage_key.clone();
+            storage::blocking(&ctx.storage, move |storage| storage.delete(&key)).await
+        }
+        .await;
+        if let Err(e) = cleanup {
+            error!("Error cleaning up failed upload, {}", e);
+        }
+        return Err(e);
+    }
+    info!("chunked upload complete, bytes: {}", committed);
+    Ok((committed, true))
+}
+
+api_type! {
+    /// Number of bytes committed of an upload's declared size
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadProgressResp {
+        pub offset: i64,
+        pub size: i64,
+        pub complete: bool,
+    }
+}
+
+/// Return the number of bytes committed for a specified upload-key
+///
+/// Clients resuming an interrupted chunked upload should continue
+/// uploading from the returned `offset`.
+pub async fn api_upload_progress(
+    ctx: Ctx,
+    upload_key: UploadKey,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let uuid = Uuid::from_str(&upload_key.key)
+            .map_err(|_| error::helpers::does_not_exist("upload not found"))?;
+        let conn = ctx.db.get().await?;
+        let progress = match models::InitUpload::find(&conn, &uuid).await {
+            Ok(init_upload) => UploadProgressResp {
+                offset: init_upload.uploaded_bytes,
+                size: init_upload.size,
+                complete: false,
+            },
+            Err(ref e) if e.is_does_not_exist() => {
+                let upload = models::Upload::find(&conn, &uuid).await?;
+                UploadProgressResp {
+                    offset: upload.size,
+                    size: upload.size,
+                    complete: true,
                 }
-                Err(err)
-            })
-            .and_then(|path| {
-                // delete the file we just created and then convert this to an error-future
-                tokio::fs::remove_file(path)
-                    .map_err(error::Error::from)
-                    .and_then(|_| {
-                        futures::future::err(error::helpers::too_large("upload too large"))
-                    })
-            })
-            // this future chain should only consist of errors at this point
-            .map(|()| unreachable!("Future chain should contain only errors"))
-            .map_err(error::helpers::reject)
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(warp::reply::json(&progress))
+    })
+    .await
+}
+
+api_type! {
+    /// Meta-data of an upload made with an api token
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadListing {
+        pub key: String,
+        pub size: i64,
+        pub date_created: String,
+        pub expire_date: String,
+        pub download_count: i64,
+        pub download_limit: Option<i32>,
+        pub remaining_downloads: Option<i64>,
+        pub deletable: bool,
+    }
+}
+
+api_type! {
+    /// Active uploads made with an api token
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadsResp {
+        pub uploads: Vec<UploadListing>,
+    }
+}
+
+/// List the active uploads made with the api token presented in an
+/// `Authorization: Bearer <token>` header
+///
+/// Only meta-data is returned, nothing needed to decrypt the uploads. Bundle
+/// members aren't included.
+pub async fn api_uploads(
+    ctx: Ctx,
+    authorization: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let conn = ctx.db.get().await?;
+        let token = find_api_token(&conn, &config, authorization.as_deref())
+            .await?
+            .ok_or_else(|| error::helpers::invalid_auth("api token required"))?;
+        let uploads = models::Upload::select_by_api_token(&conn, &token.id).await?;
+        let mut listing = Vec::with_capacity(uploads.len());
+        for upload in uploads {
+            let download_count = upload.download_count(&conn).await?;
+            let remaining_downloads = upload
+                .download_limit
+                .map(|limit| (i64::from(limit) - download_count).max(0));
+            listing.push(UploadListing {
+                key: hex::encode(upload.uuid.as_bytes()),
+                size: upload.size,
+                date_created: upload.date_created.to_rfc3339(),
+                expire_date: upload.expire_date.to_rfc3339(),
+                download_count,
+                download_limit: upload.download_limit,
+                remaining_downloads,
+                deletable: upload.deletion_password.is_some(),
+            });
+        }
+        Ok(warp::reply::json(&UploadsResp { uploads: listing }))
     })
+    .await
 }
 
-#[derive(Deserialize)]
-pub struct DeleteKeyAccessPost {
-    key: String,
-    deletion_password: String,
+api_type! {
+    /// Upload or bundle identifier and deletion password (in transport formatting)
+    #[derive(Deserialize)]
+    pub struct DeleteKeyAccessPost {
+        key: String,
+        deletion_password: String,
+    }
 }
 impl DeleteKeyAccessPost {
     fn decode_hex(&self) -> error::Result<DeleteKeyAccess> {
@@ -308,63 +1374,352 @@ struct DeleteKeyAccess {
     deletion_password: Vec<u8>,
 }
 
+api_type! {
+    /// Acknowledgement of a successful request
+    #[derive(Serialize, Deserialize)]
+    pub struct OkResp {
+        pub ok: String,
+    }
+}
+
 /// Deletes an upload by key. Only uploads that were created with a deletion password can be deleted.
 /// Deletion password must be present.
-pub fn api_upload_delete(
+pub async fn api_upload_delete(
     ctx: Ctx,
+    client: audit::Client,
     delete_key: DeleteKeyAccessPost,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
-    let cpu = ctx.cpu;
-    let db = ctx.db;
-    futures::future::result::<DeleteKeyAccess, error::Error>(delete_key.decode_hex())
-        .and_then(move |delete_key| {
-            cpu.spawn_fn(move || -> error::Result<String> {
-                let conn = db.get()?;
-                let trans = conn.transaction()?;
-                trans.set_commit();
-
-                let upload = models::Upload::find(&trans, &delete_key.uuid)?;
-                let deletion_auth = upload.get_deletion_auth(&trans)?;
-                match deletion_auth {
-                    None => Err(error::helpers::bad_request("cannot delete")),
-                    Some(auth) => {
-                        auth.verify(&delete_key.deletion_password)?;
-                        let id = upload.id;
-                        match upload.delete(&trans) {
-                            Ok(_) => {
-                                models::Status::dec_upload(&trans, upload.size)?;
-                            }
-                            Err(e) => {
-                                error!("Error deleting upload with id={}, {}", id, e);
-                                return Err(e);
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/upload/delete");
+        let delete_key = delete_key.decode_hex()?;
+        let uuid = delete_key.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &delete_key.deletion_password,
+            |conn| async move {
+                let upload = models::Upload::find(&conn, &uuid).await?;
+                let auth = upload
+                    .get_deletion_auth(&conn)
+                    .await?
+                    .ok_or_else(|| error::helpers::bad_request("cannot delete"))?;
+                Ok((upload, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let deleted: error::Result<String> = async {
+            let upload = models::Upload::find(&trans, &uuid).await?;
+            let deletion_auth = upload.get_deletion_auth(&trans).await?;
+            match deletion_auth {
+                None => Err(error::helpers::bad_request("cannot delete")),
+                Some(auth) => {
+                    let verified = verify_password(&trans, &config, &upload, &auth, check).await;
+                    audit::record(
+                        &trans,
+                        audit::Event::UploadDelete,
+                        audit::Outcome::of(&verified),
+                        &upload,
+                        &client,
+                    )
+                    .await?;
+                    verified?;
+                    let id = upload.id;
+                    match upload.delete(&trans).await {
+                        Ok(_) => {
+                            if upload.finalized {
+                                models::Status::dec_upload(&trans, upload.size).await?;
+                                if let Some(ref token) = upload.api_token {
+                                    models::ApiToken::dec_upload(&trans, token, upload.size)
+                                        .await?;
+                                }
                             }
+                            webhooks::enqueue(&trans, &config, &upload, webhooks::Event::Deleted)
+                                .await?;
+                        }
+                        Err(e) => {
+                            error!("Error deleting upload with id={}, {}", id, e);
+                            return Err(e);
                         }
-                        Ok(upload.file_path)
                     }
+                    Ok(upload.file_path)
                 }
-            })
+            }
+        }
+        .await;
+        trans.commit().await?;
+        drop(conn);
+        let file_path = deleted?;
+
+        let key = file_path.clone();
+        if let Err(e) = storage::blocking(&ctx.storage, move |storage| storage.delete(&key)).await {
+            error!(
+                "Error deleting upload file {}, {} continuing...",
+                file_path, e
+            );
+        }
+        Ok(warp::reply::json(&OkResp {
+            ok: String::from("ok"),
+        }))
+    })
+    .await
+}
+
+api_type! {
+    /// Upload identifier, deletion password, and new limits (in transport formatting)
+    #[derive(Deserialize)]
+    pub struct UploadUpdatePost {
+        key: String,
+        deletion_password: String,
+        lifespan: Option<i64>,
+        download_limit: Option<u32>,
+    }
+}
+impl UploadUpdatePost {
+    fn decode_hex(&self) -> error::Result<UploadUpdate> {
+        Ok(UploadUpdate {
+            uuid: Uuid::from_str(&self.key)
+                .map_err(|_| error::helpers::does_not_exist("upload not found"))?,
+            deletion_password: Vec::from_hex(&self.deletion_password)
+                .map_err(|_| error::helpers::bad_request("malformed info"))?,
+            lifespan: self.lifespan,
+            download_limit: self.download_limit.map(|n| n as i32),
         })
-        .and_then(|file_path| {
-            tokio::fs::remove_file(file_path.clone()).or_else(move |e| {
+    }
+}
+
+struct UploadUpdate {
+    uuid: Uuid,
+    deletion_password: Vec<u8>,
+    lifespan: Option<i64>,
+    download_limit: Option<i32>,
+}
+
+api_type! {
+    /// An upload's limits after an update
+    #[derive(Serialize, Deserialize)]
+    pub struct UploadUpdateResp {
+        pub ok: String,
+        pub expire_date: String,
+        pub download_limit: Option<i32>,
+    }
+}
+
+/// Change an upload's expiration and download limit by key. Only uploads that were
+/// created with a deletion password can be updated. Deletion password must be present.
+///
+/// A new `lifespan` is counted in seconds from now, and the upload's total lifespan
+/// can't exceed `config.upload_lifespan_secs_max`. A new `download_limit` must be
+/// greater than the number of downloads already made. The configured bounds and limits
+/// of an api token the upload was made with still apply.
+pub async fn api_upload_update(
+    ctx: Ctx,
+    client: audit::Client,
+    update: UploadUpdatePost,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/upload/update");
+        let update = update.decode_hex()?;
+        let uuid = update.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &update.deletion_password,
+            |conn| async move {
+                let upload = models::Upload::find(&conn, &uuid).await?;
+                let auth = upload
+                    .get_deletion_auth(&conn)
+                    .await?
+                    .ok_or_else(|| error::helpers::bad_request("cannot update"))?;
+                Ok((upload, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let updated: error::Result<models::Upload> = async {
+            let upload = models::Upload::find(&trans, &update.uuid).await?;
+            if Utc::now() >= upload.expire_date {
+                return Err(error::helpers::does_not_exist("upload not found"));
+            }
+            let auth = upload
+                .get_deletion_auth(&trans)
+                .await?
+                .ok_or_else(|| error::helpers::bad_request("cannot update"))?;
+            let verified = verify_password(&trans, &config, &upload, &auth, check).await;
+            audit::record(
+                &trans,
+                audit::Event::UploadUpdate,
+                audit::Outcome::of(&verified),
+                &upload,
+                &client,
+            )
+            .await?;
+            verified?;
+            let token = match upload.api_token {
+                Some(ref id) => Some(models::ApiToken::find_by_id(&trans, id).await?),
+                None => None,
+            };
+
+            check_requested_limits(
+                &config,
+                token.as_ref(),
+                update.lifespan,
+                update.download_limit,
+            )?;
+
+            let expire_date = match update.lifespan {
+                None => upload.expire_date,
+                Some(lifespan) => {
+                    let expire_date = lifespan_expire_date(lifespan)?;
+                    let total = expire_date.signed_duration_since(upload.date_created);
+                    if total > Duration::seconds(config.upload_lifespan_secs_max) {
+                        return Err(error::helpers::bad_request(format!(
+                            "lifespan too large, max secs since upload: {}",
+                            config.upload_lifespan_secs_max
+                        )));
+                    }
+                    expire_date
+                }
+            };
+
+            let download_limit = match update.download_limit {
+                None => upload.download_limit,
+                Some(limit) => {
+                    let n_downloads = upload.download_count(&trans).await?;
+                    if i64::from(limit) <= n_downloads {
+                        return Err(error::helpers::bad_request(format!(
+                            "download limit must be greater than the {} downloads already made",
+                            n_downloads
+                        )));
+                    }
+                    Some(limit)
+                }
+            };
+            upload
+                .update_limits(&trans, &expire_date, download_limit)
+                .await
+        }
+        .await;
+        trans.commit().await?;
+        let upload = updated?;
+        Ok(warp::reply::json(&UploadUpdateResp {
+            ok: String::from("ok"),
+            expire_date: upload.expire_date.to_rfc3339(),
+            download_limit: upload.download_limit,
+        }))
+    })
+    .await
+}
+
+/// Deletes a bundle and all of its members by key. Only bundles that were created with
+/// a deletion password can be deleted. Deletion password must be present.
+pub async fn api_bundle_delete(
+    ctx: Ctx,
+    client: audit::Client,
+    delete_key: DeleteKeyAccessPost,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/bundle/delete");
+        let delete_key = delete_key.decode_hex()?;
+        let uuid = delete_key.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &delete_key.deletion_password,
+            |conn| async move {
+                let bundle = models::Bundle::find(&conn, &uuid).await?;
+                let auth = bundle
+                    .get_deletion_auth(&conn)
+                    .await?
+                    .ok_or_else(|| error::helpers::bad_request("cannot delete"))?;
+                Ok((bundle, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let deleted: error::Result<Vec<String>> = async {
+            let bundle = models::Bundle::find(&trans, &uuid).await?;
+            let deletion_auth = bundle.get_deletion_auth(&trans).await?;
+            match deletion_auth {
+                None => Err(error::helpers::bad_request("cannot delete")),
+                Some(auth) => {
+                    let verified = verify_password(&trans, &config, &bundle, &auth, check).await;
+                    audit::record(
+                        &trans,
+                        audit::Event::BundleDelete,
+                        audit::Outcome::of(&verified),
+                        &bundle,
+                        &client,
+                    )
+                    .await?;
+                    verified?;
+                    let members = bundle.members(&trans).await?;
+                    let mut file_paths = Vec::with_capacity(members.len());
+                    for upload in members.into_iter() {
+                        upload.delete(&trans).await?;
+                        if upload.finalized {
+                            models::Status::dec_upload(&trans, upload.size).await?;
+                            if let Some(ref token) = upload.api_token {
+                                models::ApiToken::dec_upload(&trans, token, upload.size).await?;
+                            }
+                        }
+                        webhooks::enqueue(&trans, &config, &upload, webhooks::Event::Deleted)
+                            .await?;
+                        file_paths.push(upload.file_path);
+                    }
+                    if let Err(e) = bundle.delete(&trans).await {
+                        error!("Error deleting bundle with id={}, {}", bundle.id, e);
+                        return Err(e);
+                    }
+                    Ok(file_paths)
+                }
+            }
+        }
+        .await;
+        trans.commit().await?;
+        drop(conn);
+        let file_paths = deleted?;
+
+        for file_path in file_paths.into_iter() {
+            let key = file_path.clone();
+            if let Err(e) =
+                storage::blocking(&ctx.storage, move |storage| storage.delete(&key)).await
+            {
                 error!(
                     "Error deleting upload file {}, {} continuing...",
                     file_path, e
                 );
-                futures::future::ok(())
-            })
-        })
-        .map(|_| {
-            let resp = json!({"ok": "ok"});
-            warp::reply::json(&resp)
-        })
-        .map_err(error::helpers::reject)
+            }
+        }
+        Ok(warp::reply::json(&OkResp {
+            ok: String::from("ok"),
+        }))
+    })
+    .await
 }
 
-/// Download identifier and access/auth password
-#[derive(Deserialize)]
-pub struct DownloadKeyAccessPost {
-    key: String,
-    access_password: String,
+api_type! {
+    /// Download identifier and access/auth password
+    #[derive(Serialize, Deserialize)]
+    pub struct DownloadKeyAccessPost {
+        pub key: String,
+        pub access_password: String,
+    }
 }
 impl DownloadKeyAccessPost {
     fn decode_hex(&self) -> error::Result<DownloadKeyAccess> {
@@ -382,135 +1737,507 @@ struct DownloadKeyAccess {
     access_password: Vec<u8>,
 }
 
+api_type! {
+    /// Meta-data needed to download and decrypt an upload
+    #[derive(Serialize, Deserialize)]
+    pub struct DownloadInitResp {
+        pub nonce: String,
+        pub size: i64,
+        pub download_key: String,
+        pub confirm_key: String,
+    }
+}
+
 /// Initialize a download
 ///
 /// Using a key and access-password, obtain the download meta-data (stuff
 /// needed for decryption).
-pub fn api_download_init(
+pub async fn api_download_init(
     ctx: Ctx,
+    client: audit::Client,
     download_key: DownloadKeyAccessPost,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
-    struct Info {
-        now: DateTime<Utc>,
-        download_key: DownloadKeyAccess,
-    }
-
+) -> Result<impl warp::Reply, warp::Rejection> {
     struct Data {
         upload: models::Upload,
         init_download_content: models::InitDownload,
         init_download_confirm: models::InitDownload,
     }
 
-    let cpu = ctx.cpu;
-    let db = ctx.db;
-    futures::future::result::<Info, error::Error>((|| {
+    respond(async move {
         let now = Utc::now();
         let download_key = download_key.decode_hex()?;
-        Ok(Info { now, download_key })
-    })())
-    .and_then(move |info: Info| {
-        cpu.spawn_fn(move || -> error::Result<Data> {
-            let conn = db.get()?;
-            let trans = conn.transaction()?;
-            trans.set_commit();
-
-            let upload = models::Upload::find(&trans, &info.download_key.uuid)?;
-            let access_auth = upload.get_access_auth(&trans)?;
-            access_auth.verify(&info.download_key.access_password)?;
-            let n_downloads = upload.download_count(&trans)? as i32;
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/download/init");
+        let uuid = download_key.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &download_key.access_password,
+            |conn| async move {
+                let upload = models::Upload::find(&conn, &uuid).await?;
+                if upload.bundle.is_some() {
+                    return Err(error::helpers::does_not_exist("upload not found"));
+                }
+                let auth = upload.get_access_auth(&conn).await?;
+                Ok((upload, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let initialized: error::Result<Data> = async {
+            let upload = models::Upload::find(&trans, &uuid).await?;
+            if upload.bundle.is_some() {
+                // bundle members must be downloaded through their bundle
+                return Err(error::helpers::does_not_exist("upload not found"));
+            }
+            let access_auth = upload.get_access_auth(&trans).await?;
+            let verified = verify_password(&trans, &config, &upload, &access_auth, check).await;
+            audit::record(
+                &trans,
+                audit::Event::DownloadInit,
+                audit::Outcome::of(&verified),
+                &upload,
+                &client,
+            )
+            .await?;
+            verified?;
+            // the password is verified, so expired uploads can be told apart from missing ones
+            let n_downloads = upload.download_count(&trans).await? as i32;
             if let Some(limit) = upload.download_limit {
                 if n_downloads >= limit {
-                    return Err(error::helpers::does_not_exist("upload not found"));
+                    return Err(error::helpers::download_limit_reached(
+                        "upload download limit reached",
+                    ));
                 }
             }
-            if info.now >= upload.expire_date {
-                return Err(error::helpers::does_not_exist("upload not found"));
+            if now >= upload.expire_date {
+                return Err(error::helpers::expired("upload expired"));
             }
             let init_download_content = models::NewInitDownload {
                 uuid: Uuid::new_v4(),
                 usage: String::from("content"),
                 upload: upload.id,
             }
-            .insert(&trans)?;
+            .insert(&trans)
+            .await?;
             let init_download_confirm = models::NewInitDownload {
                 uuid: Uuid::new_v4(),
                 usage: String::from("confirm"),
                 upload: upload.id,
             }
-            .insert(&trans)?;
+            .insert(&trans)
+            .await?;
             Ok(Data {
                 upload,
                 init_download_content,
                 init_download_confirm,
             })
-        })
+        }
+        .await;
+        trans.commit().await?;
+        let data = initialized?;
+        Ok(warp::reply::json(&DownloadInitResp {
+            nonce: hex::encode(&data.upload.nonce),
+            size: data.upload.size,
+            download_key: hex::encode(data.init_download_content.uuid.as_bytes()),
+            confirm_key: hex::encode(data.init_download_confirm.uuid.as_bytes()),
+        }))
     })
-    .map(|data| {
-        let resp = json!({
-            "nonce": hex::encode(&data.upload.nonce),
-            "size": data.upload.size,
-            "download_key": hex::encode(data.init_download_content.uuid.as_bytes()),
-            "confirm_key": hex::encode(data.init_download_confirm.uuid.as_bytes()),
-        });
-        warp::reply::json(&resp)
+    .await
+}
+
+/// Parse a `Range` header value into an inclusive `(start, end)` byte range of
+/// a file containing `size` bytes.
+///
+/// Only single `bytes` ranges are supported. Unsupported or malformed ranges are
+/// ignored (returning `None`) so the whole file is served, as allowed by RFC 7233.
+fn parse_range(header: &str, size: u64) -> error::Result<Option<(u64, u64)>> {
+    let spec = match header.trim().splitn(2, '=').collect::<Vec<_>>().as_slice() {
+        [unit, spec] if unit.trim() == "bytes" && !spec.contains(',') => spec.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.splitn(2, '-').collect::<Vec<_>>().as_slice() {
+        [start, end] => (start.trim().to_string(), end.trim().to_string()),
+        _ => return Ok(None),
+    };
+    let not_satisfiable = || {
+        error::helpers::range_not_satisfiable(format!(
+            "Requested range not satisfiable: {}",
+            header
+        ))
+        .with_complete_length(size)
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => {
+            if start >= size {
+                return Err(not_satisfiable());
+            }
+            (start, end.min(size - 1))
+        }
+        (Ok(start), Err(_)) if end.is_empty() => {
+            if start >= size {
+                return Err(not_satisfiable());
+            }
+            (start, size - 1)
+        }
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || size == 0 {
+                return Err(not_satisfiable());
+            }
+            (size.saturating_sub(suffix), size - 1)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(range))
+}
+
+api_type! {
+    /// Bundle download identifier, access/auth password, and optional member selection
+    #[derive(Deserialize)]
+    pub struct BundleDownloadKeyAccessPost {
+        key: String,
+        access_password: String,
+        members: Option<Vec<i32>>,
+    }
+}
+
+api_type! {
+    /// Meta-data needed to download and decrypt a bundle member
+    #[derive(Serialize, Deserialize)]
+    pub struct BundleDownloadMember {
+        pub index: Option<i32>,
+        pub nonce: String,
+        pub size: i64,
+        pub download_key: String,
+        pub confirm_key: String,
+    }
+}
+
+api_type! {
+    /// Meta-data of the downloaded bundle members
+    #[derive(Serialize, Deserialize)]
+    pub struct BundleDownloadInitResp {
+        pub members: Vec<BundleDownloadMember>,
+    }
+}
+
+/// Initialize a bundle download
+///
+/// Using a bundle key and access-password, obtain the download meta-data of all
+/// (or the selected) bundle members. Each member is then downloaded and confirmed
+/// individually using its `download_key` and `confirm_key`, along with the bundle's
+/// access-password. Each bundle download initialization counts as one download of
+/// the bundle against its download limit.
+pub async fn api_bundle_download_init(
+    ctx: Ctx,
+    client: audit::Client,
+    download_key: BundleDownloadKeyAccessPost,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    struct Member {
+        upload: models::Upload,
+        init_download_content: models::InitDownload,
+        init_download_confirm: models::InitDownload,
+    }
+
+    respond(async move {
+        let now = Utc::now();
+        let selected = download_key.members;
+        let download_key = DownloadKeyAccessPost {
+            key: download_key.key,
+            access_password: download_key.access_password,
+        }
+        .decode_hex()?;
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/bundle/download/init");
+        let uuid = download_key.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &download_key.access_password,
+            |conn| async move {
+                let bundle = models::Bundle::find(&conn, &uuid).await?;
+                let auth = bundle.get_access_auth(&conn).await?;
+                Ok((bundle, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let initialized: error::Result<Vec<Member>> = async {
+            let bundle = models::Bundle::find(&trans, &uuid).await?;
+            let access_auth = bundle.get_access_auth(&trans).await?;
+            let verified = verify_password(&trans, &config, &bundle, &access_auth, check).await;
+            audit::record(
+                &trans,
+                audit::Event::BundleDownloadInit,
+                audit::Outcome::of(&verified),
+                &bundle,
+                &client,
+            )
+            .await?;
+            verified?;
+            // the password is verified, so expired bundles can be told apart from missing ones
+            let n_downloads = bundle.download_count(&trans).await? as i32;
+            if let Some(limit) = bundle.download_limit {
+                if n_downloads >= limit {
+                    return Err(error::helpers::download_limit_reached(
+                        "bundle download limit reached",
+                    ));
+                }
+            }
+            if now >= bundle.expire_date {
+                return Err(error::helpers::expired("bundle expired"));
+            }
+
+            let uploads = bundle
+                .members(&trans)
+                .await?
+                .into_iter()
+                .filter(|upload| upload.finalized)
+                .filter(|upload| match selected {
+                    Some(ref selected) => upload
+                        .bundle_index
+                        .map(|index| selected.contains(&index))
+                        .unwrap_or(false),
+                    None => true,
+                })
+                .collect::<Vec<_>>();
+            if uploads.is_empty() {
+                return Err(error::helpers::does_not_exist("upload not found"));
+            }
+
+            models::NewBundleDownload { bundle: bundle.id }
+                .insert(&trans)
+                .await?;
+            let mut members = Vec::with_capacity(uploads.len());
+            for upload in uploads.into_iter() {
+                let init_download_content = models::NewInitDownload {
+                    uuid: Uuid::new_v4(),
+                    usage: String::from("content"),
+                    upload: upload.id,
+                }
+                .insert(&trans)
+                .await?;
+                let init_download_confirm = models::NewInitDownload {
+                    uuid: Uuid::new_v4(),
+                    usage: String::from("confirm"),
+                    upload: upload.id,
+                }
+                .insert(&trans)
+                .await?;
+                members.push(Member {
+                    upload,
+                    init_download_content,
+                    init_download_confirm,
+                });
+            }
+            Ok(members)
+        }
+        .await;
+        trans.commit().await?;
+        let members = initialized?
+            .iter()
+            .map(|member| BundleDownloadMember {
+                index: member.upload.bundle_index,
+                nonce: hex::encode(&member.upload.nonce),
+                size: member.upload.size,
+                download_key: hex::encode(member.init_download_content.uuid.as_bytes()),
+                confirm_key: hex::encode(member.init_download_confirm.uuid.as_bytes()),
+            })
+            .collect::<Vec<_>>();
+        Ok(warp::reply::json(&BundleDownloadInitResp { members }))
     })
-    .map_err(error::helpers::reject)
+    .await
 }
 
 /// Download encrypted bytes
-pub fn api_download(
+///
+/// A single `Range` of bytes may be requested to resume an interrupted download.
+/// Only the first request against a download key is counted against the upload's
+/// download limit, subsequent requests with the same key are free while the key is
+/// still valid.
+pub async fn api_download(
     ctx: Ctx,
+    client: audit::Client,
     download_key: DownloadKeyAccessPost,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
+    range: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     info!("download started, key: {}", download_key.key);
-    struct Info {
-        now: DateTime<Utc>,
-        download_key: DownloadKeyAccess,
+    struct Data {
+        file_size: u64,
+        range: Option<(u64, u64)>,
+        reader: Box<dyn std::io::Read + Send>,
+        init_download: models::InitDownload,
     }
 
-    let cpu = ctx.cpu;
-    let db = ctx.db;
-    let fs_pool = ctx.fs;
-    futures::future::result::<Info, error::Error>((|| {
+    respond(async move {
         let now = Utc::now();
+        let transfer = ctx.shutdown.begin()?;
         let download_key = download_key.decode_hex()?;
-        Ok(Info { now, download_key })
-    })())
-    .and_then(move |info: Info| {
-        cpu.spawn_fn(move || -> error::Result<models::Upload> {
-            let conn = db.get()?;
-            let trans = conn.transaction()?;
-            trans.set_commit();
-
-            let init_download = models::InitDownload::find(
+        let config = ctx.config.get();
+        let hasher = Hasher::new(&ctx.hash_pool, &config, "/api/download");
+        let uuid = download_key.uuid;
+        let check = check_password(
+            &ctx.db,
+            &config,
+            &ctx.limiter,
+            &hasher,
+            &download_key.access_password,
+            |conn| async move {
+                let init_download =
+                    models::InitDownload::find(&conn, &uuid, models::DownloadType::Content).await?;
+                let upload = init_download.get_upload(&conn).await?;
+                let auth = upload.get_access_auth(&conn).await?;
+                Ok((upload, auth))
+            },
+        )
+        .await?;
+
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let opened: error::Result<Data> = async {
+            let init_download =
+                models::InitDownload::find_for_update(&trans, &uuid, models::DownloadType::Content)
+                    .await?;
+            let upload = init_download.get_upload(&trans).await?;
+            let access_auth = upload.get_access_auth(&trans).await?;
+            let verified = verify_password(&trans, &config, &upload, &access_auth, check).await;
+            audit::record(
                 &trans,
-                &info.download_key.uuid,
-                models::DownloadType::Content,
-            )?;
-            let upload = init_download.get_upload(&trans)?;
-            let access_auth = upload.get_access_auth(&trans)?;
-            access_auth.verify(&info.download_key.access_password)?;
-            let n_downloads = upload.download_count(&trans)? as i32;
-            if let Some(limit) = upload.download_limit {
-                if n_downloads >= limit {
-                    return Err(error::helpers::does_not_exist("upload not found"));
+                audit::Event::Download,
+                audit::Outcome::of(&verified),
+                &upload,
+                &client,
+            )
+            .await?;
+            verified?;
+            if upload.deleted || !init_download.still_valid(&config, &now) {
+                return Err(error::helpers::does_not_exist("upload not found"));
+            }
+            if now >= upload.expire_date {
+                return Err(error::helpers::expired("upload expired"));
+            }
+
+            let key = upload.file_path.clone();
+            let file_size = storage::blocking(&ctx.storage, move |storage| storage.stat(&key))
+                .await?
+                .ok_or_else(|| error::helpers::does_not_exist("upload not found"))?;
+            // encrypted blobs are larger than the upload they contain
+            let file_size = if upload.encryption_key_id.is_some() {
+                upload.size as u64
+            } else {
+                file_size
+            };
+            let range = match range {
+                Some(ref header) => parse_range(header, file_size)?,
+                None => None,
+            };
+
+            match init_download.download {
+                // this download key has already been counted, only a range
+                // request resuming the interrupted transfer may reuse it
+                Some(_) => {
+                    if range.is_none() {
+                        return Err(error::helpers::bad_request(
+                            "Download already started, resume it with a range request",
+                        ));
+                    }
+                    init_download.touch(&trans, None).await?;
+                }
+                None => {
+                    let n_downloads = upload.download_count(&trans).await? as i32;
+                    if let Some(limit) = upload.download_limit {
+                        if n_downloads >= limit {
+                            return Err(error::helpers::download_limit_reached(
+                                "upload download limit reached",
+                            ));
+                        }
+                    }
+                    let new_download = models::NewDownload { upload: upload.id };
+                    let download = new_download.insert(&trans).await?;
+                    init_download.touch(&trans, Some(download.id)).await?;
+                    webhooks::enqueue(&trans, &config, &upload, webhooks::Event::Download).await?;
                 }
             }
-            if info.now >= upload.expire_date {
-                return Err(error::helpers::does_not_exist("upload not found"));
+
+            let keys = ctx.keys.clone();
+            let (offset, len) = match range {
+                Some((start, end)) => (start, Some(end - start + 1)),
+                None => (0, None),
+            };
+            let reader = storage::blocking(&ctx.storage, move |storage| {
+                envelope::open(storage, keys.as_deref(), &upload, offset, len)
+            })
+            .await?;
+            Ok(Data {
+                file_size,
+                range,
+                reader,
+                init_download,
+            })
+        }
+        .await;
+        trans.commit().await?;
+        drop(conn);
+        let data = opened?;
+
+        use warp::http::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE};
+
+        // a transfer reaching the end of the file uses up its download key,
+        // once the last byte has been handed off
+        let completes = data.range.is_none_or(|(_, end)| end + 1 == data.file_size);
+        let init_download = data.init_download;
+        let db = ctx.db.clone();
+        let consume = stream::once(async move {
+            let consumed: error::Result<()> = async {
+                if completes {
+                    let conn = db.get().await?;
+                    init_download.delete(&conn).await?;
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = consumed {
+                error!("Failed removing completed download key: {}", e);
+            }
+        })
+        .filter_map(|()| future::ready(None));
+
+        // the transfer stays active until the response body is dropped
+        let stream = storage::read_stream(data.reader)
+            .chain(consume)
+            .inspect_ok(move |bytes| {
+                let _ = &transfer;
+                metrics::DOWNLOADED_BYTES.inc_by(bytes.len() as i64);
+            });
+        let body = warp::hyper::Body::wrap_stream(stream);
+        Ok(match data.range {
+            None => warp::http::Response::builder()
+                .header(ACCEPT_RANGES, "bytes")
+                .header(CONTENT_LENGTH, data.file_size)
+                .body(body),
+            Some((start, end)) => {
+                let len = end - start + 1;
+                warp::http::Response::builder()
+                    .status(warp::http::StatusCode::PARTIAL_CONTENT)
+                    .header(ACCEPT_RANGES, "bytes")
+                    .header(CONTENT_LENGTH, len)
+                    .header(
+                        CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, data.file_size),
+                    )
+                    .body(body)
             }
-            let new_download = models::NewDownload { upload: upload.id };
-            new_download.insert(&trans)?;
-            init_download.delete(&trans)?;
-            Ok(upload)
         })
     })
-    .map(move |upload| {
-        let stream = fs_pool.read(upload.file_path, Default::default());
-        let body = hyper::Body::wrap_stream(stream);
-        warp::http::Response::builder().body(body)
-    })
-    .map_err(error::helpers::reject)
+    .await
     // if request.header("x-proxy-nginx").unwrap_or("") == "true" {
     //     let upload_path = format!("/private/{}", hex::encode(upload.uuid.as_bytes()));
     //     let resp = Response::empty_400()
@@ -521,52 +2248,66 @@ pub fn api_download(
     // }
 }
 
-/// Download identifier and corresponding decrypted content hash
-#[derive(Deserialize)]
-pub struct DownloadKeyHash {
-    key: String,
-    hash: String,
+api_type! {
+    /// Download identifier and corresponding decrypted content hash
+    #[derive(Serialize, Deserialize)]
+    pub struct DownloadKeyHash {
+        pub key: String,
+        pub hash: String,
+    }
+}
+
+api_type! {
+    /// Hash of the decrypted file's name, revealed once its content hash is confirmed
+    #[derive(Serialize, Deserialize)]
+    pub struct DownloadConfirmResp {
+        pub file_name_hash: String,
+    }
 }
 
 /// Obtain the decrypted file's name
 ///
 /// Upload identifier and a matching hash of the decrypted content are required
-pub fn api_download_confirm(
+pub async fn api_download_confirm(
     ctx: Ctx,
+    client: audit::Client,
     download_key: DownloadKeyHash,
-) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
-    struct Info {
-        hash_bytes: Vec<u8>,
-        uuid: Uuid,
-    }
-
-    let cpu = ctx.cpu;
-    let db = ctx.db;
-    futures::future::result::<Info, error::Error>((|| {
+) -> Result<impl warp::Reply, warp::Rejection> {
+    respond(async move {
         let hash_bytes = Vec::from_hex(&download_key.hash)
             .map_err(|_| error::helpers::bad_request("malformed info"))?;
         let uuid_bytes = Vec::from_hex(&download_key.key)?;
-        let uuid = Uuid::from_bytes(&uuid_bytes)
+        let uuid = Uuid::from_slice(&uuid_bytes)
             .map_err(|_| error::helpers::does_not_exist("upload not found"))?;
-        Ok(Info { hash_bytes, uuid })
-    })())
-    .and_then(move |info| {
-        cpu.spawn_fn(move || -> error::Result<models::Upload> {
-            let conn = db.get()?;
-            let trans = conn.transaction()?;
-            trans.set_commit();
+        let config = ctx.config.get();
 
+        let mut conn = ctx.db.get().await?;
+        let trans = conn.transaction().await?;
+        let confirmed: error::Result<models::Upload> = async {
             let init_download =
-                models::InitDownload::find(&*conn, &info.uuid, models::DownloadType::Confirm)?;
-            let upload = init_download.get_upload(&trans)?;
-            auth::eq(&info.hash_bytes, &upload.content_hash)?;
-            init_download.delete(&trans)?;
+                models::InitDownload::find(&trans, &uuid, models::DownloadType::Confirm).await?;
+            let upload = init_download.get_upload(&trans).await?;
+            let verified = auth::eq(&hash_bytes, &upload.content_hash)
+                .map_err(|_| error::helpers::invalid_auth("content hash mismatch"));
+            audit::record(
+                &trans,
+                audit::Event::DownloadConfirm,
+                audit::Outcome::of(&verified),
+                &upload,
+                &client,
+            )
+            .await?;
+            verified?;
+            init_download.delete(&trans).await?;
+            webhooks::enqueue(&trans, &config, &upload, webhooks::Event::DownloadConfirmed).await?;
             Ok(upload)
-        })
-    })
-    .map(|upload| {
-        let resp = json!({"file_name_hash": hex::encode(&upload.file_name_hash)});
-        warp::reply::json(&resp)
+        }
+        .await;
+        trans.commit().await?;
+        let upload = confirmed?;
+        Ok(warp::reply::json(&DownloadConfirmResp {
+            file_name_hash: hex::encode(&upload.file_name_hash),
+        }))
     })
-    .map_err(error::helpers::reject)
+    .await
 }
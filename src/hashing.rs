@@ -0,0 +1,196 @@
+/*!
+Password hashing executor
+
+Password hashes are deliberately expensive, so they run on a small dedicated pool
+instead of the async runtime's threads, which serve every request. The pool's queue is bounded:
+once `hash_queue_depth` hashes are waiting, further requests are refused with
+`ErrorKind::Busy` (`503`) instead of queueing behind them.
+
+Hashes resolve as futures, so no thread blocks waiting on them, and handlers hash
+before taking a db connection. Each route waits at most its `hash_route_timeouts_ms`
+entry, or `hash_timeout_ms`, for its hashes to be queued and computed.
+*/
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time;
+
+use crate::auth::{self, PasswordHashing};
+use crate::config::Config;
+use crate::error::{self, Result};
+use crate::metrics;
+use crate::models;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Seconds clients are asked to wait before retrying when the pool is saturated
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Routes that hash passwords, which may set their own `hash_route_timeouts_ms`
+pub const ROUTES: &[&str] = &[
+    "/api/upload/init",
+    "/api/upload/delete",
+    "/api/upload/update",
+    "/api/download/init",
+    "/api/download",
+    "/api/bundle/init",
+    "/api/bundle/delete",
+    "/api/bundle/download/init",
+];
+
+/// Fixed size pool of hashing threads with a bounded queue
+pub struct HashPool {
+    jobs: Mutex<SyncSender<Job>>,
+}
+impl HashPool {
+    /// Start `threads` hashing threads, queueing at most `queue_depth` pending hashes
+    pub fn new(threads: usize, queue_depth: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for n in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("hash-{}", n))
+                .spawn(move || work(&receiver))
+                .expect("Failed spawning hashing thread");
+        }
+        Self {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    /// Hash `pass` with `salt` using `hashing`, resolving once the hash is computed,
+    /// or failing if it takes longer than `timeout` to be queued and computed
+    pub async fn hash(
+        &self,
+        hashing: &PasswordHashing,
+        pass: &[u8],
+        salt: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let (done, result) = oneshot::channel();
+        let (hashing, pass, salt) = (hashing.clone(), pass.to_vec(), salt.to_vec());
+        let job: Job = Box::new(move || {
+            // nobody is waiting on hashes that timed out while queued
+            if !done.is_closed() {
+                let _ = done.send(hashing.hash(&pass, &salt));
+            }
+        });
+        let queued = self
+            .jobs
+            .lock()
+            .expect("hash queue lock poisoned")
+            .try_send(job);
+        match queued {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                metrics::HASH_REJECTIONS
+                    .with_label_values(&["queue_full"])
+                    .inc();
+                return Err(error::helpers::busy("Too many pending requests, try again")
+                    .with_retry_after(RETRY_AFTER_SECS));
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error::helpers::internal("Hashing threads stopped"));
+            }
+        }
+        match time::timeout(timeout, result).await {
+            Ok(Ok(hash)) => hash,
+            Ok(Err(e)) => Err(error::helpers::internal(format!("Hashing failed: {:?}", e))),
+            Err(_) => {
+                metrics::HASH_REJECTIONS
+                    .with_label_values(&["timeout"])
+                    .inc();
+                Err(error::helpers::busy("Timed out, try again").with_retry_after(RETRY_AFTER_SECS))
+            }
+        }
+    }
+}
+
+/// Run queued jobs until the pool is dropped
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().expect("hash queue lock poisoned").recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+/// Outcome of checking a password against an `auth` record, computed before the
+/// record's transaction and applied with `models::Auth::verify`
+pub struct Verification {
+    pub matched: bool,
+    /// A new hash of the matched password, when the record was hashed with
+    /// different settings than the configured ones
+    pub rehash: Option<models::NewAuth>,
+}
+
+/// A pool and the configured hashing settings, for the duration of a request
+#[derive(Clone)]
+pub struct Hasher {
+    pub pool: Arc<HashPool>,
+    pub hashing: PasswordHashing,
+    pub timeout: Duration,
+}
+impl Hasher {
+    /// Hasher running on `pool`, using `config`'s hashing settings and the
+    /// hash timeout of `route`, one of `ROUTES`
+    pub fn new(pool: &Arc<HashPool>, config: &Config, route: &str) -> Self {
+        let timeout_ms = config
+            .hash_route_timeouts_ms
+            .get(route)
+            .cloned()
+            .unwrap_or(config.hash_timeout_ms);
+        Self {
+            pool: pool.clone(),
+            hashing: config.password_hashing.clone(),
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    /// Hash `pass` with `salt` using the configured settings
+    pub async fn hash(&self, pass: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
+        self.hash_with(&self.hashing, pass, salt).await
+    }
+
+    /// Hash `pass` with `salt` using `hashing`, e.g. the settings stored with an existing hash
+    pub async fn hash_with(
+        &self,
+        hashing: &PasswordHashing,
+        pass: &[u8],
+        salt: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.pool.hash(hashing, pass, salt, self.timeout).await
+    }
+
+    /// Hash `pass` with a new salt, using the configured settings
+    pub async fn new_auth(&self, pass: &[u8]) -> Result<models::NewAuth> {
+        let salt = auth::new_salt()?;
+        let hash = self.hash(pass, &salt).await?;
+        Ok(models::NewAuth {
+            salt,
+            hash,
+            algorithm: self.hashing.algorithm().to_string(),
+            params: self.hashing.params(),
+        })
+    }
+
+    /// Check `pass` against `auth`, also re-hashing a matching password when `auth`
+    /// was hashed with different settings than the configured ones
+    pub async fn check(&self, auth: &models::Auth, pass: &[u8]) -> Result<Verification> {
+        let stored = PasswordHashing::from_stored(&auth.algorithm, &auth.params)?;
+        let hash = self.hash_with(&stored, pass, &auth.salt).await?;
+        let matched = auth::eq(&auth.hash, &hash).is_ok();
+        let rehash = if matched && stored != self.hashing {
+            Some(self.new_auth(pass).await?)
+        } else {
+            None
+        };
+        Ok(Verification { matched, rehash })
+    }
+}
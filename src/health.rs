@@ -0,0 +1,166 @@
+/*!
+Readiness checks
+
+Each check reports whether a dependency needed to serve requests is available,
+along with details to help diagnose failures.
+*/
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::Timeouts;
+use fs2;
+use tokio::task;
+
+use crate::config::Config;
+use crate::db;
+use crate::service::Ctx;
+use crate::storage::{Storage, StorageConfig};
+use crate::sweep;
+
+/// Max time to wait for a pooled database connection
+const DB_TIMEOUT_SECS: u64 = 2;
+
+/// Extra time allowed past the sweeper interval before it's considered stalled
+const SWEEP_GRACE_SECS: u64 = 60;
+
+/// Migrations can't be un-applied while running, so stop checking once they're complete
+static MIGRATIONS_COMPLETE: AtomicBool = AtomicBool::new(false);
+
+/// Result of a single check
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub details: serde_json::Value,
+}
+impl Check {
+    fn ok(name: &'static str, details: serde_json::Value) -> Self {
+        Self {
+            name,
+            ok: true,
+            details,
+        }
+    }
+
+    fn failed<T: std::fmt::Display>(name: &'static str, error: T) -> Self {
+        Self {
+            name,
+            ok: false,
+            details: json!({ "error": error.to_string() }),
+        }
+    }
+}
+
+/// Get a pooled database connection, waiting at most `DB_TIMEOUT_SECS`
+async fn get_conn(ctx: &Ctx) -> Result<db::DbConn, deadpool_postgres::PoolError> {
+    let timeout = Some(Duration::from_secs(DB_TIMEOUT_SECS));
+    ctx.db
+        .timeout_get(&Timeouts {
+            wait: timeout,
+            create: timeout,
+            recycle: timeout,
+        })
+        .await
+}
+
+/// A pooled database connection can be acquired and used
+async fn check_database(ctx: &Ctx) -> Check {
+    let conn = match get_conn(ctx).await {
+        Ok(conn) => conn,
+        Err(e) => return Check::failed("database", e),
+    };
+    match conn.query("select 1", &[]).await {
+        Ok(_) => Check::ok("database", json!({})),
+        Err(e) => Check::failed("database", e),
+    }
+}
+
+/// All migrations in the migration directory have been applied
+async fn check_migrations(ctx: &Ctx) -> Check {
+    if MIGRATIONS_COMPLETE.load(Ordering::Relaxed) {
+        return Check::ok("migrations", json!({}));
+    }
+    let conn = match get_conn(ctx).await {
+        Ok(conn) => conn,
+        Err(e) => return Check::failed("migrations", e),
+    };
+    match db::pending_migrations(&conn).await {
+        Err(e) => Check::failed("migrations", e),
+        Ok(ref pending) if pending.is_empty() => {
+            MIGRATIONS_COMPLETE.store(true, Ordering::Relaxed);
+            Check::ok("migrations", json!({}))
+        }
+        Ok(pending) => Check {
+            name: "migrations",
+            ok: false,
+            details: json!({ "error": "migrations pending", "pending": pending }),
+        },
+    }
+}
+
+/// The configured storage backend is reachable and can store items. Local backends
+/// must also have room for at least one max size upload. Blocks.
+fn check_storage(storage: &dyn Storage, config: &Config) -> Check {
+    if let Err(e) = storage.check() {
+        return Check::failed("storage", e);
+    }
+    let backend = match config.storage {
+        StorageConfig::S3 { .. } => return Check::ok("storage", json!({ "backend": "s3" })),
+        StorageConfig::Local => "local",
+        StorageConfig::Sharded { .. } => "sharded",
+    };
+    let dir = match config.upload_dir() {
+        Ok(dir) => dir,
+        Err(e) => return Check::failed("storage", e),
+    };
+    let available = match fs2::available_space(&dir) {
+        Ok(n) => n,
+        Err(e) => return Check::failed("storage", e),
+    };
+    let required = config.upload_limit_bytes as u64;
+    Check {
+        name: "storage",
+        ok: available >= required,
+        details: json!({
+            "backend": backend,
+            "available_bytes": available,
+            "required_bytes": required,
+        }),
+    }
+}
+
+/// The sweeper has completed a run within its configured interval
+fn check_sweeper(ctx: &Ctx) -> Check {
+    let config = ctx.config.get();
+    let max_age = Duration::from_secs(config.expired_cleanup_interval_secs + SWEEP_GRACE_SECS);
+    match sweep::last_sweep() {
+        None => Check::failed("sweeper", "sweeper hasn't run yet"),
+        Some(last) => {
+            let age = Instant::now().duration_since(last);
+            Check {
+                name: "sweeper",
+                ok: age <= max_age,
+                details: json!({
+                    "last_run_secs_ago": age.as_secs(),
+                    "max_secs": max_age.as_secs(),
+                }),
+            }
+        }
+    }
+}
+
+/// Run all readiness checks
+pub async fn readiness(ctx: &Ctx) -> Vec<Check> {
+    let storage = Arc::clone(&ctx.storage);
+    let config = ctx.config.get();
+    let storage = match task::spawn_blocking(move || check_storage(&*storage, &config)).await {
+        Ok(check) => check,
+        Err(e) => Check::failed("storage", e),
+    };
+    vec![
+        check_database(ctx).await,
+        check_migrations(ctx).await,
+        storage,
+        check_sweeper(ctx),
+    ]
+}
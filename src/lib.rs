@@ -4,14 +4,16 @@
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
+extern crate argon2;
+extern crate base64;
 extern crate chrono;
 extern crate crypto;
+extern crate deadpool_postgres;
 extern crate env_logger;
+extern crate fs2;
 extern crate hex;
 extern crate migrant_lib;
-extern crate postgres;
-extern crate r2d2;
-extern crate r2d2_postgres;
+extern crate reqwest;
 extern crate ring;
 extern crate ron;
 extern crate serde;
@@ -20,29 +22,43 @@ extern crate uuid;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate async_trait;
 extern crate futures;
-extern crate futures_cpupool;
-extern crate futures_fs;
-extern crate hyper;
 extern crate num_cpus;
 extern crate serde_urlencoded;
+#[macro_use]
+extern crate prometheus;
 extern crate tokio;
+extern crate tokio_postgres;
 extern crate warp;
 extern crate xdg;
 
 #[macro_use]
 pub mod macros;
 pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod client;
+pub mod config;
 pub mod db;
+pub mod envelope;
 pub mod error;
 pub mod handlers;
+pub mod hashing;
+pub mod health;
+pub mod locks;
+pub mod metrics;
 pub mod models;
+pub mod openapi;
+pub mod ratelimit;
 pub mod service;
+pub mod shutdown;
+pub mod storage;
 pub mod sweep;
+pub mod webhooks;
 
+pub use config::Config;
 use error::Result;
-pub use models::CONFIG;
 
 pub static APPNAME: &str = "Transfer";
 
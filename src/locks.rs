@@ -0,0 +1,46 @@
+/*!
+Write locks of in-progress uploads
+
+An `init_upload`'s staging file is written by one request at a time. Locks are
+held in process, so no database connection is tied up while a request streams
+its bytes from the network.
+*/
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+pub struct UploadLocks {
+    locked: Mutex<HashSet<i32>>,
+}
+impl UploadLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Try taking the write lock of the `init_upload` with `id`, returning
+    /// `None` if another writer holds it. The lock is released when dropped.
+    pub fn try_lock(self: &Arc<Self>, id: i32) -> Option<UploadLock> {
+        let mut locked = self.locked.lock().expect("upload lock poisoned");
+        if !locked.insert(id) {
+            return None;
+        }
+        Some(UploadLock {
+            locks: self.clone(),
+            id,
+        })
+    }
+}
+
+/// Write lock of an `init_upload` record and its staging file. Writers that
+/// can't take the lock must not touch the staging file.
+pub struct UploadLock {
+    locks: Arc<UploadLocks>,
+    id: i32,
+}
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        if let Ok(mut locked) = self.locks.locked.lock() {
+            locked.remove(&self.id);
+        }
+    }
+}
@@ -2,15 +2,17 @@
 Macros
 
 For working with
-    - `postgres`
+    - `tokio_postgres`
+    - OpenAPI schemas
 */
 
 // -------------
-// postgres
+// tokio_postgres
 // -------------
 
 /// Attempts to execute an `insert`, using provided and returned columns
 /// to return a populated instance of the associated model `struct`.
+/// The query is awaited before it's passed in.
 ///
 /// Returns a `Result` containing the given model
 ///
@@ -29,10 +31,10 @@ For working with
 ///
 /// ```rust,ignore
 /// impl NewPaste {
-///     fn create(self, conn: &Connection) -> Result<Paste> {
+///     async fn create<T: GenericClient>(self, conn: &T) -> Result<Paste> {
 ///         let stmt = "insert into pastes (key, content, content_type) values ($1, $2, $3) \
 ///                     returning id, date_created, date_viewed";
-///         try_query_to_model!(conn.query(stmt, &[&self.key, &self.content, &self.content_type]) ;
+///         try_query_to_model!(conn.query(stmt, &[&self.key, &self.content, &self.content_type]).await ;
 ///                              Paste ;
 ///                              id: 0, date_created: 1, date_viewed: 2 ;
 ///                              key: self.key, content: self.content, content_type: self.content_type)
@@ -78,9 +80,9 @@ macro_rules! try_query_to_model {
 /// # Example
 ///
 /// ```rust,ignore
-/// fn find_all(text: &str, conn: &Connection) -> Result<Vec<Paste>> {
+/// async fn find_all<T: GenericClient>(text: &str, conn: &T) -> Result<Vec<Paste>> {
 ///     let stmt = "select * from pastes where content like '%' || $1 || '%'";
-///     try_query_vec!(conn.query(stmt, &[&text]), Paste)
+///     try_query_vec!(conn.query(stmt, &[&text]).await, Paste)
 /// }
 /// ```
 macro_rules! try_query_vec {
@@ -104,10 +106,10 @@ macro_rules! try_query_vec {
 /// # Example
 ///
 /// ```rust,ignore
-/// fn touch_and_get(key: &str, conn: &Connection) -> Result<Paste> {
+/// async fn touch_and_get<T: GenericClient>(key: &str, conn: &T) -> Result<Paste> {
 ///     let stmt = "update pastes set date_viewed = NOW() where key = $1 \
 ///                 returning id, key, content, content_type, date_created, date_viewed";
-///     try_query_one!(conn.query(stmt, &[&key]), Paste)
+///     try_query_one!(conn.query(stmt, &[&key]).await, Paste)
 /// }
 /// ```
 macro_rules! try_query_one {
@@ -145,9 +147,9 @@ macro_rules! try_query_one {
 /// # Example
 ///
 /// ```rust,ignore
-/// fn exists(conn: &Connection, key: &str) -> Result<bool> {
+/// async fn exists<T: GenericClient>(conn: &T, key: &str) -> Result<bool> {
 ///     let stmt = "select exists(select 1 from pastes where key = $1)";
-///     try_query_aggregate!(conn.query(stmt, &[&key]), bool)
+///     try_query_aggregate!(conn.query(stmt, &[&key]).await, bool)
 /// }
 /// ```
 macro_rules! try_query_aggregate {
@@ -164,3 +166,62 @@ macro_rules! try_query_aggregate {
         }
     };
 }
+
+// -------------
+// openapi
+// -------------
+
+/// Defines a request or response `struct` and implements `openapi::Schema` for it,
+/// describing each field by its name and type. `Option` fields are nullable and
+/// not required.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// api_type! {
+///     /// Upload identifier
+///     #[derive(Serialize, Deserialize)]
+///     pub struct UploadKey {
+///         pub key: String,
+///     }
+/// }
+/// ```
+macro_rules! api_type {
+    ($(#[$attr:meta])*
+     pub struct $name:ident {
+         $($(#[$field_attr:meta])* $vis:vis $field:ident : $field_type:ty),* $(,)*
+     }) => {
+        $(#[$attr])*
+        pub struct $name {
+            $(
+                $(#[$field_attr])*
+                $vis $field: $field_type,
+            )*
+        }
+
+        impl $crate::openapi::Schema for $name {
+            fn schema() -> serde_json::Value {
+                let mut properties = serde_json::Map::new();
+                let mut required: Vec<&str> = vec![];
+                $(
+                    properties.insert(
+                        stringify!($field).to_string(),
+                        <$field_type as $crate::openapi::Schema>::schema(),
+                    );
+                    if <$field_type as $crate::openapi::Schema>::required() {
+                        required.push(stringify!($field));
+                    }
+                )*
+                let mut schema = json!({
+                    "title": stringify!($name),
+                    "type": "object",
+                    "properties": properties,
+                });
+                if !required.is_empty() {
+                    schema["required"] = json!(required);
+                }
+                schema
+            }
+        }
+    };
+}
@@ -2,12 +2,14 @@
 #[macro_use]
 extern crate clap;
 extern crate migrant_lib;
+extern crate tokio;
 extern crate transfer;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use migrant_lib::config::PostgresSettingsBuilder;
 use migrant_lib::Config;
 use std::env;
+use std::future::Future;
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
@@ -19,6 +21,14 @@ pub fn main() {
     }
 }
 
+/// Run an admin command to completion
+fn block_on<F: Future>(command: F) -> Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(command))
+}
+
 fn run() -> Result<()> {
     let matches = App::new(transfer::APPNAME)
         .version(crate_version!())
@@ -30,15 +40,80 @@ fn run() -> Result<()> {
                     Arg::with_name("debug")
                         .long("debug")
                         .help("Output debug logging info. Shortcut for setting env-var LOG=debug"),
+                )
+                .arg(config_file_arg())
+                .arg(config_set_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("upload")
+                .about("Encrypt and upload a file")
+                .arg(Arg::with_name("file").required(true).help("File to upload"))
+                .arg(url_arg())
+                .arg(access_password_arg())
+                .arg(encrypt_password_arg())
+                .arg(
+                    Arg::with_name("deletion-password")
+                        .long("deletion-password")
+                        .takes_value(true)
+                        .env("TRANSFER_DELETION_PASSWORD")
+                        .help("Password allowing the upload to be deleted"),
+                )
+                .arg(
+                    Arg::with_name("download-limit")
+                        .long("download-limit")
+                        .takes_value(true)
+                        .help("Maximum number of downloads"),
+                )
+                .arg(
+                    Arg::with_name("lifespan")
+                        .long("lifespan")
+                        .takes_value(true)
+                        .help("Seconds until the upload expires"),
+                )
+                .arg(
+                    Arg::with_name("api-token")
+                        .long("api-token")
+                        .takes_value(true)
+                        .env("TRANSFER_API_TOKEN")
+                        .help("Api token to upload with"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("download")
+                .about("Download and decrypt a file")
+                .arg(
+                    Arg::with_name("key")
+                        .required(true)
+                        .help("Download key, as returned by `upload`"),
+                )
+                .arg(url_arg())
+                .arg(access_password_arg())
+                .arg(encrypt_password_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Output file path, defaults to the uploaded file name. Use `-` for stdout"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("admin")
                 .about("admin functions")
+                .arg(config_file_arg())
+                .arg(config_set_arg())
                 .subcommand(
                     SubCommand::with_name("config-dir")
                         .about("print the xdg config directory being used"),
                 )
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .about("configuration functions")
+                        .subcommand(SubCommand::with_name("check").about(
+                            "Validate and print the effective configuration, after applying \
+                             the config file, env vars, and --set flags",
+                        )),
+                )
                 .subcommand(
                     SubCommand::with_name("database")
                         .about("database functions")
@@ -53,20 +128,131 @@ fn run() -> Result<()> {
                 )
                 .subcommand(SubCommand::with_name("sweep-files").about(
                     "Sweep up orphaned files that are no longer referenced in the database",
-                )),
+                ))
+                .subcommand(SubCommand::with_name("verify-files").about(
+                    "Verify stored upload files against the hashes recorded when they were uploaded",
+                ))
+                .subcommand(
+                    SubCommand::with_name("keys")
+                        .about("encryption at rest key management")
+                        .subcommand(SubCommand::with_name("rotate").about(
+                            "Add a new encryption key and re-wrap existing uploads' data keys with it",
+                        ))
+                        .subcommand(SubCommand::with_name("prune").about(
+                            "Remove encryption keys that are no longer used by any upload",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("tokens")
+                        .about("api token management")
+                        .subcommand(
+                            SubCommand::with_name("create")
+                                .about("Create an api token, printing the token")
+                                .arg(
+                                    Arg::with_name("name")
+                                        .required(true)
+                                        .help("Unique name of the token's user"),
+                                )
+                                .arg(
+                                    Arg::with_name("quota-bytes")
+                                        .long("quota-bytes")
+                                        .takes_value(true)
+                                        .help("Max combined bytes of stored uploads"),
+                                )
+                                .arg(
+                                    Arg::with_name("max-file-bytes")
+                                        .long("max-file-bytes")
+                                        .takes_value(true)
+                                        .help("Max bytes of a single upload"),
+                                )
+                                .arg(
+                                    Arg::with_name("max-lifespan-secs")
+                                        .long("max-lifespan-secs")
+                                        .takes_value(true)
+                                        .help("Max lifespan of an upload in seconds"),
+                                )
+                                .arg(
+                                    Arg::with_name("max-download-limit")
+                                        .long("max-download-limit")
+                                        .takes_value(true)
+                                        .help("Max download limit of an upload"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("list").about("List api tokens and their usage"),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("revoke")
+                                .about("Revoke an api token")
+                                .arg(
+                                    Arg::with_name("name")
+                                        .required(true)
+                                        .help("Name of the token to revoke"),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("audit")
+                        .about("Export audit log events as JSON lines, oldest first")
+                        .arg(
+                            Arg::with_name("since")
+                                .long("since")
+                                .takes_value(true)
+                                .help("Only events at or after this RFC 3339 date"),
+                        )
+                        .arg(
+                            Arg::with_name("until")
+                                .long("until")
+                                .takes_value(true)
+                                .help("Only events before this RFC 3339 date"),
+                        )
+                        .arg(
+                            Arg::with_name("event")
+                                .long("event")
+                                .takes_value(true)
+                                .help("Only events of this type, e.g. `upload_delete`"),
+                        )
+                        .arg(
+                            Arg::with_name("outcome")
+                                .long("outcome")
+                                .takes_value(true)
+                                .possible_values(&["success", "failure", "locked", "rate_limited", "error"])
+                                .help("Only events with this outcome"),
+                        )
+                        .arg(
+                            Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .help("Only events of the upload or bundle with this key"),
+                        )
+                        .arg(
+                            Arg::with_name("limit")
+                                .long("limit")
+                                .takes_value(true)
+                                .help("Max number of events"),
+                        ),
+                ),
         )
         .get_matches();
 
     match matches.subcommand() {
         ("admin", Some(admin_matches)) => {
-            admin(&admin_matches)?;
+            admin(admin_matches)?;
+        }
+        ("upload", Some(upload_matches)) => {
+            upload(upload_matches)?;
+        }
+        ("download", Some(download_matches)) => {
+            download(download_matches)?;
         }
         ("serve", Some(serve_matches)) => {
             env::set_var("LOG", "info");
             if serve_matches.is_present("debug") {
                 env::set_var("LOG", "debug");
             }
-            transfer::service::start()?;
+            let overrides = config_overrides(serve_matches)?;
+            let config = transfer::Config::load(&overrides)?;
+            transfer::service::start(config, overrides)?;
         }
         _ => {
             eprintln!("{}: see `--help`", transfer::APPNAME);
@@ -75,6 +261,136 @@ fn run() -> Result<()> {
     Ok(())
 }
 
+fn config_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .help("Config file to use instead of the `config.ron` in the config or running directory")
+}
+
+fn config_set_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("set")
+        .long("set")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Override a config setting, e.g. `--set upload_limit_bytes=1000`. Takes precedence over the config file and TRANSFER_* env vars")
+}
+
+/// Collect the `--config` and `--set` config layers
+fn config_overrides(matches: &ArgMatches) -> Result<transfer::config::Overrides> {
+    let mut overrides = transfer::config::Overrides {
+        file: matches.value_of("config").map(std::path::PathBuf::from),
+        ..Default::default()
+    };
+    for setting in matches.values_of("set").into_iter().flatten() {
+        let mut parts = setting.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => overrides
+                .settings
+                .push((name.trim().to_string(), value.to_string())),
+            _ => {
+                return Err(format!("Invalid --set {:?}, expected `setting=value`", setting).into())
+            }
+        }
+    }
+    Ok(overrides)
+}
+
+fn url_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("url")
+        .long("url")
+        .takes_value(true)
+        .env("TRANSFER_URL")
+        .default_value("http://localhost:3300")
+        .help("Transfer server url")
+}
+
+fn access_password_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("access-password")
+        .long("access-password")
+        .takes_value(true)
+        .required(true)
+        .env("TRANSFER_ACCESS_PASSWORD")
+        .help("Password required to download the file")
+}
+
+fn encrypt_password_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("encrypt-password")
+        .long("encrypt-password")
+        .takes_value(true)
+        .required(true)
+        .env("TRANSFER_ENCRYPT_PASSWORD")
+        .help("Password the file is encrypted with")
+}
+
+/// Parse an optional argument value, erroring if it's present but invalid
+fn optional_value<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid --{}", name).into()),
+    }
+}
+
+pub fn upload(matches: &ArgMatches) -> Result<()> {
+    let client = transfer::client::Client::new(matches.value_of("url").unwrap());
+    let opts = transfer::client::UploadOptions {
+        access_password: matches.value_of("access-password").unwrap().to_string(),
+        encrypt_password: matches.value_of("encrypt-password").unwrap().to_string(),
+        deletion_password: matches.value_of("deletion-password").map(String::from),
+        download_limit: value_t!(matches, "download-limit", u32).ok(),
+        lifespan: value_t!(matches, "lifespan", i64).ok(),
+        api_token: matches.value_of("api-token").map(String::from),
+    };
+    if matches.is_present("download-limit") && opts.download_limit.is_none() {
+        return Err("Invalid --download-limit".into());
+    }
+    if matches.is_present("lifespan") && opts.lifespan.is_none() {
+        return Err("Invalid --lifespan".into());
+    }
+    let uploaded = client.upload_file(matches.value_of("file").unwrap(), &opts)?;
+    eprintln!("Uploaded {} bytes", uploaded.size);
+    println!("{}", uploaded.key);
+    Ok(())
+}
+
+pub fn download(matches: &ArgMatches) -> Result<()> {
+    use std::io::Write;
+
+    let client = transfer::client::Client::new(matches.value_of("url").unwrap());
+    let downloaded = client.download(
+        matches.value_of("key").unwrap(),
+        matches.value_of("access-password").unwrap(),
+        matches.value_of("encrypt-password").unwrap(),
+    )?;
+    match matches.value_of("output") {
+        Some("-") => {
+            std::io::stdout().write_all(&downloaded.data)?;
+        }
+        output => {
+            // only ever use the base name of the uploaded file name
+            let path = match output {
+                Some(path) => path,
+                None => std::path::Path::new(&downloaded.file_name)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or("Invalid file name, specify an --output")?,
+            };
+            // don't clobber existing files
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)?
+                .write_all(&downloaded.data)?;
+            eprintln!("Saved {}", path);
+        }
+    }
+    Ok(())
+}
+
 pub fn admin(matches: &ArgMatches) -> Result<()> {
     if matches.subcommand_matches("config-dir").is_some() {
         println!("{}", transfer::config_dir()?.display());
@@ -136,7 +452,7 @@ pub fn admin(matches: &ArgMatches) -> Result<()> {
                     }
                 }
                 // propagate other errors
-                let _ = res?;
+                res?;
                 return Ok(());
             }
             _ => println!("see `--help`"),
@@ -144,8 +460,107 @@ pub fn admin(matches: &ArgMatches) -> Result<()> {
         return Ok(());
     }
 
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        match config_matches.subcommand() {
+            ("check", _) => {
+                let config = transfer::Config::load_unvalidated(&config_overrides(matches)?)?;
+                println!("{}", config.to_ron()?);
+                config.validate()?;
+                eprintln!("Configuration ok");
+            }
+            _ => println!("see `--help`"),
+        }
+        return Ok(());
+    }
+
+    let config =
+        || -> Result<transfer::Config> { Ok(transfer::Config::load(&config_overrides(matches)?)?) };
+
     if matches.subcommand_matches("sweep-files").is_some() {
-        transfer::admin::sweep_files()?;
+        block_on(transfer::admin::sweep_files(&config()?))??;
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("verify-files").is_some() {
+        let failed = block_on(transfer::admin::verify_files(&config()?))??;
+        for uuid in &failed {
+            println!("{}", hex::encode(uuid.as_bytes()));
+        }
+        if !failed.is_empty() {
+            return Err(format!("{} upload files failed verification", failed.len()).into());
+        }
+        println!("All upload files verified");
+        return Ok(());
+    }
+
+    if let Some(keys_matches) = matches.subcommand_matches("keys") {
+        match keys_matches.subcommand() {
+            ("rotate", _) => {
+                let (key_id, n) = block_on(transfer::admin::rotate_keys(&config()?))??;
+                println!("Added key id={}, re-wrapped {} uploads", key_id, n);
+            }
+            ("prune", _) => {
+                let n = block_on(transfer::admin::prune_keys(&config()?))??;
+                println!("Removed {} unused keys", n);
+            }
+            _ => println!("see `--help`"),
+        }
+        return Ok(());
+    }
+
+    if let Some(tokens_matches) = matches.subcommand_matches("tokens") {
+        match tokens_matches.subcommand() {
+            ("create", Some(create_matches)) => {
+                let token = transfer::models::NewApiToken {
+                    name: create_matches.value_of("name").unwrap().to_string(),
+                    token_hash: vec![],
+                    quota_bytes: optional_value(create_matches, "quota-bytes")?,
+                    max_file_bytes: optional_value(create_matches, "max-file-bytes")?,
+                    max_lifespan_secs: optional_value(create_matches, "max-lifespan-secs")?,
+                    max_download_limit: optional_value(create_matches, "max-download-limit")?,
+                };
+                let (token, token_hex) = block_on(transfer::admin::create_token(token))??;
+                eprintln!("Created token `{}`, it won't be shown again", token.name);
+                println!("{}", token_hex);
+            }
+            ("list", _) => {
+                let show = |n: Option<i64>| n.map(|n| n.to_string()).unwrap_or_else(|| "-".into());
+                println!("name\tuploads\tbytes\tquota_bytes\tmax_file_bytes\tmax_lifespan_secs\tmax_download_limit\trevoked");
+                for token in block_on(transfer::admin::list_tokens())?? {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        token.name,
+                        token.upload_count,
+                        token.total_bytes,
+                        show(token.quota_bytes),
+                        show(token.max_file_bytes),
+                        show(token.max_lifespan_secs),
+                        show(token.max_download_limit.map(i64::from)),
+                        token.revoked,
+                    );
+                }
+            }
+            ("revoke", Some(revoke_matches)) => {
+                let name = revoke_matches.value_of("name").unwrap();
+                block_on(transfer::admin::revoke_token(name))??;
+                println!("Revoked token `{}`", name);
+            }
+            _ => println!("see `--help`"),
+        }
+        return Ok(());
+    }
+
+    if let Some(audit_matches) = matches.subcommand_matches("audit") {
+        let filter = transfer::models::AuditFilter {
+            since: optional_value(audit_matches, "since")?,
+            until: optional_value(audit_matches, "until")?,
+            event: audit_matches.value_of("event").map(String::from),
+            outcome: audit_matches.value_of("outcome").map(String::from),
+            uuid: optional_value(audit_matches, "key")?,
+            limit: optional_value(audit_matches, "limit")?,
+        };
+        let n = block_on(transfer::admin::export_audit(&filter))??;
+        eprintln!("Exported {} events", n);
         return Ok(());
     }
 
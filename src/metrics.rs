@@ -0,0 +1,175 @@
+/*!
+Prometheus metrics
+
+Counters and histograms are updated as requests are served. Gauges describing
+current state (storage usage, active transfers, db pool) are sampled when
+`/metrics` is scraped.
+*/
+use prometheus::{
+    self, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::error::{self, Result};
+use crate::models;
+use crate::service::Ctx;
+
+/// Content type of the prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Routes used as the `route` label. Other paths are labeled `other`
+/// to keep the number of label values bounded.
+const ROUTES: &[&str] = &[
+    "/",
+    "/status",
+    "/status/live",
+    "/status/ready",
+    "/metrics",
+    "/api/hello",
+    "/api/openapi.json",
+    "/api/upload/defaults",
+    "/api/upload/init",
+    "/api/upload",
+    "/api/upload/chunk",
+    "/api/upload/progress",
+    "/api/upload/delete",
+    "/api/upload/update",
+    "/api/uploads",
+    "/api/download/init",
+    "/api/download",
+    "/api/download/confirm",
+    "/api/bundle/init",
+    "/api/bundle/delete",
+    "/api/bundle/download/init",
+];
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "transfer_http_requests_total",
+        "HTTP requests by route, method, and response status",
+        &["route", "method", "status"]
+    )
+    .expect("Failed registering metric");
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "transfer_http_request_duration_seconds",
+        "HTTP request latency by route, until the response headers are sent",
+        &["route"]
+    )
+    .expect("Failed registering metric");
+    pub static ref AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "transfer_auth_failures_total",
+        "Requests rejected with invalid credentials, by route",
+        &["route"]
+    )
+    .expect("Failed registering metric");
+    pub static ref UPLOADED_BYTES: IntCounter = register_int_counter!(
+        "transfer_uploaded_bytes_total",
+        "Bytes received by completed uploads and committed chunks"
+    )
+    .expect("Failed registering metric");
+    pub static ref DOWNLOADED_BYTES: IntCounter =
+        register_int_counter!("transfer_downloaded_bytes_total", "Bytes sent to downloads")
+            .expect("Failed registering metric");
+    pub static ref ACTIVE_TRANSFERS: IntGauge = register_int_gauge!(
+        "transfer_active_transfers",
+        "Uploads and downloads currently transferring bytes"
+    )
+    .expect("Failed registering metric");
+    pub static ref STORED_UPLOADS: IntGauge =
+        register_int_gauge!("transfer_stored_uploads", "Number of stored uploads")
+            .expect("Failed registering metric");
+    pub static ref STORED_BYTES: IntGauge = register_int_gauge!(
+        "transfer_stored_bytes",
+        "Combined bytes of stored uploads, `status.total_bytes`"
+    )
+    .expect("Failed registering metric");
+    pub static ref MAX_STORED_BYTES: IntGauge = register_int_gauge!(
+        "transfer_max_stored_bytes",
+        "Configured `max_combined_upload_bytes`"
+    )
+    .expect("Failed registering metric");
+    pub static ref SWEEP_DURATION: Histogram = register_histogram!(
+        "transfer_sweep_duration_seconds",
+        "Duration of database sweeper runs"
+    )
+    .expect("Failed registering metric");
+    pub static ref SWEEP_CLEANED: IntCounterVec = register_int_counter_vec!(
+        "transfer_sweep_cleaned_total",
+        "Expired items cleaned by the database sweeper, by table",
+        &["table"]
+    )
+    .expect("Failed registering metric");
+    pub static ref HASH_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "transfer_hash_rejections_total",
+        "Password hashes refused with 503, by reason: queue_full or timeout",
+        &["reason"]
+    )
+    .expect("Failed registering metric");
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "transfer_webhook_deliveries_total",
+        "Webhook delivery attempts, by outcome: delivered, retried, or failed",
+        &["outcome"]
+    )
+    .expect("Failed registering metric");
+    pub static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "transfer_db_pool_connections",
+        "Open database pool connections"
+    )
+    .expect("Failed registering metric");
+    pub static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "transfer_db_pool_idle_connections",
+        "Idle database pool connections"
+    )
+    .expect("Failed registering metric");
+    pub static ref DB_POOL_MAX_SIZE: IntGauge = register_int_gauge!(
+        "transfer_db_pool_max_size",
+        "Maximum database pool connections"
+    )
+    .expect("Failed registering metric");
+}
+
+/// Label for a request `path`
+pub fn route_label(path: &str) -> &'static str {
+    ROUTES
+        .iter()
+        .find(|route| **route == path)
+        .cloned()
+        .unwrap_or("other")
+}
+
+/// Record a served request
+pub fn observe_request(path: &str, method: &str, status: u16, secs: f64) {
+    let route = route_label(path);
+    HTTP_REQUESTS
+        .with_label_values(&[route, method, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(secs);
+    if status == 401 {
+        AUTH_FAILURES.with_label_values(&[route]).inc();
+    }
+}
+
+/// Sample current state and encode all metrics in the prometheus text format
+pub async fn render(ctx: &Ctx) -> Result<String> {
+    let config = ctx.config.get();
+    MAX_STORED_BYTES.set(config.max_combined_upload_bytes);
+    ACTIVE_TRANSFERS.set(ctx.shutdown.active_count() as i64);
+
+    let state = ctx.db.status();
+    DB_POOL_CONNECTIONS.set(state.size as i64);
+    DB_POOL_IDLE_CONNECTIONS.set(state.available as i64);
+    DB_POOL_MAX_SIZE.set(state.max_size as i64);
+
+    let conn = ctx.db.get().await?;
+    let status = models::Status::load(&conn).await?;
+    STORED_UPLOADS.set(status.upload_count());
+    STORED_BYTES.set(status.total_bytes());
+
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(|e| error::helpers::internal(format!("Error encoding metrics: {}", e)))?;
+    String::from_utf8(buf)
+        .map_err(|e| error::helpers::internal(format!("Error encoding metrics: {}", e)))
+}
@@ -2,88 +2,72 @@
 Database models
 
 */
+use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
-use postgres::{self, GenericConnection};
-use ron;
-use std::env;
-use std::fs;
+use deadpool_postgres::GenericClient;
 use std::path::{Path, PathBuf};
+use tokio_postgres::Row;
 use uuid::Uuid;
 
-use crate::auth;
-use crate::config_dir;
+use crate::config::Config;
 use crate::error::{self, Error, Result};
-
-lazy_static! {
-    pub static ref CONFIG: Config = {
-        let config_dir_ = config_dir().expect("Failed getting xdg config dir");
-        let f = match fs::File::open(config_dir_.join("config.ron")) {
-            Err(_) => {
-                let cwd = env::current_dir().expect("current dir error");
-                fs::File::open(cwd.join("config.ron")).expect("Failed opening config file")
-            }
-            Ok(f) => f,
-        };
-        let mut config: Config = ron::de::from_reader(f).expect("Failed parsing config file");
-        let env_port = std::env::var("PORT").unwrap_or_default();
-        if !env_port.is_empty() {
-            config.port = env_port.parse::<u16>().expect("invalid port");
-        }
-        config
-    };
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub upload_limit_bytes: i64,
-    pub upload_timeout_secs: i64,
-    pub upload_lifespan_secs_default: i64,
-    pub max_combined_upload_bytes: i64,
-    pub download_timeout_secs: i64,
-    pub download_limit_default: Option<i32>,
-    pub expired_cleanup_interval_secs: u64,
-    pub upload_directory: String,
-    pub host: String,
-    pub port: u16,
-}
-impl Config {
-    pub fn upload_dir(&self) -> Result<PathBuf> {
-        let path = PathBuf::from(&self.upload_directory);
-        if path.is_absolute() {
-            Ok(path)
-        } else {
-            Ok(env::current_dir()?.join(&self.upload_directory))
-        }
-    }
-}
+use crate::hashing::Verification;
 
 pub trait FromRow {
     /// Return the associated database table name
     fn table_name() -> &'static str;
-    /// Convert a `postgres::row::Row` into an instance
-    fn from_row(row: postgres::rows::Row) -> Self;
+    /// Convert a `tokio_postgres::Row` into an instance
+    fn from_row(row: &Row) -> Self;
+}
+
+/// Records protected by passwords, that count failed password attempts
+#[async_trait]
+pub trait Lockable: FromRow + Sync {
+    fn id(&self) -> i32;
+
+    /// Public identifier, the key handed to clients
+    fn uuid(&self) -> Uuid;
+
+    /// Number of failed password attempts
+    fn failed_attempts(&self) -> i32;
+
+    /// Increment the failed password attempt count, returning the new count
+    async fn record_failed_attempt<T: GenericClient>(&self, conn: &T) -> Result<i32> {
+        let stmt = format!(
+            "update {} set failed_attempts = failed_attempts + 1 where id = $1 returning failed_attempts",
+            Self::table_name()
+        );
+        try_query_aggregate!(conn.query(stmt.as_str(), &[&self.id()]).await, i32)
+    }
+
+    /// Expire the record immediately, leaving its cleanup to the sweeper
+    async fn expire_now<T: GenericClient>(&self, conn: &T) -> Result<()> {
+        let stmt = format!(
+            "update {} set expire_date = $2 where id = $1",
+            Self::table_name()
+        );
+        conn.execute(stmt.as_str(), &[&self.id(), &Utc::now()])
+            .await?;
+        Ok(())
+    }
 }
 
 /// For inserting a new `Auth` record
 pub struct NewAuth {
     pub salt: Vec<u8>,
     pub hash: Vec<u8>,
+    pub algorithm: String,
+    pub params: String,
 }
 impl NewAuth {
-    pub fn from_pass_bytes(pass: &[u8]) -> error::Result<Self> {
-        let salt = auth::new_salt()?;
-        let sha = auth::sha256(pass);
-        let hash = auth::bcrypt_hash(&sha, &salt)?;
-        Ok(Self { salt, hash })
-    }
-
-    pub fn insert<T: GenericConnection>(self, conn: &T) -> error::Result<Auth> {
-        let stmt = "insert into auth (salt, hash) values ($1, $2) \
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> error::Result<Auth> {
+        let stmt = "insert into auth (salt, hash, algorithm, params) values ($1, $2, $3, $4) \
                     returning id, date_created";
-        try_query_to_model!(conn.query(stmt, &[&self.salt, &self.hash]);
+        try_query_to_model!(conn.query(stmt, &[&self.salt, &self.hash, &self.algorithm, &self.params]).await;
                             Auth;
                             id: 0, date_created: 1;
-                            salt: self.salt, hash: self.hash)
+                            salt: self.salt, hash: self.hash, algorithm: self.algorithm,
+                            params: self.params)
     }
 }
 
@@ -92,36 +76,65 @@ pub struct Auth {
     pub id: i32,
     pub salt: Vec<u8>,
     pub hash: Vec<u8>,
+    pub algorithm: String,
+    pub params: String,
     pub date_created: DateTime<Utc>,
 }
 impl FromRow for Auth {
     fn table_name() -> &'static str {
         "auth"
     }
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             salt: row.get("salt"),
             hash: row.get("hash"),
+            algorithm: row.get("algorithm"),
+            params: row.get("params"),
             date_created: row.get("date_created"),
         }
     }
 }
 impl Auth {
     /// Return the `auth` record for the given `id` or `ErrorKind::DoesNotExist`
-    pub fn find<T: GenericConnection>(conn: &T, id: &i32) -> Result<Self> {
-        let stmt = "select id, salt, hash, date_created from auth \
+    pub async fn find<T: GenericClient>(conn: &T, id: &i32) -> Result<Self> {
+        let stmt = "select id, salt, hash, algorithm, params, date_created from auth \
                     where id = $1";
-        try_query_one!(conn.query(stmt, &[id]), Auth)
+        try_query_one!(conn.query(stmt, &[id]).await, Auth)
     }
 
-    /// Try verifying the current `auth` record against a set of bytes, returning
-    /// `Ok` if verification passes or `ErrorKind::InvalidAuth`
-    pub fn verify(&self, other_pass_bytes: &[u8]) -> error::Result<()> {
-        let other_sha = auth::sha256(other_pass_bytes);
-        let other_hash = auth::bcrypt_hash(&other_sha, &self.salt)?;
-        auth::eq(&self.hash, &other_hash)
-            .map_err(|_| error::helpers::invalid_auth("Invalid authentication"))?;
+    /// Apply a `Verification` of a password against the current `auth` record, returning
+    /// `Ok` if the password matched or `ErrorKind::InvalidAuth`
+    ///
+    /// Once verified, the stored hash is replaced by the verification's re-hash, made when the
+    /// record was hashed with a different algorithm or parameters than the configured ones.
+    pub async fn verify<T: GenericClient>(
+        &self,
+        conn: &T,
+        verification: &Verification,
+    ) -> error::Result<()> {
+        if !verification.matched {
+            return Err(error::helpers::invalid_auth("Invalid authentication"));
+        }
+        if let Some(ref new) = verification.rehash {
+            self.rehash(conn, new).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the stored hash with `new`
+    async fn rehash<T: GenericClient>(&self, conn: &T, new: &NewAuth) -> Result<()> {
+        let stmt =
+            "update auth set salt = $1, hash = $2, algorithm = $3, params = $4 where id = $5";
+        conn.execute(
+            stmt,
+            &[&new.salt, &new.hash, &new.algorithm, &new.params, &self.id],
+        )
+        .await?;
+        debug!(
+            "Re-hashed auth id={} from {} {} to {} {}",
+            self.id, self.algorithm, self.params, new.algorithm, new.params
+        );
         Ok(())
     }
 }
@@ -133,30 +146,39 @@ pub struct NewInitUpload {
     pub content_hash: Vec<u8>,
     pub size: i64,
     pub nonce: Vec<u8>,
-    pub access_password: i32,
+    pub access_password: Option<i32>,
     pub deletion_password: Option<i32>,
     pub download_limit: Option<i32>,
     pub expire_date: DateTime<Utc>,
+    pub bundle: Option<i32>,
+    pub bundle_index: Option<i32>,
+    pub api_token: Option<i32>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<Vec<u8>>,
 }
 impl NewInitUpload {
-    pub fn insert<T: GenericConnection>(self, conn: &T) -> error::Result<InitUpload> {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> error::Result<InitUpload> {
         let stmt = "insert into init_upload \
-                    (uuid_, file_name_hash, content_hash, size_, nonce, access_password, deletion_password, download_limit, expire_date) \
-                    values ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
-                    returning id, date_created";
+                    (uuid_, file_name_hash, content_hash, size_, nonce, access_password, deletion_password, download_limit, expire_date, bundle, bundle_index, api_token, webhook_url, webhook_secret) \
+                    values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) \
+                    returning id, uploaded_bytes, date_modified, date_created";
         try_query_to_model!(conn.query(stmt, &[&self.uuid, &self.file_name_hash, &self.content_hash, &self.size,
                                         &self.nonce, &self.access_password, &self.deletion_password,
-                                        &self.download_limit, &self.expire_date]);
+                                        &self.download_limit, &self.expire_date, &self.bundle, &self.bundle_index,
+                                        &self.api_token, &self.webhook_url, &self.webhook_secret]).await;
                             InitUpload;
-                            id: 0, date_created: 1;
+                            id: 0, uploaded_bytes: 1, date_modified: 2, date_created: 3;
                             uuid: self.uuid, file_name_hash: self.file_name_hash, content_hash: self.content_hash,
                             size: self.size, nonce: self.nonce, access_password: self.access_password,
                             deletion_password: self.deletion_password, download_limit: self.download_limit,
-                            expire_date: self.expire_date)
+                            expire_date: self.expire_date, bundle: self.bundle, bundle_index: self.bundle_index,
+                            api_token: self.api_token, webhook_url: self.webhook_url,
+                            webhook_secret: self.webhook_secret)
     }
 }
 
 /// Maps to db table `init_upload`
+#[derive(Clone)]
 pub struct InitUpload {
     pub id: i32,
     pub uuid: Uuid,
@@ -164,17 +186,24 @@ pub struct InitUpload {
     pub content_hash: Vec<u8>,
     pub size: i64,
     pub nonce: Vec<u8>,
-    pub access_password: i32,
+    pub access_password: Option<i32>,
     pub deletion_password: Option<i32>,
     pub download_limit: Option<i32>,
     pub expire_date: DateTime<Utc>,
+    pub bundle: Option<i32>,
+    pub bundle_index: Option<i32>,
+    pub api_token: Option<i32>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<Vec<u8>>,
+    pub uploaded_bytes: i64,
+    pub date_modified: DateTime<Utc>,
     pub date_created: DateTime<Utc>,
 }
 impl FromRow for InitUpload {
     fn table_name() -> &'static str {
         "init_upload"
     }
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             uuid: row.get("uuid_"),
@@ -186,30 +215,62 @@ impl FromRow for InitUpload {
             deletion_password: row.get("deletion_password"),
             download_limit: row.get("download_limit"),
             expire_date: row.get("expire_date"),
+            bundle: row.get("bundle"),
+            bundle_index: row.get("bundle_index"),
+            api_token: row.get("api_token"),
+            webhook_url: row.get("webhook_url"),
+            webhook_secret: row.get("webhook_secret"),
+            uploaded_bytes: row.get("uploaded_bytes"),
+            date_modified: row.get("date_modified"),
             date_created: row.get("date_created"),
         }
     }
 }
 impl InitUpload {
     /// Return the `init_upload` record for the given `uuid` or `ErrorKind::DoesNotExist`
-    pub fn find<T: GenericConnection>(conn: &T, uuid: &Uuid) -> Result<Self> {
+    pub async fn find<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<Self> {
         let stmt = "select * \
                     from init_upload \
                     where uuid_ = $1";
-        try_query_one!(conn.query(stmt, &[uuid]), InitUpload)
+        try_query_one!(conn.query(stmt, &[uuid]).await, InitUpload)
+    }
+
+    /// Check if an `init_upload` record with the given `uuid` exists
+    pub async fn uuid_exists<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<bool> {
+        let stmt = "select exists(select 1 from init_upload where uuid_ = $1)";
+        try_query_aggregate!(conn.query(stmt, &[&uuid]).await, bool)
+    }
+
+    /// Advance the committed byte count of the current record by `n_bytes`, returning
+    /// the updated record.
+    ///
+    /// The update only applies if the committed byte count hasn't changed since the
+    /// current instance was loaded, otherwise `ErrorKind::DoesNotExist` is returned.
+    pub async fn commit_bytes<T: GenericClient>(&self, conn: &T, n_bytes: i64) -> Result<Self> {
+        let stmt = "update init_upload set \
+                    uploaded_bytes = uploaded_bytes + $1, \
+                    date_modified = $2 \
+                    where id = $3 and uploaded_bytes = $4 \
+                    returning *";
+        let now = Utc::now();
+        try_query_one!(
+            conn.query(stmt, &[&n_bytes, &now, &self.id, &self.uploaded_bytes])
+                .await,
+            InitUpload
+        )
     }
 
     /// Try deleting the current record from the database, returning the number of items deleted
-    pub fn delete<T: GenericConnection>(&self, conn: &T) -> Result<i64> {
+    pub async fn delete<T: GenericClient>(&self, conn: &T) -> Result<i64> {
         let stmt = "with deleted as (delete from init_upload where id = $1 returning 1) \
                     select count(*) from deleted";
-        try_query_aggregate!(conn.query(stmt, &[&self.id]), i64)
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
     }
 
     /// Convert the current `InitUpload` into a `NewUpload`
     ///
-    /// Converts current instance with a given `file_path` where the associated upload data
-    /// will be saved. Note, the current `InitUpload` should be deleted before being converted.
+    /// Converts current instance with a given `file_path`, the storage key where the associated
+    /// upload data will be saved. Note, the current `InitUpload` should be deleted before being converted.
     pub fn into_upload<T: AsRef<Path>>(self, file_path: T) -> Result<NewUpload> {
         let pb = Path::to_str(file_path.as_ref())
             .map(str::to_string)
@@ -223,31 +284,43 @@ impl InitUpload {
             size: self.size,
             file_name_hash: self.file_name_hash,
             file_path: pb,
+            file_hash: None,
             nonce: self.nonce,
             access_password: self.access_password,
             deletion_password: self.deletion_password,
             download_limit: self.download_limit,
             expire_date: self.expire_date,
+            bundle: self.bundle,
+            bundle_index: self.bundle_index,
+            api_token: self.api_token,
+            webhook_url: self.webhook_url,
+            webhook_secret: self.webhook_secret,
+            encryption_key_id: None,
+            encrypted_data_key: None,
+            finalized: false,
         })
     }
 
-    pub fn still_valid(&self, dt: &DateTime<Utc>) -> bool {
-        dt.signed_duration_since(self.date_created) <= Duration::seconds(CONFIG.upload_timeout_secs)
+    /// Check if upload initializer is still valid, i.e. bytes were last received
+    /// within the current `config.upload_timeout_secs`
+    pub fn still_valid(&self, config: &Config, dt: &DateTime<Utc>) -> bool {
+        dt.signed_duration_since(self.date_modified)
+            <= Duration::seconds(config.upload_timeout_secs)
     }
 
-    /// Try deleting all `init_upload` records that are older than the current `CONFIG.upload_timeout_secs`
-    pub fn clear_outdated<T: GenericConnection>(conn: &T) -> Result<i64> {
-        let stmt = "with deleted as (delete from init_upload where date_created < $1 returning 1) \
-                    select count(*) from deleted";
-        let timeout = Duration::seconds(CONFIG.upload_timeout_secs);
+    /// Return a collection of `InitUpload` instances that haven't received any bytes
+    /// within the current `config.upload_timeout_secs`
+    pub async fn select_outdated<T: GenericClient>(conn: &T, config: &Config) -> Result<Vec<Self>> {
+        let stmt = "select * from init_upload where date_modified < $1";
+        let timeout = Duration::seconds(config.upload_timeout_secs);
         let now = Utc::now();
         let cutoff = now.checked_sub_signed(timeout).ok_or_else(|| {
             error::helpers::internal(format!(
                 "Error subtracting {} secs from {:?}",
-                CONFIG.upload_timeout_secs, now
+                config.upload_timeout_secs, now
             ))
         })?;
-        try_query_aggregate!(conn.query(stmt, &[&cutoff]), i64)
+        try_query_vec!(conn.query(stmt, &[&cutoff]).await, InitUpload)
     }
 }
 
@@ -258,30 +331,49 @@ pub struct NewUpload {
     pub size: i64,
     pub file_name_hash: Vec<u8>,
     pub file_path: String,
+    pub file_hash: Option<Vec<u8>>,
     pub nonce: Vec<u8>,
-    pub access_password: i32,
+    pub access_password: Option<i32>,
     pub deletion_password: Option<i32>,
     pub download_limit: Option<i32>,
     pub expire_date: DateTime<Utc>,
+    pub bundle: Option<i32>,
+    pub bundle_index: Option<i32>,
+    pub api_token: Option<i32>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<Vec<u8>>,
+    pub encryption_key_id: Option<i32>,
+    pub encrypted_data_key: Option<Vec<u8>>,
+    pub finalized: bool,
 }
 impl NewUpload {
-    pub fn insert<T: GenericConnection>(self, conn: &T) -> Result<Upload> {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<Upload> {
         let stmt = "insert into upload \
-                    (uuid_, content_hash, size_, file_name_hash, file_path, nonce, access_password, deletion_password, download_limit, expire_date) \
-                    values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
-                    returning id, deleted, date_created";
+                    (uuid_, content_hash, size_, file_name_hash, file_path, file_hash, nonce, access_password, deletion_password, download_limit, expire_date, bundle, bundle_index, api_token, webhook_url, webhook_secret, encryption_key_id, encrypted_data_key, finalized) \
+                    values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) \
+                    returning id, deleted, date_created, failed_attempts";
         try_query_to_model!(conn.query(stmt, &[&self.uuid, &self.content_hash, &self.size, &self.file_name_hash,
-                                                &self.file_path, &self.nonce, &self.access_password, &self.deletion_password,
-                                                &self.download_limit, &self.expire_date]);
+                                                &self.file_path, &self.file_hash, &self.nonce, &self.access_password,
+                                                &self.deletion_password, &self.download_limit, &self.expire_date,
+                                                &self.bundle, &self.bundle_index, &self.api_token,
+                                                &self.webhook_url, &self.webhook_secret,
+                                                &self.encryption_key_id, &self.encrypted_data_key, &self.finalized]).await;
                             Upload;
-                            id: 0, deleted: 1, date_created: 2;
+                            id: 0, deleted: 1, date_created: 2, failed_attempts: 3;
                             uuid: self.uuid, content_hash: self.content_hash, size: self.size, file_name_hash: self.file_name_hash,
-                            file_path: self.file_path, nonce: self.nonce, access_password: self.access_password,
-                            deletion_password: self.deletion_password, download_limit: self.download_limit, expire_date: self.expire_date)
+                            file_path: self.file_path, file_hash: self.file_hash, nonce: self.nonce, access_password: self.access_password,
+                            deletion_password: self.deletion_password, download_limit: self.download_limit, expire_date: self.expire_date,
+                            bundle: self.bundle, bundle_index: self.bundle_index, api_token: self.api_token,
+                            webhook_url: self.webhook_url, webhook_secret: self.webhook_secret,
+                            encryption_key_id: self.encryption_key_id, encrypted_data_key: self.encrypted_data_key,
+                            finalized: self.finalized)
     }
 }
 
 /// Maps to db table `upload`
+///
+/// Whole-file uploads are recorded before their bytes arrive and stay unavailable
+/// until they're `finalized`. Only finalized uploads count towards the status totals.
 #[derive(Clone)]
 pub struct Upload {
     pub id: i32,
@@ -290,11 +382,21 @@ pub struct Upload {
     pub size: i64,
     pub file_name_hash: Vec<u8>,
     pub file_path: String,
+    pub file_hash: Option<Vec<u8>>,
     pub nonce: Vec<u8>,
-    pub access_password: i32,
+    pub access_password: Option<i32>,
     pub deletion_password: Option<i32>,
     pub download_limit: Option<i32>,
     pub expire_date: DateTime<Utc>,
+    pub bundle: Option<i32>,
+    pub bundle_index: Option<i32>,
+    pub api_token: Option<i32>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<Vec<u8>>,
+    pub encryption_key_id: Option<i32>,
+    pub encrypted_data_key: Option<Vec<u8>>,
+    pub finalized: bool,
+    pub failed_attempts: i32,
     pub deleted: bool,
     pub date_created: DateTime<Utc>,
 }
@@ -302,7 +404,7 @@ impl FromRow for Upload {
     fn table_name() -> &'static str {
         "upload"
     }
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             uuid: row.get("uuid_"),
@@ -310,51 +412,93 @@ impl FromRow for Upload {
             size: row.get("size_"),
             file_name_hash: row.get("file_name_hash"),
             file_path: row.get("file_path"),
+            file_hash: row.get("file_hash"),
             nonce: row.get("nonce"),
             access_password: row.get("access_password"),
             deletion_password: row.get("deletion_password"),
             download_limit: row.get("download_limit"),
             expire_date: row.get("expire_date"),
+            bundle: row.get("bundle"),
+            bundle_index: row.get("bundle_index"),
+            api_token: row.get("api_token"),
+            webhook_url: row.get("webhook_url"),
+            webhook_secret: row.get("webhook_secret"),
+            encryption_key_id: row.get("encryption_key_id"),
+            encrypted_data_key: row.get("encrypted_data_key"),
+            finalized: row.get("finalized"),
+            failed_attempts: row.get("failed_attempts"),
             deleted: row.get("deleted"),
             date_created: row.get("date_created"),
         }
     }
 }
 impl Upload {
-    /// Convert an `Upload`s `uuid` into a valid upload file-path
-    pub fn new_file_path(uuid: &Uuid) -> Result<PathBuf> {
-        let upload_dir = CONFIG.upload_dir()?;
-        Ok(upload_dir.join(hex::encode(uuid.as_bytes())))
+    /// Convert an `Upload`s `uuid` into a valid upload file-path, where
+    /// uploaded bytes are staged before being handed off to storage
+    pub fn new_file_path(config: &Config, uuid: &Uuid) -> Result<PathBuf> {
+        let upload_dir = config.upload_dir()?;
+        Ok(upload_dir.join(Self::storage_key(uuid)))
     }
 
-    /// Return the `upload` record for the given `uuid` or `ErrorKind::DoesNotExist`
-    pub fn find<T: GenericConnection>(conn: &T, uuid: &Uuid) -> Result<Self> {
+    /// Convert an `Upload`s `uuid` into the key its bytes are stored under
+    pub fn storage_key(uuid: &Uuid) -> String {
+        hex::encode(uuid.as_bytes())
+    }
+
+    /// Return the finalized `upload` record for the given `uuid` or `ErrorKind::DoesNotExist`
+    pub async fn find<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<Self> {
         let stmt = "select * \
                     from upload \
-                    where uuid_ = $1 and deleted = false";
-        try_query_one!(conn.query(stmt, &[uuid]), Upload)
+                    where uuid_ = $1 and deleted = false and finalized = true";
+        try_query_one!(conn.query(stmt, &[uuid]).await, Upload)
+    }
+
+    /// Return the `upload` record for the given `uuid` that's still receiving its bytes
+    /// or `ErrorKind::DoesNotExist`
+    pub async fn find_unfinalized<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<Self> {
+        let stmt = "select * \
+                    from upload \
+                    where uuid_ = $1 and deleted = false and finalized = false";
+        try_query_one!(conn.query(stmt, &[uuid]).await, Upload)
     }
 
-    pub fn get_access_auth<T: GenericConnection>(&self, conn: &T) -> Result<Auth> {
-        Auth::find(conn, &self.access_password)
+    /// Return the upload's access `auth` record. Bundle members are accessed
+    /// using their bundle's access password.
+    pub async fn get_access_auth<T: GenericClient>(&self, conn: &T) -> Result<Auth> {
+        match (self.access_password, self.bundle) {
+            (Some(ref id), _) => Auth::find(conn, id).await,
+            (None, Some(ref id)) => {
+                Bundle::find_by_id(conn, id)
+                    .await?
+                    .get_access_auth(conn)
+                    .await
+            }
+            (None, None) => Err(error::helpers::internal(format!(
+                "upload with id={} has no access password",
+                self.id
+            ))),
+        }
     }
 
-    pub fn get_deletion_auth<T: GenericConnection>(&self, conn: &T) -> Result<Option<Auth>> {
+    pub async fn get_deletion_auth<T: GenericClient>(&self, conn: &T) -> Result<Option<Auth>> {
         Ok(match self.deletion_password {
-            Some(ref id) => Some(Auth::find(conn, id)?),
+            Some(ref id) => Some(Auth::find(conn, id).await?),
             None => None,
         })
     }
 
-    /// Check if an `upload` record with the given `uuid` exists and is available for download
-    pub fn uuid_exists_available<T: GenericConnection>(conn: &T, uuid: &Uuid) -> Result<bool> {
+    /// Check if an `upload` record with the given `uuid` exists and hasn't been deleted
+    pub async fn uuid_exists_available<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<bool> {
         let stmt = "select exists(select 1 from upload where uuid_ = $1 and deleted = false)";
-        try_query_aggregate!(conn.query(stmt, &[&uuid]), bool)
+        try_query_aggregate!(conn.query(stmt, &[&uuid]).await, bool)
     }
 
     /// Return a collection of `Upload` instances that are older than `UPLOAD_MAX_LIFE_SECS`
-    /// or are over their download limit
-    pub fn select_outdated<T: GenericConnection>(conn: &T) -> Result<Vec<Self>> {
+    /// or are over their download limit.
+    ///
+    /// Uploads over their download limit are retained while a counted download
+    /// is still active so that it can be resumed.
+    pub async fn select_outdated<T: GenericClient>(conn: &T, config: &Config) -> Result<Vec<Self>> {
         let stmt = "select * \
                     from upload \
                     where (expire_date <= $1 and deleted = false) \
@@ -364,21 +508,310 @@ impl Upload {
                     from download join upload on (upload.id = download.upload) \
                     where deleted = false \
                     group by upload) \
-                    select upload from dl_counts where count >= download_limit)";
+                    select upload from dl_counts where count >= download_limit \
+                    and upload not in \
+                    (select upload from init_download \
+                    where usage = 'content' and download is not null and date_accessed >= $2))";
+        let now = Utc::now();
+        let timeout = Duration::seconds(config.download_timeout_secs);
+        let cutoff = now.checked_sub_signed(timeout).ok_or_else(|| {
+            error::helpers::internal(format!(
+                "Error subtracting {} secs from {:?}",
+                config.download_timeout_secs, now
+            ))
+        })?;
+        try_query_vec!(conn.query(stmt, &[&now, &cutoff]).await, Upload)
+    }
+
+    /// Return the available, unexpired `Upload`s made with the given api token that
+    /// aren't bundle members, newest first
+    pub async fn select_by_api_token<T: GenericClient>(
+        conn: &T,
+        api_token: &i32,
+    ) -> Result<Vec<Self>> {
+        let stmt = "select * \
+                    from upload \
+                    where api_token = $1 and bundle is null and deleted = false and finalized = true \
+                    and expire_date > $2 \
+                    order by date_created desc";
         let now = Utc::now();
-        try_query_vec!(conn.query(stmt, &[&now]), Upload)
+        try_query_vec!(conn.query(stmt, &[api_token, &now]).await, Upload)
+    }
+
+    /// Return a collection of available `Upload` instances that have a recorded `file_hash`
+    pub async fn select_hashed<T: GenericClient>(conn: &T) -> Result<Vec<Self>> {
+        let stmt = "select * \
+                    from upload \
+                    where deleted = false and finalized = true and file_hash is not null";
+        try_query_vec!(conn.query(stmt, &[]).await, Upload)
+    }
+
+    /// Record the hash of the current instance's stored file
+    pub async fn set_file_hash<T: GenericClient>(&self, conn: &T, file_hash: &[u8]) -> Result<i64> {
+        let stmt = "with updated as (update upload set file_hash = $1 where id = $2 returning 1) \
+                    select count(*) from updated";
+        try_query_aggregate!(conn.query(stmt, &[&file_hash, &self.id]).await, i64)
+    }
+
+    /// Select all non-deleted, finalized uploads that are encrypted at rest
+    pub async fn select_encrypted<T: GenericClient>(conn: &T) -> Result<Vec<Self>> {
+        let stmt = "select * \
+                    from upload \
+                    where deleted = false and finalized = true and encryption_key_id is not null";
+        try_query_vec!(conn.query(stmt, &[]).await, Upload)
+    }
+
+    /// Record the key-encryption key id and wrapped data key of the current instance's stored file
+    pub async fn set_encryption<T: GenericClient>(
+        &self,
+        conn: &T,
+        key_id: i32,
+        encrypted_data_key: &[u8],
+    ) -> Result<i64> {
+        let stmt = "with updated as (update upload set encryption_key_id = $1, encrypted_data_key = $2 where id = $3 returning 1) \
+                    select count(*) from updated";
+        try_query_aggregate!(
+            conn.query(stmt, &[&key_id, &encrypted_data_key, &self.id])
+                .await,
+            i64
+        )
+    }
+
+    /// Update the expiration date and download limit of the current instance,
+    /// returning the updated record
+    pub async fn update_limits<T: GenericClient>(
+        &self,
+        conn: &T,
+        expire_date: &DateTime<Utc>,
+        download_limit: Option<i32>,
+    ) -> Result<Self> {
+        let stmt = "update upload set expire_date = $1, download_limit = $2 \
+                    where id = $3 \
+                    returning *";
+        try_query_one!(
+            conn.query(stmt, &[expire_date, &download_limit, &self.id])
+                .await,
+            Upload
+        )
+    }
+
+    /// Mark the current instance's bytes stored, making it available for download,
+    /// returning the number of items marked. Deleted uploads aren't marked.
+    pub async fn finalize<T: GenericClient>(&self, conn: &T) -> Result<i64> {
+        let stmt = "with finalized as (update upload set finalized = true where id = $1 and deleted = false returning 1) \
+                    select count(*) from finalized";
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
     }
 
     /// Try marking the current instance deleted, returning the number of items marked
-    pub fn delete<T: GenericConnection>(&self, conn: &T) -> Result<i64> {
+    pub async fn delete<T: GenericClient>(&self, conn: &T) -> Result<i64> {
         let stmt = "with deleted as (update upload set deleted = true where id = $1 returning 1) \
                     select count(*) from deleted";
-        try_query_aggregate!(conn.query(stmt, &[&self.id]), i64)
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
     }
 
-    pub fn download_count<T: GenericConnection>(&self, conn: &T) -> Result<i64> {
+    pub async fn download_count<T: GenericClient>(&self, conn: &T) -> Result<i64> {
         let stmt = "select count(*) from download where upload = $1";
-        try_query_aggregate!(conn.query(stmt, &[&self.id]), i64)
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
+    }
+}
+
+impl Lockable for Upload {
+    fn id(&self) -> i32 {
+        self.id
+    }
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+    fn failed_attempts(&self) -> i32 {
+        self.failed_attempts
+    }
+}
+
+/// For initializing a new `Bundle` record
+pub struct NewBundle {
+    pub uuid: Uuid,
+    pub access_password: i32,
+    pub deletion_password: Option<i32>,
+    pub download_limit: Option<i32>,
+    pub expire_date: DateTime<Utc>,
+}
+impl NewBundle {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<Bundle> {
+        let stmt = "insert into bundle \
+                    (uuid_, access_password, deletion_password, download_limit, expire_date) \
+                    values ($1, $2, $3, $4, $5) \
+                    returning id, deleted, date_created, failed_attempts";
+        try_query_to_model!(conn.query(stmt, &[&self.uuid, &self.access_password, &self.deletion_password,
+                                                &self.download_limit, &self.expire_date]).await;
+                            Bundle;
+                            id: 0, deleted: 1, date_created: 2, failed_attempts: 3;
+                            uuid: self.uuid, access_password: self.access_password, deletion_password: self.deletion_password,
+                            download_limit: self.download_limit, expire_date: self.expire_date)
+    }
+}
+
+/// Maps to db table `bundle`
+///
+/// A bundle groups many member `upload`s under a single key. Access/deletion passwords,
+/// download limits and expiration apply to the bundle as a whole.
+#[derive(Clone)]
+pub struct Bundle {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub access_password: i32,
+    pub deletion_password: Option<i32>,
+    pub download_limit: Option<i32>,
+    pub expire_date: DateTime<Utc>,
+    pub failed_attempts: i32,
+    pub deleted: bool,
+    pub date_created: DateTime<Utc>,
+}
+impl FromRow for Bundle {
+    fn table_name() -> &'static str {
+        "bundle"
+    }
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            uuid: row.get("uuid_"),
+            access_password: row.get("access_password"),
+            deletion_password: row.get("deletion_password"),
+            download_limit: row.get("download_limit"),
+            expire_date: row.get("expire_date"),
+            failed_attempts: row.get("failed_attempts"),
+            deleted: row.get("deleted"),
+            date_created: row.get("date_created"),
+        }
+    }
+}
+impl Bundle {
+    /// Return the `bundle` record for the given `uuid` or `ErrorKind::DoesNotExist`
+    pub async fn find<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<Self> {
+        let stmt = "select * \
+                    from bundle \
+                    where uuid_ = $1 and deleted = false";
+        try_query_one!(conn.query(stmt, &[uuid]).await, Bundle)
+    }
+
+    /// Return the `bundle` record for the given `id` or `ErrorKind::DoesNotExist`
+    pub async fn find_by_id<T: GenericClient>(conn: &T, id: &i32) -> Result<Self> {
+        let stmt = "select * from bundle where id = $1";
+        try_query_one!(conn.query(stmt, &[id]).await, Bundle)
+    }
+
+    pub async fn get_access_auth<T: GenericClient>(&self, conn: &T) -> Result<Auth> {
+        Auth::find(conn, &self.access_password).await
+    }
+
+    pub async fn get_deletion_auth<T: GenericClient>(&self, conn: &T) -> Result<Option<Auth>> {
+        Ok(match self.deletion_password {
+            Some(ref id) => Some(Auth::find(conn, id).await?),
+            None => None,
+        })
+    }
+
+    /// Return the non-deleted member `upload`s of the current bundle, including any that
+    /// aren't finalized yet, ordered by their bundle index
+    pub async fn members<T: GenericClient>(&self, conn: &T) -> Result<Vec<Upload>> {
+        let stmt = "select * \
+                    from upload \
+                    where bundle = $1 and deleted = false \
+                    order by bundle_index";
+        try_query_vec!(conn.query(stmt, &[&self.id]).await, Upload)
+    }
+
+    /// Return a collection of `Bundle` instances that are expired or are over their download limit
+    ///
+    /// Bundle downloads are counted when they're initialized, so bundles over their
+    /// download limit are retained while any member still has an active content
+    /// download key, letting the counted download complete.
+    pub async fn select_outdated<T: GenericClient>(conn: &T, config: &Config) -> Result<Vec<Self>> {
+        let stmt = "select * \
+                    from bundle \
+                    where (expire_date <= $1 and deleted = false) \
+                    or id in \
+                    (with dl_counts as \
+                    (select bundle, min(download_limit) as download_limit, count(*) \
+                    from bundle_download join bundle on (bundle.id = bundle_download.bundle) \
+                    where deleted = false \
+                    group by bundle) \
+                    select bundle from dl_counts where count >= download_limit \
+                    and bundle not in \
+                    (select upload.bundle from init_download \
+                    join upload on (upload.id = init_download.upload) \
+                    where usage = 'content' and upload.bundle is not null \
+                    and date_accessed >= $2))";
+        let now = Utc::now();
+        let timeout = Duration::seconds(config.download_timeout_secs);
+        let cutoff = now.checked_sub_signed(timeout).ok_or_else(|| {
+            error::helpers::internal(format!(
+                "Error subtracting {} secs from {:?}",
+                config.download_timeout_secs, now
+            ))
+        })?;
+        try_query_vec!(conn.query(stmt, &[&now, &cutoff]).await, Bundle)
+    }
+
+    /// Try marking the current instance deleted and removing any of its members that
+    /// haven't finished uploading, returning the number of bundles marked
+    pub async fn delete<T: GenericClient>(&self, conn: &T) -> Result<i64> {
+        conn.execute("delete from init_upload where bundle = $1", &[&self.id])
+            .await?;
+        let stmt = "with deleted as (update bundle set deleted = true where id = $1 returning 1) \
+                    select count(*) from deleted";
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
+    }
+
+    pub async fn download_count<T: GenericClient>(&self, conn: &T) -> Result<i64> {
+        let stmt = "select count(*) from bundle_download where bundle = $1";
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
+    }
+}
+
+impl Lockable for Bundle {
+    fn id(&self) -> i32 {
+        self.id
+    }
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+    fn failed_attempts(&self) -> i32 {
+        self.failed_attempts
+    }
+}
+
+/// For initializing a new `BundleDownload` record
+pub struct NewBundleDownload {
+    pub bundle: i32,
+}
+impl NewBundleDownload {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<BundleDownload> {
+        let stmt = "insert into bundle_download (bundle) values ($1) returning id, date_created";
+        try_query_to_model!(conn.query(stmt, &[&self.bundle]).await;
+                            BundleDownload;
+                            id: 0, date_created: 1;
+                            bundle: self.bundle)
+    }
+}
+
+/// Maps to db table `bundle_download`
+pub struct BundleDownload {
+    pub id: i32,
+    pub bundle: i32,
+    pub date_created: DateTime<Utc>,
+}
+impl FromRow for BundleDownload {
+    fn table_name() -> &'static str {
+        "bundle_download"
+    }
+
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            bundle: row.get("bundle"),
+            date_created: row.get("date_created"),
+        }
     }
 }
 
@@ -405,14 +838,14 @@ pub struct NewInitDownload {
     pub upload: i32,
 }
 impl NewInitDownload {
-    pub fn insert<T: GenericConnection>(self, conn: &T) -> Result<InitDownload> {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<InitDownload> {
         let stmt = "insert into init_download \
                     (uuid_, usage, upload) \
                     values ($1, $2, $3) \
-                    returning id, date_created";
-        try_query_to_model!(conn.query(stmt, &[&self.uuid, &self.usage, &self.upload]);
+                    returning id, download, date_accessed, date_created";
+        try_query_to_model!(conn.query(stmt, &[&self.uuid, &self.usage, &self.upload]).await;
                             InitDownload;
-                            id: 0, date_created: 1;
+                            id: 0, download: 1, date_accessed: 2, date_created: 3;
                             uuid: self.uuid, usage: self.usage, upload: self.upload)
     }
 }
@@ -423,6 +856,8 @@ pub struct InitDownload {
     pub uuid: Uuid,
     pub usage: String,
     pub upload: i32,
+    pub download: Option<i32>,
+    pub date_accessed: DateTime<Utc>,
     pub date_created: DateTime<Utc>,
 }
 impl FromRow for InitDownload {
@@ -430,59 +865,97 @@ impl FromRow for InitDownload {
         "init_download"
     }
 
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             uuid: row.get("uuid_"),
             usage: row.get("usage"),
             upload: row.get("upload"),
+            download: row.get("download"),
+            date_accessed: row.get("date_accessed"),
             date_created: row.get("date_created"),
         }
     }
 }
 impl InitDownload {
     /// Return the `init_download` record for the given `uuid` or `ErrorKind::DoesNotExist`
-    pub fn find<T: GenericConnection>(conn: &T, uuid: &Uuid, usage: DownloadType) -> Result<Self> {
+    pub async fn find<T: GenericClient>(
+        conn: &T,
+        uuid: &Uuid,
+        usage: DownloadType,
+    ) -> Result<Self> {
         let stmt = "select * \
                     from init_download \
                     where uuid_ = $1 and usage = $2";
         let usage = usage.as_str();
-        try_query_one!(conn.query(stmt, &[uuid, &usage]), InitDownload)
+        try_query_one!(conn.query(stmt, &[uuid, &usage]).await, InitDownload)
+    }
+
+    /// Return the `init_download` record for the given `uuid`, locking it for the
+    /// remainder of the current transaction, or `ErrorKind::DoesNotExist`
+    pub async fn find_for_update<T: GenericClient>(
+        conn: &T,
+        uuid: &Uuid,
+        usage: DownloadType,
+    ) -> Result<Self> {
+        let stmt = "select * \
+                    from init_download \
+                    where uuid_ = $1 and usage = $2 \
+                    for update";
+        let usage = usage.as_str();
+        try_query_one!(conn.query(stmt, &[uuid, &usage]).await, InitDownload)
+    }
+
+    /// Refresh the last-accessed date of the current record, associating it with
+    /// the given `download` record if it isn't already associated with one.
+    pub async fn touch<T: GenericClient>(&self, conn: &T, download: Option<i32>) -> Result<Self> {
+        let stmt = "update init_download set \
+                    download = coalesce(download, $1), \
+                    date_accessed = $2 \
+                    where id = $3 \
+                    returning *";
+        let now = Utc::now();
+        try_query_one!(
+            conn.query(stmt, &[&download, &now, &self.id]).await,
+            InitDownload
+        )
     }
 
     /// Try deleting the current record from the database, returning the number of items deleted
-    pub fn delete<T: GenericConnection>(&self, conn: &T) -> Result<i64> {
+    pub async fn delete<T: GenericClient>(&self, conn: &T) -> Result<i64> {
         let stmt = "with deleted as (delete from init_download where id = $1 returning 1) \
                     select count(*) from deleted";
-        try_query_aggregate!(conn.query(stmt, &[&self.id]), i64)
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
     }
 
     /// Try fetching the associated `Upload`
-    pub fn get_upload<T: GenericConnection>(&self, conn: &T) -> Result<Upload> {
+    pub async fn get_upload<T: GenericClient>(&self, conn: &T) -> Result<Upload> {
         let stmt = "select * from upload where id = $1";
-        try_query_one!(conn.query(stmt, &[&self.upload]), Upload)
+        try_query_one!(conn.query(stmt, &[&self.upload]).await, Upload)
     }
 
-    /// Check if download initializer is still valid
-    pub fn still_valid(&self, dt: &DateTime<Utc>) -> bool {
-        dt.signed_duration_since(self.date_created)
-            <= Duration::seconds(CONFIG.download_timeout_secs)
+    /// Check if download initializer is still valid, i.e. it was last accessed
+    /// within the current `config.download_timeout_secs`
+    pub fn still_valid(&self, config: &Config, dt: &DateTime<Utc>) -> bool {
+        dt.signed_duration_since(self.date_accessed)
+            <= Duration::seconds(config.download_timeout_secs)
     }
 
-    /// Try deleting all `init_download` records that are older than the current `CONFIG.download_timeout_secs`
-    pub fn clear_outdated<T: GenericConnection>(conn: &T) -> Result<i64> {
+    /// Try deleting all `init_download` records that haven't been accessed within the
+    /// current `config.download_timeout_secs`
+    pub async fn clear_outdated<T: GenericClient>(conn: &T, config: &Config) -> Result<i64> {
         let stmt =
-            "with deleted as (delete from init_download where date_created < $1 returning 1) \
+            "with deleted as (delete from init_download where date_accessed < $1 returning 1) \
              select count(*) from deleted";
-        let timeout = Duration::seconds(CONFIG.download_timeout_secs);
+        let timeout = Duration::seconds(config.download_timeout_secs);
         let now = Utc::now();
         let cutoff = now.checked_sub_signed(timeout).ok_or_else(|| {
             error::helpers::internal(format!(
                 "Error subtracting {} secs from {:?}",
-                CONFIG.download_timeout_secs, now
+                config.download_timeout_secs, now
             ))
         })?;
-        try_query_aggregate!(conn.query(stmt, &[&cutoff]), i64)
+        try_query_aggregate!(conn.query(stmt, &[&cutoff]).await, i64)
     }
 }
 
@@ -491,9 +964,9 @@ pub struct NewDownload {
     pub upload: i32,
 }
 impl NewDownload {
-    pub fn insert<T: GenericConnection>(self, conn: &T) -> Result<Download> {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<Download> {
         let stmt = "insert into download (upload) values ($1) returning id, date_created";
-        try_query_to_model!(conn.query(stmt, &[&self.upload]);
+        try_query_to_model!(conn.query(stmt, &[&self.upload]).await;
                             Download;
                             id: 0, date_created: 1;
                             upload: self.upload)
@@ -511,7 +984,7 @@ impl FromRow for Download {
         "download"
     }
 
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             upload: row.get("upload"),
@@ -532,7 +1005,7 @@ impl FromRow for Status {
     fn table_name() -> &'static str {
         "status"
     }
-    fn from_row(row: postgres::rows::Row) -> Self {
+    fn from_row(row: &Row) -> Self {
         Self {
             id: row.get("id"),
             upload_count: row.get("upload_count"),
@@ -543,41 +1016,66 @@ impl FromRow for Status {
 }
 impl Status {
     /// Fetch or initialize the single `status` table record
-    pub fn init_load<T: GenericConnection>(conn: &T) -> Result<Self> {
-        let trans = conn.transaction()?;
-        let status = Self::load(&trans);
+    pub async fn init_load<T: GenericClient>(conn: &mut T) -> Result<Self> {
+        let trans = conn.transaction().await?;
+        let status = Self::load(&trans).await;
         let status = match status {
-            Err(ref e) if e.is_does_not_exist() => Self::init(&trans),
+            Err(ref e) if e.is_does_not_exist() => Self::init(&trans).await,
             status => status,
         };
-        trans.commit()?;
+        trans.commit().await?;
         status
     }
 
-    pub fn load<T: GenericConnection>(conn: &T) -> error::Result<Self> {
+    pub async fn load<T: GenericClient>(conn: &T) -> error::Result<Self> {
         let stmt = "select id, upload_count, total_bytes, date_modified from status";
-        try_query_one!(conn.query(stmt, &[]), Status)
+        try_query_one!(conn.query(stmt, &[]).await, Status)
     }
 
-    pub fn init<T: GenericConnection>(conn: &T) -> Result<Self> {
+    /// Load the `status` record, locking it until the end of the transaction
+    pub async fn load_for_update<T: GenericClient>(conn: &T) -> error::Result<Self> {
+        let stmt = "select id, upload_count, total_bytes, date_modified from status for update";
+        try_query_one!(conn.query(stmt, &[]).await, Status)
+    }
+
+    pub async fn init<T: GenericClient>(conn: &T) -> Result<Self> {
         let stmt = "insert into status (upload_count, total_bytes, date_modified) \
                     values ($1, $2, $3) \
                     returning id";
         let now = Utc::now();
-        try_query_to_model!(conn.query(stmt, &[&0i64, &0i64, &now]);
+        try_query_to_model!(conn.query(stmt, &[&0i64, &0i64, &now]).await;
                             Status;
                             id: 0;
                             upload_count: 0, total_bytes: 0, date_modified: now)
     }
 
-    /// Check if we can hold `n` more bytes, staying under `CONFIG.max_combined_upload_bytes`
-    pub fn can_fit<T: GenericConnection>(conn: &T, n_bytes: i64) -> error::Result<bool> {
-        let status = Self::load(conn)?;
-        Ok((status.total_bytes + n_bytes) < CONFIG.max_combined_upload_bytes)
+    /// Number of stored uploads
+    pub fn upload_count(&self) -> i64 {
+        self.upload_count
+    }
+
+    /// Combined bytes of all stored uploads
+    pub fn total_bytes(&self) -> i64 {
+        self.total_bytes
+    }
+
+    /// Check if we can hold `n` more bytes, staying under `config.max_combined_upload_bytes`
+    pub async fn can_fit<T: GenericClient>(
+        conn: &T,
+        config: &Config,
+        n_bytes: i64,
+    ) -> error::Result<bool> {
+        Ok(Self::load(conn).await?.has_room(config, n_bytes))
+    }
+
+    /// Check if `n` more bytes fit alongside this record's total,
+    /// staying under `config.max_combined_upload_bytes`
+    pub fn has_room(&self, config: &Config, n_bytes: i64) -> bool {
+        (self.total_bytes + n_bytes) < config.max_combined_upload_bytes
     }
 
     /// Increment `status` record count and running total of uploaded bytes
-    pub fn inc_upload<T: GenericConnection>(conn: &T, n_bytes: i64) -> Result<Self> {
+    pub async fn inc_upload<T: GenericClient>(conn: &T, n_bytes: i64) -> Result<Self> {
         let stmt = "with updated as (update status set \
                     upload_count = upload_count + 1, \
                     total_bytes = total_bytes + $1, \
@@ -585,14 +1083,14 @@ impl Status {
                     returning id, upload_count, total_bytes) \
                     select * from updated";
         let now = Utc::now();
-        try_query_to_model!(conn.query(stmt, &[&n_bytes, &now]);
+        try_query_to_model!(conn.query(stmt, &[&n_bytes, &now]).await;
                             Status;
                             id: 0, upload_count: 1, total_bytes: 2;
                             date_modified: now)
     }
 
     /// Decrement `status` record count and running total of uploaded bytes
-    pub fn dec_upload<T: GenericConnection>(conn: &T, n_bytes: i64) -> Result<Self> {
+    pub async fn dec_upload<T: GenericClient>(conn: &T, n_bytes: i64) -> Result<Self> {
         let stmt = "with updated as (update status set \
                     upload_count = upload_count - 1, \
                     total_bytes = total_bytes - $1, \
@@ -600,9 +1098,369 @@ impl Status {
                     returning id, upload_count, total_bytes) \
                     select * from updated";
         let now = Utc::now();
-        try_query_to_model!(conn.query(stmt, &[&n_bytes, &now]);
+        try_query_to_model!(conn.query(stmt, &[&n_bytes, &now]).await;
                             Status;
                             id: 0, upload_count: 1, total_bytes: 2;
                             date_modified: now)
     }
 }
+
+/// For inserting a new `ApiToken` record
+pub struct NewApiToken {
+    pub name: String,
+    pub token_hash: Vec<u8>,
+    pub quota_bytes: Option<i64>,
+    pub max_file_bytes: Option<i64>,
+    pub max_lifespan_secs: Option<i64>,
+    pub max_download_limit: Option<i32>,
+}
+impl NewApiToken {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<ApiToken> {
+        let stmt = "insert into api_token \
+                    (name, token_hash, quota_bytes, max_file_bytes, max_lifespan_secs, max_download_limit) \
+                    values ($1, $2, $3, $4, $5, $6) \
+                    returning id, upload_count, total_bytes, revoked, date_created";
+        try_query_to_model!(conn.query(stmt, &[&self.name, &self.token_hash, &self.quota_bytes, &self.max_file_bytes,
+                                                &self.max_lifespan_secs, &self.max_download_limit]).await;
+                            ApiToken;
+                            id: 0, upload_count: 1, total_bytes: 2, revoked: 3, date_created: 4;
+                            name: self.name, token_hash: self.token_hash, quota_bytes: self.quota_bytes,
+                            max_file_bytes: self.max_file_bytes, max_lifespan_secs: self.max_lifespan_secs,
+                            max_download_limit: self.max_download_limit)
+    }
+}
+
+/// Maps to db table `api_token`
+///
+/// Uploads and bundles initialized with a token are held to its limits, and their
+/// stored bytes are counted against its quota.
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub token_hash: Vec<u8>,
+    pub quota_bytes: Option<i64>,
+    pub max_file_bytes: Option<i64>,
+    pub max_lifespan_secs: Option<i64>,
+    pub max_download_limit: Option<i32>,
+    pub upload_count: i64,
+    pub total_bytes: i64,
+    pub revoked: bool,
+    pub date_created: DateTime<Utc>,
+}
+impl FromRow for ApiToken {
+    fn table_name() -> &'static str {
+        "api_token"
+    }
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            name: row.get("name"),
+            token_hash: row.get("token_hash"),
+            quota_bytes: row.get("quota_bytes"),
+            max_file_bytes: row.get("max_file_bytes"),
+            max_lifespan_secs: row.get("max_lifespan_secs"),
+            max_download_limit: row.get("max_download_limit"),
+            upload_count: row.get("upload_count"),
+            total_bytes: row.get("total_bytes"),
+            revoked: row.get("revoked"),
+            date_created: row.get("date_created"),
+        }
+    }
+}
+impl ApiToken {
+    /// Return the un-revoked `api_token` record with the given `token_hash`
+    /// or `ErrorKind::DoesNotExist`
+    pub async fn find_by_hash<T: GenericClient>(conn: &T, token_hash: &[u8]) -> Result<Self> {
+        let stmt = "select * from api_token where token_hash = $1 and revoked = false";
+        try_query_one!(conn.query(stmt, &[&token_hash]).await, ApiToken)
+    }
+
+    /// Return the `api_token` record for the given `id` or `ErrorKind::DoesNotExist`
+    pub async fn find_by_id<T: GenericClient>(conn: &T, id: &i32) -> Result<Self> {
+        let stmt = "select * from api_token where id = $1";
+        try_query_one!(conn.query(stmt, &[id]).await, ApiToken)
+    }
+
+    /// Return the `api_token` record with the given `id`, locking it until the end of
+    /// the transaction, or `ErrorKind::DoesNotExist`
+    pub async fn find_by_id_for_update<T: GenericClient>(conn: &T, id: &i32) -> Result<Self> {
+        let stmt = "select * from api_token where id = $1 for update";
+        try_query_one!(conn.query(stmt, &[id]).await, ApiToken)
+    }
+
+    /// Return the `api_token` record with the given `name` or `ErrorKind::DoesNotExist`
+    pub async fn find_by_name<T: GenericClient>(conn: &T, name: &str) -> Result<Self> {
+        let stmt = "select * from api_token where name = $1";
+        try_query_one!(conn.query(stmt, &[&name]).await, ApiToken)
+    }
+
+    /// Return all `api_token` records, ordered by name
+    pub async fn select_all<T: GenericClient>(conn: &T) -> Result<Vec<Self>> {
+        let stmt = "select * from api_token order by name";
+        try_query_vec!(conn.query(stmt, &[]).await, ApiToken)
+    }
+
+    /// Revoke the current token, returning the number of items revoked. Uploads made
+    /// with it are left alone and still count against its quota until they're deleted.
+    pub async fn revoke<T: GenericClient>(&self, conn: &T) -> Result<i64> {
+        let stmt =
+            "with revoked as (update api_token set revoked = true where id = $1 returning 1) \
+                    select count(*) from revoked";
+        try_query_aggregate!(conn.query(stmt, &[&self.id]).await, i64)
+    }
+
+    /// Check if `n_bytes` more bytes fit in the token's `quota_bytes`
+    pub fn can_fit(&self, n_bytes: i64) -> bool {
+        match self.quota_bytes {
+            Some(quota) => self.total_bytes + n_bytes <= quota,
+            None => true,
+        }
+    }
+
+    /// Increment the token's upload count and running total of uploaded bytes
+    pub async fn inc_upload<T: GenericClient>(conn: &T, id: &i32, n_bytes: i64) -> Result<Self> {
+        let stmt = "update api_token set \
+                    upload_count = upload_count + 1, \
+                    total_bytes = total_bytes + $1 \
+                    where id = $2 \
+                    returning *";
+        try_query_one!(conn.query(stmt, &[&n_bytes, id]).await, ApiToken)
+    }
+
+    /// Decrement the token's upload count and running total of uploaded bytes. Only
+    /// finalized uploads were counted, see `Upload::finalize`.
+    pub async fn dec_upload<T: GenericClient>(conn: &T, id: &i32, n_bytes: i64) -> Result<Self> {
+        let stmt = "update api_token set \
+                    upload_count = upload_count - 1, \
+                    total_bytes = total_bytes - $1 \
+                    where id = $2 \
+                    returning *";
+        try_query_one!(conn.query(stmt, &[&n_bytes, id]).await, ApiToken)
+    }
+}
+
+/// For queueing a new `WebhookEvent` record
+pub struct NewWebhookEvent {
+    pub url: String,
+    pub secret: Vec<u8>,
+    pub event: String,
+    pub payload: String,
+}
+impl NewWebhookEvent {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<WebhookEvent> {
+        let stmt = "insert into webhook_event (url, secret, event, payload) \
+                    values ($1, $2, $3, $4) \
+                    returning id, attempts, next_attempt, last_error, delivered, failed, date_created";
+        try_query_to_model!(conn.query(stmt, &[&self.url, &self.secret, &self.event, &self.payload]).await;
+                            WebhookEvent;
+                            id: 0, attempts: 1, next_attempt: 2, last_error: 3, delivered: 4, failed: 5,
+                            date_created: 6;
+                            url: self.url, secret: self.secret, event: self.event, payload: self.payload)
+    }
+}
+
+/// Maps to db table `webhook_event`
+///
+/// A queued webhook delivery, retried until it's delivered or runs out of attempts
+#[derive(Clone)]
+pub struct WebhookEvent {
+    pub id: i32,
+    pub url: String,
+    pub secret: Vec<u8>,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub delivered: bool,
+    pub failed: bool,
+    pub date_created: DateTime<Utc>,
+}
+impl FromRow for WebhookEvent {
+    fn table_name() -> &'static str {
+        "webhook_event"
+    }
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            url: row.get("url"),
+            secret: row.get("secret"),
+            event: row.get("event"),
+            payload: row.get("payload"),
+            attempts: row.get("attempts"),
+            next_attempt: row.get("next_attempt"),
+            last_error: row.get("last_error"),
+            delivered: row.get("delivered"),
+            failed: row.get("failed"),
+            date_created: row.get("date_created"),
+        }
+    }
+}
+impl WebhookEvent {
+    /// Claim up to `limit` pending events that are due for a delivery attempt, oldest first,
+    /// by postponing their next attempt to `until`. Claimed events are hidden from other
+    /// senders once the current transaction commits, and become due again at `until` if
+    /// their delivery result is never recorded.
+    pub async fn claim_due<T: GenericClient>(
+        conn: &T,
+        limit: i64,
+        until: &DateTime<Utc>,
+    ) -> Result<Vec<Self>> {
+        let stmt = "with claimed as \
+                    (update webhook_event set next_attempt = $1 \
+                    where id in \
+                    (select id from webhook_event \
+                    where delivered = false and failed = false and next_attempt <= $2 \
+                    order by next_attempt \
+                    limit $3 \
+                    for update skip locked) \
+                    returning *) \
+                    select * from claimed order by id";
+        let now = Utc::now();
+        try_query_vec!(conn.query(stmt, &[until, &now, &limit]).await, WebhookEvent)
+    }
+
+    /// Return all events, oldest first
+    pub async fn select_all<T: GenericClient>(conn: &T) -> Result<Vec<Self>> {
+        let stmt = "select * from webhook_event order by id";
+        try_query_vec!(conn.query(stmt, &[]).await, WebhookEvent)
+    }
+
+    /// Record a successful delivery
+    pub async fn mark_delivered<T: GenericClient>(&self, conn: &T) -> Result<()> {
+        let stmt =
+            "update webhook_event set attempts = attempts + 1, delivered = true where id = $1";
+        conn.execute(stmt, &[&self.id]).await?;
+        Ok(())
+    }
+
+    /// Record a failed delivery attempt, retrying at `next_attempt`, or giving up
+    /// when `next_attempt` is `None`
+    pub async fn mark_attempt_failed<T: GenericClient>(
+        &self,
+        conn: &T,
+        error: &str,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let stmt = "update webhook_event set \
+                    attempts = attempts + 1, \
+                    last_error = $1, \
+                    next_attempt = coalesce($2, next_attempt), \
+                    failed = $3 \
+                    where id = $4";
+        conn.execute(
+            stmt,
+            &[&error, &next_attempt, &next_attempt.is_none(), &self.id],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Delete delivered and failed events created before `cutoff`, returning the number deleted
+    pub async fn clear_finished<T: GenericClient>(conn: &T, cutoff: &DateTime<Utc>) -> Result<i64> {
+        let stmt = "with deleted as \
+                    (delete from webhook_event where (delivered = true or failed = true) and date_created < $1 \
+                    returning 1) \
+                    select count(*) from deleted";
+        try_query_aggregate!(conn.query(stmt, &[cutoff]).await, i64)
+    }
+}
+
+/// For appending a new `AuditEvent` record
+pub struct NewAuditEvent {
+    pub event: String,
+    pub outcome: String,
+    pub upload: Option<i32>,
+    pub bundle: Option<i32>,
+    pub uuid: Option<Uuid>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+impl NewAuditEvent {
+    pub async fn insert<T: GenericClient>(self, conn: &T) -> Result<AuditEvent> {
+        let stmt = "insert into audit_event \
+                    (event, outcome, upload, bundle, uuid_, client_ip, user_agent) \
+                    values ($1, $2, $3, $4, $5, $6, $7) \
+                    returning id, date_created";
+        try_query_to_model!(conn.query(stmt, &[&self.event, &self.outcome, &self.upload, &self.bundle,
+                                                &self.uuid, &self.client_ip, &self.user_agent]).await;
+                            AuditEvent;
+                            id: 0, date_created: 1;
+                            event: self.event, outcome: self.outcome, upload: self.upload,
+                            bundle: self.bundle, uuid: self.uuid, client_ip: self.client_ip,
+                            user_agent: self.user_agent)
+    }
+}
+
+/// Maps to db table `audit_event`
+///
+/// An append-only record of a security relevant action, see `audit`
+pub struct AuditEvent {
+    pub id: i64,
+    pub event: String,
+    pub outcome: String,
+    pub upload: Option<i32>,
+    pub bundle: Option<i32>,
+    pub uuid: Option<Uuid>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub date_created: DateTime<Utc>,
+}
+impl FromRow for AuditEvent {
+    fn table_name() -> &'static str {
+        "audit_event"
+    }
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            event: row.get("event"),
+            outcome: row.get("outcome"),
+            upload: row.get("upload"),
+            bundle: row.get("bundle"),
+            uuid: row.get("uuid_"),
+            client_ip: row.get("client_ip"),
+            user_agent: row.get("user_agent"),
+            date_created: row.get("date_created"),
+        }
+    }
+}
+
+/// Criteria for selecting `AuditEvent`s, unset fields match everything
+#[derive(Default)]
+pub struct AuditFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub event: Option<String>,
+    pub outcome: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+impl AuditEvent {
+    /// Return events matching `filter`, oldest first
+    pub async fn select<T: GenericClient>(conn: &T, filter: &AuditFilter) -> Result<Vec<Self>> {
+        let stmt = "select * \
+                    from audit_event \
+                    where ($1::timestamptz is null or date_created >= $1) \
+                    and ($2::timestamptz is null or date_created < $2) \
+                    and ($3::text is null or event = $3) \
+                    and ($4::text is null or outcome = $4) \
+                    and ($5::uuid is null or uuid_ = $5) \
+                    order by id \
+                    limit $6";
+        try_query_vec!(
+            conn.query(
+                stmt,
+                &[
+                    &filter.since,
+                    &filter.until,
+                    &filter.event,
+                    &filter.outcome,
+                    &filter.uuid,
+                    &filter.limit
+                ]
+            )
+            .await,
+            AuditEvent
+        )
+    }
+}
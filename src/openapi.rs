@@ -0,0 +1,432 @@
+/*!
+OpenAPI specification
+
+The spec served at `/api/openapi.json` is built from the request and response
+types in `handlers`, which implement `Schema` through `api_type!`. Error responses
+are described by the `error::ErrorCode`s each route may return.
+*/
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+use crate::error::ErrorCode;
+use crate::handlers::*;
+use crate::service::ErrorResp;
+
+/// OpenAPI version of the generated spec
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+lazy_static! {
+    /// The spec, built once
+    pub static ref SPEC: Value = spec();
+}
+
+/// Types describable by an OpenAPI schema object
+pub trait Schema {
+    fn schema() -> Value;
+
+    /// Whether a field of this type must be present, `false` for `Option`
+    fn required() -> bool {
+        true
+    }
+}
+
+impl Schema for String {
+    fn schema() -> Value {
+        json!({"type": "string"})
+    }
+}
+
+impl Schema for bool {
+    fn schema() -> Value {
+        json!({"type": "boolean"})
+    }
+}
+
+impl Schema for i32 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int32"})
+    }
+}
+
+impl Schema for i64 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int64"})
+    }
+}
+
+impl Schema for u32 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int32", "minimum": 0})
+    }
+}
+
+impl Schema for u64 {
+    fn schema() -> Value {
+        json!({"type": "integer", "format": "int64", "minimum": 0})
+    }
+}
+
+impl Schema for Value {
+    fn schema() -> Value {
+        json!({"type": "object"})
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        schema["nullable"] = json!(true);
+        schema
+    }
+
+    fn required() -> bool {
+        false
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({"type": "array", "items": T::schema()})
+    }
+}
+
+impl<T: Schema> Schema for BTreeMap<String, T> {
+    fn schema() -> Value {
+        json!({"type": "object", "additionalProperties": T::schema()})
+    }
+}
+
+/// A documented route
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    response: Value,
+    other_responses: Vec<(&'static str, Value)>,
+    errors: &'static [ErrorCode],
+}
+impl Route {
+    fn new(method: &'static str, path: &'static str, summary: &'static str) -> Self {
+        Self {
+            method,
+            path,
+            summary,
+            parameters: vec![],
+            request_body: None,
+            response: json!({"description": "Success"}),
+            other_responses: vec![],
+            errors: &[],
+        }
+    }
+
+    /// Query parameters, one per field of `T`
+    fn query<T: Schema>(mut self) -> Self {
+        let schema = T::schema();
+        let required = schema["required"].as_array().cloned().unwrap_or_default();
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties {
+                self.parameters.push(json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required.contains(&json!(name)),
+                    "schema": property,
+                }));
+            }
+        }
+        self
+    }
+
+    fn header(mut self, name: &str, required: bool, description: &str) -> Self {
+        self.parameters.push(json!({
+            "name": name,
+            "in": "header",
+            "required": required,
+            "description": description,
+            "schema": {"type": "string"},
+        }));
+        self
+    }
+
+    /// An `Authorization: Bearer <token>` api token header
+    fn api_token(self, required: bool) -> Self {
+        self.header("Authorization", required, "`Bearer <api token>`")
+    }
+
+    fn json_body<T: Schema>(mut self) -> Self {
+        self.request_body = Some(json!({
+            "required": true,
+            "content": {"application/json": {"schema": T::schema()}},
+        }));
+        self
+    }
+
+    fn binary_body(mut self, description: &str) -> Self {
+        self.request_body = Some(json!({
+            "required": true,
+            "description": description,
+            "content": {"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}},
+        }));
+        self
+    }
+
+    fn json_response<T: Schema>(mut self) -> Self {
+        self.response = json!({
+            "description": "Success",
+            "content": {"application/json": {"schema": T::schema()}},
+        });
+        self
+    }
+
+    /// A non-error response with a JSON body, other than the `200` success response
+    fn json_status_response<T: Schema>(mut self, status: &'static str, description: &str) -> Self {
+        self.other_responses.push((
+            status,
+            json!({
+                "description": description,
+                "content": {"application/json": {"schema": T::schema()}},
+            }),
+        ));
+        self
+    }
+
+    fn binary_response(mut self, description: &str) -> Self {
+        self.response = json!({
+            "description": description,
+            "content": {"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}},
+        });
+        self
+    }
+
+    fn errors(mut self, errors: &'static [ErrorCode]) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// The OpenAPI operation object
+    fn operation(&self) -> Value {
+        let mut responses = Map::new();
+        responses.insert("200".to_string(), self.response.clone());
+        for (status, response) in &self.other_responses {
+            responses.insert(status.to_string(), response.clone());
+        }
+        for code in self.errors {
+            let status = code.status().as_u16().to_string();
+            let response = responses.entry(status).or_insert_with(|| {
+                json!({
+                    "description": "",
+                    "content": {"application/json": {"schema": error_schema()}},
+                })
+            });
+            let description = match response["description"].as_str() {
+                Some("") | None => format!("`{}`", code.as_str()),
+                Some(codes) => format!("{}, `{}`", codes, code.as_str()),
+            };
+            response["description"] = json!(description);
+        }
+        let mut operation = json!({
+            "summary": self.summary,
+            "responses": responses,
+        });
+        if !self.parameters.is_empty() {
+            operation["parameters"] = json!(self.parameters);
+        }
+        if let Some(ref body) = self.request_body {
+            operation["requestBody"] = body.clone();
+        }
+        operation
+    }
+}
+
+/// Error response schema, listing all codes
+fn error_schema() -> Value {
+    let mut schema = ErrorResp::schema();
+    let codes = ErrorCode::ALL
+        .iter()
+        .map(ErrorCode::as_str)
+        .collect::<Vec<_>>();
+    schema["properties"]["code"]["enum"] = json!(codes);
+    schema
+}
+
+fn routes() -> Vec<Route> {
+    use crate::error::ErrorCode::*;
+    const PASSWORD_ERRORS: &[ErrorCode] = &[
+        BadRequest,
+        WrongPassword,
+        NotFound,
+        Locked,
+        RateLimited,
+        Busy,
+    ];
+    const DOWNLOAD_ERRORS: &[ErrorCode] = &[
+        BadRequest,
+        WrongPassword,
+        NotFound,
+        Expired,
+        DownloadLimitReached,
+        Locked,
+        RateLimited,
+        Busy,
+    ];
+    const UPLOAD_INIT_ERRORS: &[ErrorCode] =
+        &[BadRequest, WrongPassword, UploadTooLarge, OutOfSpace, Busy];
+    const UPLOAD_ERRORS: &[ErrorCode] = &[
+        BadRequest,
+        UploadTooLate,
+        NotFound,
+        UploadTooLarge,
+        OutOfSpace,
+        ShuttingDown,
+    ];
+
+    vec![
+        Route::new("get", "/status", "Status and version").json_response::<StatusResp>(),
+        Route::new("get", "/status/live", "Liveness").json_response::<StatusResp>(),
+        Route::new(
+            "get",
+            "/status/ready",
+            "Readiness of the database, migrations, storage backend, and sweeper",
+        )
+        .json_response::<ReadinessResp>()
+        .json_status_response::<ReadinessResp>("503", "A readiness check failed"),
+        Route::new("get", "/api/hello", "Greet api clients").json_response::<HelloResp>(),
+        Route::new(
+            "get",
+            "/api/upload/defaults",
+            "Return the default configurable upload constraints",
+        )
+        .json_response::<UploadDefaultsResp>(),
+        Route::new("post", "/api/upload/init", "Initialize an upload")
+            .api_token(false)
+            .json_body::<UploadInitPost>()
+            .json_response::<UploadInitResp>()
+            .errors(UPLOAD_INIT_ERRORS),
+        Route::new(
+            "post",
+            "/api/upload",
+            "Upload an initialized upload's encrypted bytes",
+        )
+        .query::<UploadKey>()
+        .binary_body("Encrypted bytes, exactly the size declared at initialization")
+        .json_response::<UploadFileResp>()
+        .errors(UPLOAD_ERRORS),
+        Route::new(
+            "put",
+            "/api/upload/chunk",
+            "Upload a chunk of encrypted bytes",
+        )
+        .query::<UploadChunkKey>()
+        .binary_body("Encrypted bytes starting at `offset`")
+        .json_response::<UploadChunkResp>()
+        .errors(UPLOAD_ERRORS),
+        Route::new(
+            "get",
+            "/api/upload/progress",
+            "Return the number of bytes committed to an upload",
+        )
+        .query::<UploadKey>()
+        .json_response::<UploadProgressResp>()
+        .errors(&[NotFound]),
+        Route::new(
+            "get",
+            "/api/uploads",
+            "List the active uploads made with an api token",
+        )
+        .api_token(true)
+        .json_response::<UploadsResp>()
+        .errors(&[WrongPassword]),
+        Route::new("post", "/api/upload/delete", "Delete an upload")
+            .json_body::<DeleteKeyAccessPost>()
+            .json_response::<OkResp>()
+            .errors(PASSWORD_ERRORS),
+        Route::new(
+            "post",
+            "/api/upload/update",
+            "Change an upload's lifespan and download limit",
+        )
+        .json_body::<UploadUpdatePost>()
+        .json_response::<UploadUpdateResp>()
+        .errors(PASSWORD_ERRORS),
+        Route::new("post", "/api/download/init", "Initialize a download")
+            .json_body::<DownloadKeyAccessPost>()
+            .json_response::<DownloadInitResp>()
+            .errors(DOWNLOAD_ERRORS),
+        Route::new(
+            "post",
+            "/api/download",
+            "Download an upload's encrypted bytes",
+        )
+        .header(
+            "Range",
+            false,
+            "A single `bytes` range, to resume a download",
+        )
+        .json_body::<DownloadKeyAccessPost>()
+        .binary_response("Encrypted bytes, `206` when a range was requested")
+        .errors(&[
+            BadRequest,
+            WrongPassword,
+            NotFound,
+            Expired,
+            DownloadLimitReached,
+            RangeNotSatisfiable,
+            Locked,
+            RateLimited,
+            Busy,
+            ShuttingDown,
+        ]),
+        Route::new(
+            "post",
+            "/api/download/confirm",
+            "Confirm a decrypted download, revealing its file name hash",
+        )
+        .json_body::<DownloadKeyHash>()
+        .json_response::<DownloadConfirmResp>()
+        .errors(&[BadRequest, WrongPassword, NotFound]),
+        Route::new("post", "/api/bundle/init", "Initialize a bundle of uploads")
+            .api_token(false)
+            .json_body::<BundleInitPost>()
+            .json_response::<BundleInitResp>()
+            .errors(UPLOAD_INIT_ERRORS),
+        Route::new(
+            "post",
+            "/api/bundle/delete",
+            "Delete a bundle and its members",
+        )
+        .json_body::<DeleteKeyAccessPost>()
+        .json_response::<OkResp>()
+        .errors(PASSWORD_ERRORS),
+        Route::new(
+            "post",
+            "/api/bundle/download/init",
+            "Initialize a download of a bundle's members",
+        )
+        .json_body::<BundleDownloadKeyAccessPost>()
+        .json_response::<BundleDownloadInitResp>()
+        .errors(DOWNLOAD_ERRORS),
+        Route::new("get", "/api/openapi.json", "This OpenAPI specification")
+            .json_response::<Value>(),
+    ]
+}
+
+/// Build the OpenAPI document
+pub fn spec() -> Value {
+    let mut paths = Map::new();
+    for route in routes() {
+        let path = paths
+            .entry(route.path.to_string())
+            .or_insert_with(|| json!({}));
+        path[route.method] = route.operation();
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": crate::APPNAME,
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+    })
+}
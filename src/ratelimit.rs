@@ -0,0 +1,70 @@
+/*!
+Rate limiting of password attempts
+
+Attempts are counted in fixed one minute windows per key, e.g. a client IP
+or an upload.
+*/
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{self, Result};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of tracked keys after which expired windows are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an attempt against `key`, returning `ErrorKind::TooManyRequests`
+    /// if more than `limit` attempts were made in the current window
+    pub fn check(&self, key: &str, limit: u32) -> Result<()> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("rate limit lock poisoned");
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        }
+        let window = windows.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(window.0) >= WINDOW {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        if window.1 > limit {
+            let retry = WINDOW - now.duration_since(window.0);
+            let secs = retry.as_secs() + 1;
+            return Err(error::helpers::too_many_requests(format!(
+                "Too many attempts, retry in {} seconds",
+                secs
+            ))
+            .with_retry_after(secs));
+        }
+        Ok(())
+    }
+}
+
+/// Determine the client's IP. When `trust_forwarded_for` is set, the last address
+/// in `X-Forwarded-For` is used, which is the one appended by our own proxy.
+pub fn client_ip(
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = forwarded_for
+            .and_then(|header| header.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    remote.map(|addr| addr.ip())
+}
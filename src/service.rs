@@ -2,46 +2,114 @@
 Service initialization
 */
 use std::io::Write;
-use std::thread;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::Local;
 use env_logger;
-use futures_cpupool::CpuPool;
-use futures_fs::FsPool;
 use num_cpus;
-use warp::http::StatusCode;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::task;
 use warp::{self, Filter};
 
+use crate::audit;
+use crate::config::{Config, LiveConfig, Overrides};
 use crate::db;
-use crate::error::{self, Result};
+use crate::envelope::KeyRing;
+use crate::error::{self, ErrorCode, Result};
 use crate::handlers;
+use crate::hashing::HashPool;
+use crate::locks::UploadLocks;
+use crate::metrics;
 use crate::models;
-use crate::models::CONFIG;
+use crate::openapi;
+use crate::ratelimit::{self, RateLimiter};
+use crate::shutdown::Shutdown;
+use crate::storage::{self, Storage};
 use crate::sweep;
-use std::net::SocketAddr;
+use crate::webhooks;
+
+lazy_static! {
+    /// Commit the running build was made from, read from `commit_hash.txt`
+    pub static ref COMMIT_HASH: String = {
+        use std::io::Read;
+        std::fs::File::open("commit_hash.txt")
+            .map(|mut f| {
+                let mut s = String::new();
+                f.read_to_string(&mut s).expect("Error reading commit_hash.txt");
+                s
+            })
+            .unwrap_or_else(|_| "unknown".to_string())
+    };
+}
 
 #[derive(Clone)]
 pub struct Ctx {
-    pub cpu: CpuPool,
+    pub config: Arc<LiveConfig>,
     pub db: db::Pool,
-    pub fs: FsPool,
+    pub storage: Arc<dyn Storage>,
+    pub keys: Option<Arc<KeyRing>>,
+    pub shutdown: Arc<Shutdown>,
+    pub limiter: Arc<RateLimiter>,
+    pub hash_pool: Arc<HashPool>,
+    pub upload_locks: Arc<UploadLocks>,
 }
 
 /// Initialize the `status` database table if it doesn't already exist
-fn init_status() -> Result<()> {
-    let conn = db::init_conn()?;
-    models::Status::init_load(&conn)?;
+async fn init_status() -> Result<()> {
+    let mut conn = db::init_conn().await?;
+    models::Status::init_load(&mut conn).await?;
+    Ok(())
+}
+
+/// Wait until a `SIGTERM` or `SIGINT` is received
+async fn wait_for_shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
     Ok(())
 }
 
-/// Initialize things
+/// Reload the configuration whenever a `SIGHUP` is received
+async fn config_reloader(config: Arc<LiveConfig>) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        let reload_config = config.clone();
+        // reloading reads the config file
+        match task::spawn_blocking(move || reload_config.reload()).await? {
+            Err(e) => error!("Config reload failed, keeping current config: {}", e),
+            Ok(ref changes) if changes.is_empty() => info!("Config reloaded, no changes"),
+            Ok(changes) => {
+                for change in &changes {
+                    info!("Config changed: {}", change);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Initialize things, using an already loaded and validated `config`
+/// and the `overrides` it was loaded with
 /// - env logger
 /// - database `status` table
 /// - database connection pool
-/// - cleaning thread
+/// - storage backend
+/// - cleaning task
+/// - webhook delivery task
+/// - config reloading task
+/// - routes
 /// - server
 /// - handle errors
-pub fn start() -> Result<()> {
+/// - graceful shutdown on `SIGTERM`/`SIGINT`, waiting up to `shutdown_timeout_secs`
+///   for active transfers
+pub fn start(config: Config, overrides: Overrides) -> Result<()> {
     // Set a custom logging format & change the env-var to "LOG"
     // e.g. LOG=info chatbot serve
     let mut logger = env_logger::Builder::from_env("LOG");
@@ -58,31 +126,174 @@ pub fn start() -> Result<()> {
         })
         .init();
 
-    // force a config load
-    let _ = CONFIG;
+    let storage = storage::init(&config)?;
+    let keys = match config.encryption_key_path()? {
+        None => None,
+        Some(path) => {
+            if !path.exists() {
+                return Err(error::helpers::internal(format!(
+                    "Encryption key file {:?} not found, create one with `transfer admin keys rotate`",
+                    path
+                )));
+            }
+            Some(Arc::new(KeyRing::load(path)?))
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(serve(config, overrides, storage, keys))
+}
 
+/// Run the server and its background tasks until a shutdown signal is received
+async fn serve(
+    config: Config,
+    overrides: Overrides,
+    storage: Arc<dyn Storage>,
+    keys: Option<Arc<KeyRing>>,
+) -> Result<()> {
     // make sure `status` record is initialized
-    init_status()?;
-
-    // spawn our cleaning thread
-    let _ = thread::spawn(sweep::db_sweeper);
+    init_status().await?;
+
+    let addr = config.addr()?;
+    let config = Arc::new(LiveConfig::new(config, overrides));
+    let shutdown = Arc::new(Shutdown::new());
+    let db_pool = db::init_pool(num_cpus::get() * 2);
+
+    // spawn our cleaning task
+    let sweeper = tokio::spawn(sweep::db_sweeper(
+        config.clone(),
+        db_pool.clone(),
+        storage.clone(),
+        shutdown.clone(),
+    ));
+
+    // spawn our webhook delivery task
+    let webhook_sender = tokio::spawn(webhooks::webhook_sender(
+        config.clone(),
+        db_pool.clone(),
+        shutdown.clone(),
+    ));
+
+    // spawn our config reloading task
+    let reload_config = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = config_reloader(reload_config).await {
+            error!(
+                "Unable to listen for SIGHUP, config reloading disabled: {}",
+                e
+            );
+        }
+    });
 
-    let cpus = num_cpus::get();
-    let db_pool = db::init_pool(cpus as u32);
-    let cpu_pool = CpuPool::new(cpus * 2);
-    let fs_pool = FsPool::new(cpus);
+    let hash_pool = {
+        let config = config.get();
+        HashPool::new(config.hash_threads, config.hash_queue_depth)
+    };
     let ctx = Ctx {
-        cpu: cpu_pool,
+        config: config.clone(),
         db: db_pool,
-        fs: fs_pool,
+        storage,
+        keys,
+        shutdown: shutdown.clone(),
+        limiter: Arc::new(RateLimiter::new()),
+        hash_pool: Arc::new(hash_pool),
+        upload_locks: Arc::new(UploadLocks::new()),
     };
 
-    let addr = format!("{}:{}", CONFIG.host, CONFIG.port).parse::<SocketAddr>()?;
-    route_and_serve(addr, ctx);
+    // the server stops accepting connections once `stop_server` fires
+    let (stop_server, server_stopped) = oneshot::channel::<()>();
+    let (addr, server) =
+        warp::serve(routes(ctx.clone())).bind_with_graceful_shutdown(addr, async {
+            let _ = server_stopped.await;
+        });
+    info!("listening on http://{}", addr);
+    tokio::spawn(server);
+
+    wait_for_shutdown_signal().await?;
+    info!("Shutting down, no longer accepting new requests");
+    shutdown.request();
+    let _ = stop_server.send(());
+
+    let config = config.get();
+    let interrupted = shutdown
+        .wait_idle(Duration::from_secs(config.shutdown_timeout_secs))
+        .await;
+    if !interrupted.is_empty() {
+        warn!(
+            "Shutdown timeout reached, failing {} interrupted uploads",
+            interrupted.len()
+        );
+        let conn = ctx.db.get().await?;
+        let n = crate::shutdown::fail_interrupted(&conn, &config, &interrupted).await?;
+        info!("Marked {} interrupted uploads as failed", n);
+    }
+    if sweeper.await.is_err() {
+        error!("Sweeper task panicked");
+    }
+    if webhook_sender.await.is_err() {
+        error!("Webhook sender task panicked");
+    }
+    info!("Shutdown complete");
     Ok(())
 }
 
-fn route_and_serve(addr: SocketAddr, ctx: Ctx) {
+/// Reject request bodies larger than the currently configured `upload_limit_bytes`
+async fn check_upload_length(
+    ctx: Ctx,
+    content_length: Option<u64>,
+) -> std::result::Result<Ctx, warp::Rejection> {
+    let limit = ctx.config.get().upload_limit_bytes as u64;
+    match content_length {
+        None => Err(error::helpers::reject(error::helpers::bad_request(
+            "Missing content-length",
+        ))),
+        Some(n) if n > limit => Err(error::helpers::reject(error::helpers::too_large(format!(
+            "Upload too large, max bytes: {}",
+            limit
+        )))),
+        Some(_) => Ok(ctx),
+    }
+}
+
+/// Limit password attempts per client IP, on routes that verify passwords
+async fn check_auth_rate(
+    ctx: Ctx,
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+) -> std::result::Result<Ctx, warp::Rejection> {
+    let config = ctx.config.get();
+    if let Some(limit) = config.auth_rate_limit_per_minute {
+        let ip = ratelimit::client_ip(remote, forwarded_for.as_deref(), config.trust_forwarded_for);
+        if let Some(ip) = ip {
+            ctx.limiter
+                .check(&format!("ip:{}", ip), limit)
+                .map_err(error::helpers::reject)?;
+        }
+    }
+    Ok(ctx)
+}
+
+/// Identify the client making a request, for the audit log
+fn audit_client(
+    ctx: Ctx,
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+    user_agent: Option<String>,
+) -> audit::Client {
+    let config = ctx.config.get();
+    audit::Client {
+        ip: ratelimit::client_ip(remote, forwarded_for.as_deref(), config.trust_forwarded_for),
+        user_agent,
+    }
+}
+
+/// Build the complete route tree, including request logging and error handling
+pub fn routes(
+    ctx: Ctx,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone + Send + Sync + 'static
+{
     // `/api`
     let api_root = warp::path("api");
 
@@ -92,134 +303,244 @@ fn route_and_serve(addr: SocketAddr, ctx: Ctx) {
     // `/api/download`
     let api_download = api_root.and(warp::path("download"));
 
+    // `/api/bundle`
+    let api_bundle = api_root.and(warp::path("bundle"));
+
     let with_ctx = warp::any().map(move || ctx.clone());
-    let with_body_stream = warp::body::content_length_limit(CONFIG.upload_limit_bytes as u64)
-        .and(warp::body::stream());
+    // checked against the current config, so reloaded limits apply to new uploads
+    let with_upload_limit = with_ctx
+        .clone()
+        .and(warp::header::optional::<u64>("content-length"))
+        .and_then(check_upload_length);
+    let with_auth_limit = with_ctx
+        .clone()
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(check_auth_rate);
+    let with_client = with_ctx
+        .clone()
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(audit_client);
     let with_body_limit = warp::body::content_length_limit(1_000_000);
 
     // `/`
-    let index = warp::get2()
+    let index = warp::get()
         .and(warp::path::end())
         .and(warp::fs::file("assets/main.html"));
 
-    lazy_static::lazy_static! {
-        pub static ref COMMIT_HASH: String = {
-            use std::io::Read;
-            std::fs::File::open("commit_hash.txt")
-                .map(|mut f| {
-                    let mut s = String::new();
-                    f.read_to_string(&mut s).expect("Error reading commit_hash.txt");
-                    s
-                })
-                .unwrap_or_else(|_| "unknown".to_string())
-        };
-    }
+    // `/status`, `/status/live`
+    let status = warp::get()
+        .and(warp::path("status"))
+        .and(warp::path::end().or(warp::path("live").and(warp::path::end())))
+        .map(|_| handlers::status())
+        .boxed();
 
-    // `/status`
-    let status = warp::get2()
-        .and(warp::path("status").and(warp::path::end()))
-        .map(|| {
-            let body = json!({"status": "ok", "version": &*COMMIT_HASH});
-            warp::reply::json(&body)
-        });
+    // `/status/ready`
+    let status_ready = warp::get()
+        .and(warp::path("status"))
+        .and(warp::path("ready"))
+        .and(warp::path::end())
+        .and(with_ctx.clone())
+        .and_then(handlers::status_ready)
+        .boxed();
+
+    // `/metrics`
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_ctx.clone())
+        .and_then(handlers::metrics)
+        .boxed();
 
     // `/api/hello`
-    let api_hello = warp::get2()
+    let api_hello = warp::get()
         .and(api_root)
         .and(warp::path("hello"))
         .and(warp::path::end())
-        .map(|| warp::reply::json(&json!({"message": "hello!"})))
+        .map(handlers::api_hello)
+        .boxed();
+
+    // `/api/openapi.json`
+    let api_openapi = warp::get()
+        .and(api_root)
+        .and(warp::path("openapi.json"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&*openapi::SPEC))
         .boxed();
 
     // `/api/upload/defaults`
-    let api_defaults = warp::get2()
+    let api_defaults = warp::get()
         .and(api_upload)
         .and(warp::path("defaults"))
         .and(warp::path::end())
+        .and(with_ctx.clone())
         .map(handlers::api_upload_defaults)
         .boxed();
 
-    let api_upload_init = warp::post2()
+    let api_upload_init = warp::post()
         .and(api_upload)
         .and(warp::path("init"))
         .and(warp::path::end())
         .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
         .and(with_body_limit)
         .and(warp::body::json())
         .and_then(handlers::api_upload_init)
         .boxed();
 
-    let api_upload_file = warp::post2()
+    let api_upload_file = warp::post()
         .and(api_upload)
         .and(warp::path::end())
-        .and(with_ctx.clone())
+        .and(with_upload_limit.clone())
         .and(warp::query())
-        .and(with_body_stream)
+        .and(warp::body::stream())
         .and_then(handlers::api_upload_file)
         .boxed();
 
-    let api_upload_delete = warp::post2()
+    let api_upload_chunk = warp::put()
         .and(api_upload)
-        .and(warp::path("delete"))
+        .and(warp::path("chunk"))
+        .and(warp::path::end())
+        .and(with_upload_limit.clone())
+        .and(warp::query())
+        .and(warp::body::stream())
+        .and_then(handlers::api_upload_chunk)
+        .boxed();
+
+    let api_upload_progress = warp::get()
+        .and(api_upload)
+        .and(warp::path("progress"))
         .and(warp::path::end())
         .and(with_ctx.clone())
+        .and(warp::query())
+        .and_then(handlers::api_upload_progress)
+        .boxed();
+
+    // `/api/uploads`
+    let api_uploads = warp::get()
+        .and(api_root)
+        .and(warp::path("uploads"))
+        .and(warp::path::end())
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(handlers::api_uploads)
+        .boxed();
+
+    let api_upload_delete = warp::post()
+        .and(api_upload)
+        .and(warp::path("delete"))
+        .and(warp::path::end())
+        .and(with_auth_limit.clone())
+        .and(with_client.clone())
         .and(with_body_limit)
         .and(warp::body::json())
         .and_then(handlers::api_upload_delete)
         .boxed();
 
-    let api_download_init = warp::post2()
+    let api_upload_update = warp::post()
+        .and(api_upload)
+        .and(warp::path("update"))
+        .and(warp::path::end())
+        .and(with_auth_limit.clone())
+        .and(with_client.clone())
+        .and(with_body_limit)
+        .and(warp::body::json())
+        .and_then(handlers::api_upload_update)
+        .boxed();
+
+    let api_download_init = warp::post()
         .and(api_download)
         .and(warp::path("init"))
         .and(warp::path::end())
-        .and(with_ctx.clone())
+        .and(with_auth_limit.clone())
+        .and(with_client.clone())
         .and(with_body_limit)
         .and(warp::body::json())
         .and_then(handlers::api_download_init)
         .boxed();
 
-    let api_download_file = warp::post2()
+    let api_download_file = warp::post()
         .and(api_download)
         .and(warp::path::end())
-        .and(with_ctx.clone())
+        .and(with_auth_limit.clone())
+        .and(with_client.clone())
         .and(with_body_limit)
         .and(warp::body::json())
+        .and(warp::header::optional::<String>("range"))
         .and_then(handlers::api_download)
         .boxed();
 
-    let api_download_confirm = warp::post2()
+    let api_download_confirm = warp::post()
         .and(api_download)
         .and(warp::path("confirm"))
         .and(warp::path::end())
-        .and(with_ctx)
+        .and(with_ctx.clone())
+        .and(with_client.clone())
         .and(with_body_limit)
         .and(warp::body::json())
         .and_then(handlers::api_download_confirm)
         .boxed();
 
-    // match everything else as a static file
-    let static_file = warp::get2().and(warp::fs::dir("assets"));
+    let api_bundle_init = warp::post()
+        .and(api_bundle)
+        .and(warp::path("init"))
+        .and(warp::path::end())
+        .and(with_ctx)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_body_limit)
+        .and(warp::body::json())
+        .and_then(handlers::api_bundle_init)
+        .boxed();
 
-    let not_found = warp::any()
-        .map(|| {
-            warp::http::Response::builder()
-                .status(404)
-                .body(serde_json::to_string(&json!({"error": "not found"})).unwrap())
-        })
+    let api_bundle_delete = warp::post()
+        .and(api_bundle)
+        .and(warp::path("delete"))
+        .and(warp::path::end())
+        .and(with_auth_limit.clone())
+        .and(with_client.clone())
+        .and(with_body_limit)
+        .and(warp::body::json())
+        .and_then(handlers::api_bundle_delete)
         .boxed();
 
+    let api_bundle_download_init = warp::post()
+        .and(api_bundle)
+        .and(warp::path("download"))
+        .and(warp::path("init"))
+        .and(warp::path::end())
+        .and(with_auth_limit)
+        .and(with_client)
+        .and(with_body_limit)
+        .and(warp::body::json())
+        .and_then(handlers::api_bundle_download_init)
+        .boxed();
+
+    // match everything else as a static file
+    let static_file = warp::get().and(warp::fs::dir("assets"));
+
     let api = index
         .or(status)
+        .or(status_ready)
+        .or(metrics)
         .or(api_hello)
+        .or(api_openapi)
         .or(api_defaults)
         .or(api_upload_init)
         .or(api_upload_file)
+        .or(api_upload_chunk)
+        .or(api_upload_progress)
+        .or(api_uploads)
         .or(api_upload_delete)
+        .or(api_upload_update)
         .or(api_download_init)
         .or(api_download_file)
         .or(api_download_confirm)
-        .or(static_file)
-        .or(not_found);
+        .or(api_bundle_init)
+        .or(api_bundle_delete)
+        .or(api_bundle_download_init)
+        .or(static_file);
 
     let logger = warp::log::custom(|info| {
         let elap = info.elapsed();
@@ -231,71 +552,69 @@ fn route_and_serve(addr: SocketAddr, ctx: Ctx) {
             info.path(),
             ms,
         );
+        metrics::observe_request(
+            info.path(),
+            info.method().as_str(),
+            info.status().as_u16(),
+            f64::from(ms) / 1_000.,
+        );
     });
 
-    let routes = api.with(logger).recover(handle_error);
+    api.recover(handle_error).with(logger)
+}
 
-    warp::serve(routes).run(addr);
+api_type! {
+    /// Error response body, see `error::ErrorCode`
+    #[derive(Serialize, Deserialize)]
+    pub struct ErrorResp {
+        pub code: String,
+        pub error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub retry_after: Option<u64>,
+    }
 }
 
-fn handle_error(err: warp::Rejection) -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    {
-        let inner = err.find_cause::<error::Error>();
-        if let Some(inner) = inner {
-            use error::ErrorKind::*;
-            type S = StatusCode;
-            error!("Handler error: {}", inner);
-            return Ok(match inner.kind() {
-                BadRequest(ref s) => {
-                    // 400
-                    let body = json!({ "error": s });
-                    warp::reply::with_status(warp::reply::json(&body), S::BAD_REQUEST)
-                }
-                InvalidAuth(ref s) => {
-                    // 401
-                    let body = json!({ "error": s });
-                    warp::reply::with_status(warp::reply::json(&body), S::UNAUTHORIZED)
-                }
-                DoesNotExist(ref s) => {
-                    // 404
-                    let body = json!({ "error": s });
-                    warp::reply::with_status(warp::reply::json(&body), S::NOT_FOUND)
-                }
-                UploadTooLarge(ref s) => {
-                    // 413
-                    let body = json!({ "error": s });
-                    warp::reply::with_status(warp::reply::json(&body), S::PAYLOAD_TOO_LARGE)
-                }
-                OutOfSpace(ref s) => {
-                    // 503
-                    let body = json!({ "error": s });
-                    warp::reply::with_status(warp::reply::json(&body), S::SERVICE_UNAVAILABLE)
-                }
-                _ => {
-                    // 500
-                    let body = json!({"error": "something went wrong"});
-                    warp::reply::with_status(warp::reply::json(&body), S::INTERNAL_SERVER_ERROR)
-                }
-            });
-        }
+/// Build a JSON error response, see `error::ErrorCode`
+fn error_response(
+    code: ErrorCode,
+    message: &str,
+    retry_after: Option<u64>,
+) -> warp::http::Response<String> {
+    let body = ErrorResp {
+        code: code.as_str().to_string(),
+        error: message.to_string(),
+        retry_after,
+    };
+    let mut resp = warp::http::Response::builder()
+        .status(code.status())
+        .header("content-type", "application/json");
+    if let Some(secs) = retry_after {
+        resp = resp.header("retry-after", secs.to_string());
     }
+    resp.body(serde_json::to_string(&body).expect("Failed serializing error response"))
+        .expect("Failed building error response")
+}
 
-    error!("Handler error: {:?}", err.cause());
-    match err.status() {
-        e @ StatusCode::NOT_FOUND | e @ StatusCode::METHOD_NOT_ALLOWED => {
-            error!("Not found: {}", e);
-            Ok(warp::reply::with_status(
-                warp::reply::json(&json!({"error": "not found"})),
-                StatusCode::NOT_FOUND,
-            ))
+async fn handle_error(
+    err: warp::Rejection,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    if let Some(inner) = err.find::<error::Error>() {
+        error!("Handler error: {}", inner);
+        let mut resp = error_response(inner.code(), &inner.public_message(), inner.retry_after());
+        if let Some(size) = inner.complete_length() {
+            let content_range = format!("bytes */{}", size)
+                .parse()
+                .expect("Failed building content-range header");
+            resp.headers_mut()
+                .insert(warp::http::header::CONTENT_RANGE, content_range);
         }
-        e @ StatusCode::INTERNAL_SERVER_ERROR => {
-            error!("Internal error: {}", e);
-            Ok(warp::reply::with_status(
-                warp::reply::json(&json!({"error": "internal error"})),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ))
-        }
-        _ => Err(err),
+        return Ok(resp);
+    }
+
+    error!("Handler error: {:?}", err);
+    if err.is_not_found() || err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error!("Not found: {:?}", err);
+        return Ok(error_response(ErrorCode::NotFound, "not found", None));
     }
+    Err(err)
 }
@@ -0,0 +1,161 @@
+/*!
+Graceful shutdown
+
+Uploads and downloads register themselves with `Shutdown::begin` for as long as
+they're transferring bytes. Once shutdown is requested, new transfers are refused,
+`wait_idle` waits for active transfers to finish, and `fail_interrupted` cleans up
+any whole-file uploads that didn't finish before the deadline.
+*/
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use deadpool_postgres::GenericClient;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::error::{self, Result};
+use crate::models;
+
+#[derive(Default)]
+struct State {
+    requested: bool,
+    next_id: u64,
+    /// Active transfers, along with the upload being written by whole-file uploads
+    active: HashMap<u64, Option<Uuid>>,
+}
+
+/// Shutdown coordinator, shared by the server, request handlers, and the sweeper
+#[derive(Default)]
+pub struct Shutdown {
+    state: Mutex<State>,
+    changed: Notify,
+}
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop accepting new transfers and wake anything waiting on shutdown
+    pub fn request(&self) {
+        self.state.lock().expect("shutdown lock poisoned").requested = true;
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().expect("shutdown lock poisoned").requested
+    }
+
+    /// Number of active transfers
+    pub fn active_count(&self) -> usize {
+        self.state
+            .lock()
+            .expect("shutdown lock poisoned")
+            .active
+            .len()
+    }
+
+    /// Register an active transfer, or return `ErrorKind::ShuttingDown`
+    /// if shutdown has been requested
+    pub fn begin(self: &Arc<Self>) -> Result<Transfer> {
+        let mut state = self.state.lock().expect("shutdown lock poisoned");
+        if state.requested {
+            return Err(error::helpers::shutting_down("Server is shutting down"));
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.active.insert(id, None);
+        Ok(Transfer {
+            shutdown: self.clone(),
+            id,
+        })
+    }
+
+    /// Wait up to `timeout` for a shutdown request, returning `true` if one was made
+    pub async fn wait_requested(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // registered before checking, so a request made in between isn't missed
+            let changed = self.changed.notified();
+            if self.is_requested() {
+                return true;
+            }
+            if time::timeout_at(deadline, changed).await.is_err() {
+                return self.is_requested();
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for all active transfers to finish, returning the
+    /// uploads of any whole-file uploads still in progress
+    pub async fn wait_idle(&self, timeout: Duration) -> Vec<Uuid> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let changed = self.changed.notified();
+            let active = self.active_count();
+            if active == 0 {
+                break;
+            }
+            info!("Waiting for {} active transfers", active);
+            if time::timeout_at(deadline, changed).await.is_err() {
+                break;
+            }
+        }
+        let state = self.state.lock().expect("shutdown lock poisoned");
+        state.active.values().filter_map(|upload| *upload).collect()
+    }
+}
+
+/// An active transfer, unregistered when dropped
+pub struct Transfer {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+impl Transfer {
+    /// Record the upload this transfer is writing, so it can be
+    /// cleaned up if it's interrupted by shutdown
+    pub fn upload(&self, uuid: Uuid) {
+        let mut state = self.shutdown.state.lock().expect("shutdown lock poisoned");
+        state.active.insert(self.id, Some(uuid));
+    }
+}
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shutdown.state.lock() {
+            state.active.remove(&self.id);
+        }
+        self.shutdown.changed.notify_waiters();
+    }
+}
+
+/// Mark interrupted whole-file uploads as deleted and remove their staged bytes.
+///
+/// Uploads that were already finalized have handed their bytes off to storage and
+/// are left alone.
+pub async fn fail_interrupted<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    uploads: &[Uuid],
+) -> Result<i64> {
+    let mut count = 0;
+    for uuid in uploads {
+        let staging_path = models::Upload::new_file_path(config, uuid)?;
+        let encrypted_path = staging_path.with_extension("enc");
+        if !staging_path.exists() && !encrypted_path.exists() {
+            continue;
+        }
+        match models::Upload::find_unfinalized(conn, uuid).await {
+            Ok(upload) => count += upload.delete(conn).await?,
+            Err(ref e) if e.is_does_not_exist() => (),
+            Err(e) => return Err(e),
+        }
+        for path in &[staging_path, encrypted_path] {
+            if path.exists() {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+    }
+    Ok(count)
+}
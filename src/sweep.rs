@@ -1,42 +1,104 @@
 /*!
 Databse/filesystem cleanup routines
 */
-use std::fs;
-use std::path;
-use std::thread;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use postgres;
+use deadpool_postgres::GenericClient;
+use tokio::fs;
 use uuid::Uuid;
 
+use crate::audit;
+use crate::config::{Config, LiveConfig};
 use crate::db;
 use crate::error::Result;
+use crate::metrics;
 use crate::models;
+use crate::shutdown::Shutdown;
+use crate::storage::{self, Storage, StorageConfig};
+use crate::webhooks;
 
-/// Cleanup `init_upload` table, deleting expired items
-fn sweep_init_upload(conn: &postgres::Connection) -> Result<i64> {
-    models::InitUpload::clear_outdated(conn)
+/// Cleanup `init_upload` table, deleting expired items and any partially uploaded files
+async fn sweep_init_upload<T: GenericClient>(conn: &T, config: &Config) -> Result<i64> {
+    let init_uploads = models::InitUpload::select_outdated(conn, config).await?;
+    let mut sum = 0;
+    for init_upload in init_uploads.into_iter() {
+        if init_upload.uploaded_bytes > 0 {
+            let file_path = models::Upload::new_file_path(config, &init_upload.uuid)?;
+            match fs::remove_file(&file_path).await {
+                Ok(_) => (),
+                Err(e) => error!("Error deleting {:?}, {}, continuing...", file_path, e),
+            }
+        }
+        let id = init_upload.id;
+        match init_upload.delete(conn).await {
+            Ok(n) => sum += n,
+            Err(e) => error!(
+                "Error deleting init_upload with id={}, {}, continuing...",
+                id, e
+            ),
+        }
+    }
+    Ok(sum)
 }
 
 /// Cleanup `init_download` table, deleting expire items
-fn sweep_init_download(conn: &postgres::Connection) -> Result<i64> {
-    models::InitDownload::clear_outdated(conn)
+async fn sweep_init_download<T: GenericClient>(conn: &T, config: &Config) -> Result<i64> {
+    models::InitDownload::clear_outdated(conn, config).await
+}
+
+/// Record the sweeper's deletion of `record` in the audit log
+async fn audit_expired<T: GenericClient, L: models::Lockable>(conn: &T, record: &L) {
+    let client = audit::Client::default();
+    if let Err(e) = audit::record(
+        conn,
+        audit::Event::Expired,
+        audit::Outcome::Success,
+        record,
+        &client,
+    )
+    .await
+    {
+        error!(
+            "Error auditing deletion of {} id={}, {}",
+            L::table_name(),
+            record.id(),
+            e
+        );
+    }
 }
 
 /// Cleanup `upload` table, deleting expired items
-fn sweep_upload(conn: &postgres::Connection) -> Result<i64> {
-    let uploads = models::Upload::select_outdated(conn)?;
+async fn sweep_upload<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    storage: &Arc<dyn Storage>,
+) -> Result<i64> {
+    let uploads = models::Upload::select_outdated(conn, config).await?;
     let mut sum = 0;
     for upload in uploads.into_iter() {
-        match fs::remove_file(&upload.file_path) {
+        let key = upload.file_path.clone();
+        match storage::blocking(storage, move |storage| storage.delete(&key)).await {
             Ok(_) => (),
             Err(e) => error!("Error deleting {}, {}, continuing...", upload.file_path, e),
         }
         let id = upload.id;
-        match upload.delete(conn) {
+        match upload.delete(conn).await {
             Ok(n) => {
                 sum += n;
-                models::Status::dec_upload(conn, upload.size)?;
+                if upload.finalized {
+                    models::Status::dec_upload(conn, upload.size).await?;
+                    if let Some(ref token) = upload.api_token {
+                        models::ApiToken::dec_upload(conn, token, upload.size).await?;
+                    }
+                }
+                if let Err(e) =
+                    webhooks::enqueue(conn, config, &upload, webhooks::Event::Expired).await
+                {
+                    error!("Error queueing webhook for upload with id={}, {}", id, e);
+                }
+                audit_expired(conn, &upload).await;
             }
             Err(e) => error!("Error deleting upload with id={}, {}, continuing...", id, e),
         }
@@ -44,60 +106,210 @@ fn sweep_upload(conn: &postgres::Connection) -> Result<i64> {
     Ok(sum)
 }
 
-/// Periodically check/clean the database
-pub fn db_sweeper() {
-    loop {
-        {
-            match db::init_conn() {
-                Err(e) => error!("Unable to acquire db connection: {}", e),
-                Ok(conn) => {
-                    match sweep_init_upload(&conn) {
-                        Err(e) => error!("InitUpload Sweeper Error: {}", e),
-                        Ok(n) => info!("Sweeper cleaned out {} old `init_upload` items", n),
-                    };
-                    match sweep_init_download(&conn) {
-                        Err(e) => error!("InitDownload Sweeper Error: {}", e),
-                        Ok(n) => info!("Sweeper cleaned out {} old `init_download` items", n),
-                    };
-                    match sweep_upload(&conn) {
-                        Err(e) => error!("Upload Sweeper Error: {}", e),
-                        Ok(n) => info!("Sweeper cleaned out {} old `upload` items", n),
-                    };
+/// Cleanup `bundle` table, deleting expired items and their members
+async fn sweep_bundle<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    storage: &Arc<dyn Storage>,
+) -> Result<i64> {
+    let bundles = models::Bundle::select_outdated(conn, config).await?;
+    let mut sum = 0;
+    for bundle in bundles.into_iter() {
+        for upload in bundle.members(conn).await?.into_iter() {
+            let key = upload.file_path.clone();
+            match storage::blocking(storage, move |storage| storage.delete(&key)).await {
+                Ok(_) => (),
+                Err(e) => error!("Error deleting {}, {}, continuing...", upload.file_path, e),
+            }
+            let id = upload.id;
+            match upload.delete(conn).await {
+                Ok(_) => {
+                    if upload.finalized {
+                        models::Status::dec_upload(conn, upload.size).await?;
+                        if let Some(ref token) = upload.api_token {
+                            models::ApiToken::dec_upload(conn, token, upload.size).await?;
+                        }
+                    }
+                    if let Err(e) =
+                        webhooks::enqueue(conn, config, &upload, webhooks::Event::Expired).await
+                    {
+                        error!("Error queueing webhook for upload with id={}, {}", id, e);
+                    }
+                    audit_expired(conn, &upload).await;
                 }
+                Err(e) => error!("Error deleting upload with id={}, {}, continuing...", id, e),
+            }
+        }
+        let id = bundle.id;
+        match bundle.delete(conn).await {
+            Ok(n) => {
+                sum += n;
+                audit_expired(conn, &bundle).await;
             }
+            Err(e) => error!("Error deleting bundle with id={}, {}, continuing...", id, e),
+        }
+    }
+    Ok(sum)
+}
+
+lazy_static! {
+    static ref LAST_SWEEP: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Time the last successful `sweep` completed, if one has
+pub fn last_sweep() -> Option<Instant> {
+    *LAST_SWEEP.lock().expect("sweep lock poisoned")
+}
+
+/// Run each database cleanup once. The sweep is only recorded as completed
+/// when every cleanup succeeds.
+pub async fn sweep<T: GenericClient>(conn: &T, config: &Config, storage: &Arc<dyn Storage>) {
+    let _timer = metrics::SWEEP_DURATION.start_timer();
+    let mut ok = true;
+    match sweep_init_upload(conn, config).await {
+        Err(e) => {
+            error!("InitUpload Sweeper Error: {}", e);
+            ok = false;
+        }
+        Ok(n) => {
+            info!("Sweeper cleaned out {} old `init_upload` items", n);
+            metrics::SWEEP_CLEANED
+                .with_label_values(&["init_upload"])
+                .inc_by(n);
+        }
+    };
+    match sweep_init_download(conn, config).await {
+        Err(e) => {
+            error!("InitDownload Sweeper Error: {}", e);
+            ok = false;
+        }
+        Ok(n) => {
+            info!("Sweeper cleaned out {} old `init_download` items", n);
+            metrics::SWEEP_CLEANED
+                .with_label_values(&["init_download"])
+                .inc_by(n);
+        }
+    };
+    match sweep_bundle(conn, config, storage).await {
+        Err(e) => {
+            error!("Bundle Sweeper Error: {}", e);
+            ok = false;
+        }
+        Ok(n) => {
+            info!("Sweeper cleaned out {} old `bundle` items", n);
+            metrics::SWEEP_CLEANED
+                .with_label_values(&["bundle"])
+                .inc_by(n);
+        }
+    };
+    match sweep_upload(conn, config, storage).await {
+        Err(e) => {
+            error!("Upload Sweeper Error: {}", e);
+            ok = false;
+        }
+        Ok(n) => {
+            info!("Sweeper cleaned out {} old `upload` items", n);
+            metrics::SWEEP_CLEANED
+                .with_label_values(&["upload"])
+                .inc_by(n);
+        }
+    };
+    if ok {
+        *LAST_SWEEP.lock().expect("sweep lock poisoned") = Some(Instant::now());
+    }
+}
+
+/// Periodically check/clean the database, until shutdown is requested
+pub async fn db_sweeper(
+    live_config: Arc<LiveConfig>,
+    db: db::Pool,
+    storage: Arc<dyn Storage>,
+    shutdown: Arc<Shutdown>,
+) {
+    loop {
+        let config = live_config.get();
+        match db.get().await {
+            Err(e) => error!("Unable to acquire db connection: {}", e),
+            Ok(conn) => sweep(&conn, &config, &storage).await,
+        }
+        let interval = Duration::from_secs(config.expired_cleanup_interval_secs);
+        if shutdown.wait_requested(interval).await {
+            info!("Sweeper stopped");
+            return;
         }
-        thread::sleep(Duration::from_secs(
-            models::CONFIG.expired_cleanup_interval_secs,
-        ));
     }
 }
 
-/// Cleanup upload files that've been orphaned, no longer have an associated db record
-fn sweep_files(conn: &postgres::Connection, upload_dir: &path::Path) -> Result<u64> {
-    use std::ffi::OsStr;
-    use std::str::FromStr;
+/// Cleanup upload files that've been orphaned, no longer have an associated db record.
+///
+/// Stored items, and staging files in the upload directory (including `.enc` files of
+/// uploads being encrypted), are removed once neither an upload nor an in-progress upload
+/// exists for their key. Files that aren't named after a key are skipped.
+pub async fn sweep_files<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    storage: &Arc<dyn Storage>,
+) -> Result<u64> {
     let mut count = 0;
-    for file in fs::read_dir(upload_dir)? {
-        let path = file?.path();
-        if path.is_dir() {
+    for key in storage::blocking(storage, |storage| storage.list()).await? {
+        let uuid = match Uuid::from_str(&key) {
+            Ok(uuid) => uuid,
+            Err(_) => {
+                // `Local` storage lists the `.enc` staging files swept below
+                if !key.ends_with(".enc") {
+                    warn!("Skipping stored item {:?}, not an upload key", key);
+                }
+                continue;
+            }
+        };
+        if is_orphaned(conn, &uuid).await? {
+            storage::blocking(storage, move |storage| storage.delete(&key)).await?;
+            count += 1;
+        }
+    }
+
+    // under `Local` storage, staging files without an extension are the stored items swept above
+    let local = matches!(config.storage, StorageConfig::Local);
+    let mut entries = fs::read_dir(config.upload_dir()?).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
             continue;
         }
-        if let Some(file_name) = path.file_name().and_then(OsStr::to_str) {
-            if file_name.starts_with('.') {
+        let path = entry.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let key = match name.strip_suffix(".enc") {
+            Some(key) => key,
+            None if local => continue,
+            None => name,
+        };
+        let uuid = match Uuid::from_str(key) {
+            Ok(uuid) => uuid,
+            Err(_) => {
+                warn!("Skipping staging file {:?}, not an upload key", path);
                 continue;
             }
-            let uuid = Uuid::from_str(file_name)?;
-            if !models::Upload::uuid_exists_available(conn, &uuid)? {
-                fs::remove_file(&path)?;
-                count += 1;
+        };
+        if is_orphaned(conn, &uuid).await? {
+            match fs::remove_file(&path).await {
+                Ok(_) => count += 1,
+                Err(e) => error!("Error deleting {:?}, {}, continuing...", path, e),
             }
         }
     }
     Ok(count)
 }
 
+/// Check whether neither an upload nor an in-progress upload exists for `uuid`
+async fn is_orphaned<T: GenericClient>(conn: &T, uuid: &Uuid) -> Result<bool> {
+    Ok(!models::Upload::uuid_exists_available(conn, uuid).await?
+        && !models::InitUpload::uuid_exists(conn, uuid).await?)
+}
+
 /// Cleanup orphaned upload files
-pub fn sweep_fs<P: AsRef<path::Path>>(upload_dir: P) -> Result<u64> {
-    let conn = db::init_conn()?;
-    sweep_files(&conn, upload_dir.as_ref())
+pub async fn sweep_fs(config: &Config, storage: &Arc<dyn Storage>) -> Result<u64> {
+    let conn = db::init_conn().await?;
+    sweep_files(&conn, config, storage).await
 }
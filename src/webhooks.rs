@@ -0,0 +1,546 @@
+/*!
+Webhook notifications
+
+Events are queued in the `webhook_event` table, in the same transaction as the change
+they describe, and delivered by `webhook_sender`. Failed deliveries are retried with
+exponential backoff, up to `config.webhook_max_attempts` times.
+
+Each delivery is a `POST` of a JSON body, signed with HMAC-SHA256 using the webhook's
+secret. The hex encoded signature is sent in the `X-Transfer-Signature: sha256=<hex>` header.
+
+Webhooks supplied by uploads may only target public addresses, unless their host is
+listed in `config.webhook_allowed_hosts`. Their hosts are resolved and checked again
+before each delivery, and the checked address is the one connected to. Redirects
+are never followed.
+*/
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{self, Utc};
+use deadpool_postgres::GenericClient;
+use native_tls;
+use reqwest;
+use tokio::task;
+
+use crate::auth;
+use crate::config::{Config, LiveConfig};
+use crate::db;
+use crate::error::{self, Result};
+use crate::metrics;
+use crate::models;
+use crate::shutdown::Shutdown;
+
+/// Header containing the signature of the request body
+pub const SIGNATURE_HEADER: &str = "X-Transfer-Signature";
+
+/// Header containing the event name
+pub const EVENT_HEADER: &str = "X-Transfer-Event";
+
+/// Max events delivered per pass
+const BATCH_SIZE: i64 = 100;
+
+/// Max seconds to wait on a webhook response
+const TIMEOUT_SECS: u64 = 10;
+
+/// Delay before the first retry, doubled for each following attempt
+const RETRY_BASE_SECS: i64 = 30;
+
+/// Max delay between retries
+const RETRY_MAX_SECS: i64 = 3600;
+
+/// Seconds claimed events are hidden from other senders, long enough to attempt
+/// a full batch
+const CLAIM_SECS: i64 = BATCH_SIZE * TIMEOUT_SECS as i64;
+
+/// Days to keep delivered and failed events
+const RETAIN_DAYS: i64 = 7;
+
+/// Upload events that webhooks are notified of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A download was counted against the upload's download limit
+    Download,
+    /// A downloader confirmed they decrypted the upload
+    DownloadConfirmed,
+    /// The sweeper deleted the upload after it expired or reached its download limit
+    Expired,
+    /// The upload was deleted with its deletion password
+    Deleted,
+}
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Event::Download => "download",
+            Event::DownloadConfirmed => "download_confirmed",
+            Event::Expired => "expired",
+            Event::Deleted => "deleted",
+        }
+    }
+}
+
+/// Check `url` is an absolute `http` or `https` url
+pub fn check_url(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| error::helpers::bad_request(format!("invalid webhook url: {}", e)))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(error::helpers::bad_request(format!(
+            "invalid webhook url scheme: {}",
+            scheme
+        ))),
+    }
+}
+
+/// Check an upload supplied webhook `url` is valid and targets a public address.
+/// Hosts listed in `config.webhook_allowed_hosts` may resolve to any address.
+pub fn check_upload_url(config: &Config, url: &str) -> Result<()> {
+    resolve_upload_url(config, url).map(|_| ())
+}
+
+/// Check an upload supplied webhook `url` like `check_upload_url`, returning the
+/// checked address to connect to, or `None` for hosts in `config.webhook_allowed_hosts`
+fn resolve_upload_url(config: &Config, url: &str) -> Result<Option<SocketAddr>> {
+    check_url(url)?;
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| error::helpers::bad_request(format!("invalid webhook url: {}", e)))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| error::helpers::bad_request("webhook url is missing a host"))?;
+    if config
+        .webhook_allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(None);
+    }
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addrs = match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| {
+                error::helpers::bad_request(format!("unresolvable webhook host {}: {}", host, e))
+            })?
+            .collect(),
+    };
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(&addr.ip())) {
+        return Err(error::helpers::bad_request(format!(
+            "webhook host {} is not a public address",
+            host
+        )));
+    }
+    Ok(Some(addrs[0]))
+}
+
+/// Return the IPv4 address embedded in an IPv4-mapped (`::ffff:0:0/96`),
+/// IPv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`), or 6to4 (`2002::/16`) address
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |hi: u16, lo: u16| Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+    if let Some(ip) = ip.to_ipv4() {
+        return Some(ip);
+    }
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return Some(v4(segments[6], segments[7]));
+    }
+    if segments[0] == 0x2002 {
+        return Some(v4(segments[1], segments[2]));
+    }
+    None
+}
+
+/// Check `ip` is publicly routable, i.e. not loopback, link-local, private,
+/// shared, multicast, or otherwise reserved. IPv6 addresses embedding an IPv4
+/// address are checked by the embedded address.
+fn is_public(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || octets[0] == 0
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+                || octets[0] >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return false;
+            }
+            if let Some(ip) = embedded_ipv4(&ip) {
+                return is_public(&IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            !(ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80
+                // local-use NAT64, Teredo, and documentation ranges
+                || segments[..3] == [0x64, 0xff9b, 1]
+                || segments[..2] == [0x2001, 0]
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+/// Return the `X-Transfer-Signature` header value for `payload`
+pub fn signature(secret: &[u8], payload: &[u8]) -> String {
+    format!("sha256={}", hex::encode(auth::hmac_sha256(secret, payload)))
+}
+
+/// Queue `event` for delivery to the upload's webhook and the configured global webhook
+pub async fn enqueue<T: GenericClient>(
+    conn: &T,
+    config: &Config,
+    upload: &models::Upload,
+    event: Event,
+) -> Result<()> {
+    let mut targets = vec![];
+    if let (Some(ref url), Some(ref secret)) = (&upload.webhook_url, &upload.webhook_secret) {
+        targets.push((url.clone(), secret.clone()));
+    }
+    if let (Some(ref url), Some(ref secret)) = (&config.webhook_url, &config.webhook_secret) {
+        targets.push((url.clone(), secret.as_bytes().to_vec()));
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let payload = json!({
+        "event": event.as_str(),
+        "upload": hex::encode(upload.uuid.as_bytes()),
+        "size": upload.size,
+        "date": Utc::now().to_rfc3339(),
+    })
+    .to_string();
+    for (url, secret) in targets {
+        models::NewWebhookEvent {
+            url,
+            secret,
+            event: event.as_str().to_string(),
+            payload: payload.clone(),
+        }
+        .insert(conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Delay before retrying an event that has failed `attempts` times
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exp = (attempts.max(1) - 1).min(16) as u32;
+    chrono::Duration::seconds((RETRY_BASE_SECS * 2i64.pow(exp)).min(RETRY_MAX_SECS))
+}
+
+/// `POST` a single event to the upload webhook address `addr`, checked by
+/// `resolve_upload_url`, instead of resolving the event's url again. Blocks.
+fn deliver_to(addr: &SocketAddr, event: &models::WebhookEvent) -> Result<()> {
+    let url = reqwest::Url::parse(&event.url)
+        .map_err(|e| error::helpers::bad_request(format!("invalid webhook url: {}", e)))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| error::helpers::bad_request("webhook url is missing a host"))?;
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let head = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         {}: {}\r\n\
+         {}: {}\r\n\
+         Connection: close\r\n\r\n",
+        target,
+        host_header,
+        event.payload.len(),
+        EVENT_HEADER,
+        event.event,
+        SIGNATURE_HEADER,
+        signature(&event.secret, event.payload.as_bytes()),
+    );
+
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let stream = TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let status = if url.scheme() == "https" {
+        let tls = native_tls::TlsConnector::new()
+            .map_err(|e| error::helpers::internal(format!("webhook tls error: {}", e)))?;
+        // certificates are verified against the url's host, not the address
+        let stream = tls
+            .connect(host.trim_matches(|c| c == '[' || c == ']'), stream)
+            .map_err(|e| error::helpers::internal(format!("webhook tls error: {}", e)))?;
+        post_raw(stream, head.as_bytes(), event.payload.as_bytes())?
+    } else {
+        post_raw(stream, head.as_bytes(), event.payload.as_bytes())?
+    };
+    if !(200..300).contains(&status) {
+        return Err(error::helpers::internal(format!(
+            "webhook responded with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Write an HTTP/1.1 request `head` and `body` to `stream`, returning the response status
+fn post_raw<S: Read + Write>(mut stream: S, head: &[u8], body: &[u8]) -> Result<u16> {
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    match (
+        parts.next(),
+        parts.next().and_then(|s| s.parse::<u16>().ok()),
+    ) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => Ok(status),
+        _ => Err(error::helpers::internal(format!(
+            "invalid webhook response: {:?}",
+            line.trim_end()
+        ))),
+    }
+}
+
+/// `POST` a single event
+async fn deliver(http: &reqwest::Client, event: &models::WebhookEvent) -> Result<()> {
+    let resp = http
+        .post(&event.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event.event.as_str())
+        .header(
+            SIGNATURE_HEADER,
+            signature(&event.secret, event.payload.as_bytes()),
+        )
+        .body(event.payload.clone())
+        .send()
+        .await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(error::helpers::internal(format!(
+            "webhook responded with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Deliver an upload supplied event. Its host is checked again, in case it now resolves
+/// elsewhere, and the checked address is connected to without resolving the host again.
+async fn deliver_upload_event(
+    http: &reqwest::Client,
+    config: &Arc<Config>,
+    event: &models::WebhookEvent,
+) -> Result<()> {
+    let (config, url) = (config.clone(), event.url.clone());
+    match task::spawn_blocking(move || resolve_upload_url(&config, &url)).await?? {
+        Some(addr) => {
+            let event = event.clone();
+            task::spawn_blocking(move || deliver_to(&addr, &event)).await?
+        }
+        None => deliver(http, event).await,
+    }
+}
+
+/// Attempt delivery of all due events, returning the number delivered
+///
+/// Events are claimed in a short transaction and delivered outside of it, so no
+/// rows stay locked while waiting on webhook responses. Each result is then
+/// recorded in its own transaction.
+pub async fn deliver_due(conn: &mut db::DbConn, config: &Arc<Config>) -> Result<i64> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let claimed_until = Utc::now() + chrono::Duration::seconds(CLAIM_SECS);
+    let events = {
+        let trans = conn.transaction().await?;
+        let events = models::WebhookEvent::claim_due(&trans, BATCH_SIZE, &claimed_until).await?;
+        trans.commit().await?;
+        events
+    };
+    let mut delivered = 0;
+    for event in events.into_iter() {
+        let result = if config.webhook_url.as_ref() == Some(&event.url) {
+            deliver(&http, &event).await
+        } else {
+            deliver_upload_event(&http, config, &event).await
+        };
+        let trans = conn.transaction().await?;
+        match result {
+            Ok(_) => {
+                event.mark_delivered(&trans).await?;
+                delivered += 1;
+                metrics::WEBHOOK_DELIVERIES
+                    .with_label_values(&["delivered"])
+                    .inc();
+            }
+            Err(e) => {
+                let attempts = event.attempts + 1;
+                let next_attempt = if attempts >= config.webhook_max_attempts {
+                    warn!(
+                        "Giving up on webhook event id={} to {} after {} attempts: {}",
+                        event.id, event.url, attempts, e
+                    );
+                    metrics::WEBHOOK_DELIVERIES
+                        .with_label_values(&["failed"])
+                        .inc();
+                    None
+                } else {
+                    metrics::WEBHOOK_DELIVERIES
+                        .with_label_values(&["retried"])
+                        .inc();
+                    Some(Utc::now() + retry_delay(attempts))
+                };
+                event
+                    .mark_attempt_failed(&trans, &e.to_string(), next_attempt)
+                    .await?;
+            }
+        }
+        trans.commit().await?;
+    }
+    let cutoff = Utc::now() - chrono::Duration::days(RETAIN_DAYS);
+    models::WebhookEvent::clear_finished(&*conn, &cutoff).await?;
+    Ok(delivered)
+}
+
+/// Periodically deliver queued webhook events, until shutdown is requested
+pub async fn webhook_sender(live_config: Arc<LiveConfig>, db: db::Pool, shutdown: Arc<Shutdown>) {
+    loop {
+        let config = live_config.get();
+        match db.get().await {
+            Err(e) => error!("Unable to acquire db connection: {}", e),
+            Ok(mut conn) => match deliver_due(&mut conn, &config).await {
+                Err(e) => error!("Webhook Sender Error: {}", e),
+                Ok(0) => (),
+                Ok(n) => info!("Delivered {} webhook events", n),
+            },
+        }
+        let interval = Duration::from_secs(config.webhook_interval_secs);
+        if shutdown.wait_requested(interval).await {
+            info!("Webhook sender stopped");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn public(ip: &str) -> bool {
+        is_public(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses() {
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1::248"));
+        assert!(public("64:ff9b::5db8:d822"));
+        for ip in &[
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "2002:c0a8:101::1",
+            "64:ff9b:1::1",
+            "2001:db8::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    /// Accept a single request, responding with `status` and returning the request
+    fn serve_once(status: &'static str) -> (SocketAddr, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nLocation: http://127.0.0.1:1/\r\nContent-Length: 0\r\n\r\n",
+                status
+            )
+            .unwrap();
+            request
+        });
+        (addr, handle)
+    }
+
+    fn event(url: &str) -> models::WebhookEvent {
+        models::WebhookEvent {
+            id: 1,
+            url: url.to_string(),
+            secret: b"secret".to_vec(),
+            event: Event::Download.as_str().to_string(),
+            payload: String::from(r#"{"event":"download"}"#),
+            attempts: 0,
+            next_attempt: Utc::now(),
+            last_error: None,
+            delivered: false,
+            failed: false,
+            date_created: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn deliver_to_checked_address() {
+        // the host doesn't resolve, the checked address is used instead
+        let (addr, handle) = serve_once("204 No Content");
+        let event = event("http://webhook.invalid:8080/hook?id=1");
+        deliver_to(&addr, &event).unwrap();
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("POST /hook?id=1 HTTP/1.1\r\n"));
+        assert!(request.contains("Host: webhook.invalid:8080\r\n"));
+        assert!(request.contains(&format!(
+            "{}: {}\r\n",
+            SIGNATURE_HEADER,
+            signature(b"secret", event.payload.as_bytes())
+        )));
+        assert!(request.ends_with(&event.payload));
+    }
+
+    #[test]
+    fn redirects_are_not_followed() {
+        let (addr, handle) = serve_once("302 Found");
+        assert!(deliver_to(&addr, &event("http://webhook.invalid/hook")).is_err());
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,1276 @@
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use transfer::auth::PasswordHashing;
+use transfer::client::{Client, UploadOptions};
+use transfer::config::AuthFailureAction;
+use transfer::error::ErrorKind;
+use transfer::models;
+use transfer::storage::StorageConfig;
+
+use common::{TestServer, Upload, MAX_COMBINED_UPLOAD_BYTES, UPLOAD_LIMIT_BYTES};
+
+#[test]
+fn hello() {
+    let server = TestServer::start();
+    let resp = server.get("/api/hello");
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.json(), json!({"message": "hello!"}));
+}
+
+#[test]
+fn upload_download_delete() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"some secret content");
+    let init = server.upload(&upload);
+
+    let data = server
+        .download(&init.key, &upload)
+        .expect("download failed");
+    assert_eq!(data, upload.data);
+
+    // wrong deletion password
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"nope")}),
+    );
+    assert_eq!(resp.status, StatusCode::UNAUTHORIZED);
+
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"delete")}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn chunked_upload_resume_and_progress() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"content uploaded in several chunks");
+    let (resp, init) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+    let size = init.encrypted.len() as i64;
+    let progress = || {
+        let resp = server.get(&format!("/api/upload/progress?key={}", init.key));
+        assert_eq!(resp.status, StatusCode::OK);
+        let progress = resp.json();
+        assert_eq!(progress["size"], size);
+        (progress["offset"].clone(), progress["complete"].clone())
+    };
+    let chunk = |offset: usize, bytes: &[u8]| {
+        server.put_bytes(
+            &format!("/api/upload/chunk?key={}&offset={}", init.key, offset),
+            bytes,
+        )
+    };
+    assert_eq!(progress(), (json!(0), json!(false)));
+
+    let resp = chunk(0, &init.encrypted[..10]);
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.json()["offset"], 10);
+    assert_eq!(resp.json()["complete"], false);
+    assert_eq!(progress(), (json!(10), json!(false)));
+
+    // resuming from a stale offset
+    let resp = chunk(0, &init.encrypted[..10]);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+
+    // a concurrent writer holds the upload's write lock
+    let uuid = Uuid::parse_str(&init.key).unwrap();
+    let id = server.block_on(async {
+        let conn = server.ctx.db.get().await.unwrap();
+        models::InitUpload::find(&conn, &uuid).await.unwrap().id
+    });
+    let lock = server
+        .ctx
+        .upload_locks
+        .try_lock(id)
+        .expect("Failed taking upload lock");
+    let resp = chunk(10, &init.encrypted[10..]);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    let resp = server.post_bytes(&format!("/api/upload?key={}", init.key), &init.encrypted);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    drop(lock);
+    assert_eq!(progress(), (json!(10), json!(false)));
+
+    let resp = chunk(10, &init.encrypted[10..]);
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.json()["offset"], size);
+    assert_eq!(resp.json()["complete"], true);
+    assert_eq!(progress(), (json!(size), json!(true)));
+
+    assert_eq!(server.download(&init.key, &upload).unwrap(), upload.data);
+}
+
+#[test]
+fn chunk_upload_holds_no_db_connection_while_streaming() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let mut server = TestServer::start();
+    let upload = Upload::new(b"content streamed slowly");
+    let (resp, init) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+    let addr = server.serve();
+
+    let mut stream = TcpStream::connect(addr.trim_start_matches("http://")).unwrap();
+    write!(
+        stream,
+        "PUT /api/upload/chunk?key={}&offset=0 HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        init.key,
+        init.encrypted.len()
+    )
+    .unwrap();
+    stream.write_all(&init.encrypted[..5]).unwrap();
+    stream.flush().unwrap();
+
+    // the chunk is being streamed, without a checked out connection
+    thread::sleep(Duration::from_millis(500));
+    let status = server.ctx.db.status();
+    assert_eq!(status.size, status.available);
+
+    stream.write_all(&init.encrypted[5..]).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert_eq!(server.download(&init.key, &upload).unwrap(), upload.data);
+}
+
+#[test]
+fn download_stored_bytes_and_range() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"0123456789abcdef");
+    let init = server.upload(&upload);
+
+    let access_password = hex::encode(upload.access_password.as_bytes());
+    let resp = server.post_json(
+        "/api/download/init",
+        &json!({"key": &init.key, "access_password": &access_password}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    let download_key = resp.json()["download_key"].clone();
+    let body = json!({"key": &download_key, "access_password": &access_password});
+    let size = init.encrypted.len();
+
+    let resp = server.post_json_with_headers("/api/download", &body, &[("range", "bytes=0-3")]);
+    assert_eq!(resp.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.body, &init.encrypted[..4]);
+    assert_eq!(
+        resp.headers["content-range"],
+        format!("bytes 0-3/{}", size).as_str()
+    );
+
+    let resp = server.post_json_with_headers(
+        "/api/download",
+        &body,
+        &[("range", &format!("bytes={}-", size))],
+    );
+    assert_eq!(resp.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        resp.headers["content-range"],
+        format!("bytes */{}", size).as_str()
+    );
+
+    // the started download may only be resumed
+    let resp = server.post_json("/api/download", &body);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+
+    let resp = server.post_json_with_headers("/api/download", &body, &[("range", "bytes=4-")]);
+    assert_eq!(resp.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.body, &init.encrypted[4..]);
+
+    // reaching the end of the file used up the download key
+    let resp = server.post_json_with_headers("/api/download", &body, &[("range", "bytes=4-")]);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn download_key_used_up_by_full_download() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"0123456789abcdef");
+    let init = server.upload(&upload);
+
+    let access_password = hex::encode(upload.access_password.as_bytes());
+    let resp = server.post_json(
+        "/api/download/init",
+        &json!({"key": &init.key, "access_password": &access_password}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    let body = json!({"key": resp.json()["download_key"], "access_password": &access_password});
+
+    let resp = server.post_json("/api/download", &body);
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.body, init.encrypted);
+
+    let resp = server.post_json("/api/download", &body);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    let resp = server.post_json_with_headers("/api/download", &body, &[("range", "bytes=0-")]);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn invalid_access_password() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"content");
+    let init = server.upload(&upload);
+
+    let mut wrong = Upload::new(b"content");
+    wrong.access_password = String::from("wrong");
+    assert_eq!(
+        server.download(&init.key, &wrong),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[test]
+fn lock_after_failed_passwords() {
+    let server = TestServer::start();
+    server
+        .reload_config(|c| {
+            c.max_auth_failures = Some(3);
+            c.auth_failure_action = AuthFailureAction::Lock;
+        })
+        .expect("reload failed");
+    let upload = Upload::new(b"content");
+    let init = server.upload(&upload);
+
+    let mut wrong = Upload::new(b"content");
+    wrong.access_password = String::from("wrong");
+    for _ in 0..3 {
+        assert_eq!(
+            server.download(&init.key, &wrong),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+    // locked, even with the correct password
+    assert_eq!(server.download(&init.key, &upload), Err(StatusCode::LOCKED));
+
+    server
+        .reload_config(|c| c.auth_failure_action = AuthFailureAction::Delete)
+        .expect("reload failed");
+    let init = server.upload(&upload);
+    for _ in 0..3 {
+        assert_eq!(
+            server.download(&init.key, &wrong),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn password_rehash() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"rehashed content");
+    let init = server.upload(&upload);
+    let auth = server.access_auth(&init.key);
+    assert_eq!(auth.algorithm, "argon2id");
+    assert_eq!(auth.params, "m=64,t=1,p=1");
+
+    // swap in a hash made before algorithms were recorded
+    let salt = transfer::auth::new_salt().unwrap();
+    let sha = transfer::auth::sha256(upload.access_password.as_bytes());
+    let hash = transfer::auth::bcrypt_hash(10, &sha, &salt).unwrap();
+    server
+        .block_on(async {
+            let conn = server.ctx.db.get().await.unwrap();
+            conn.execute(
+                "update auth set salt = $1, hash = $2, algorithm = 'bcrypt', params = 'cost=10' where id = $3",
+                &[&salt, &hash, &auth.id],
+            )
+            .await
+        })
+        .unwrap();
+
+    // failed attempts leave the old hash in place
+    let mut wrong = Upload::new(b"rehashed content");
+    wrong.access_password = String::from("wrong");
+    assert_eq!(
+        server.download(&init.key, &wrong),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(server.access_auth(&init.key).algorithm, "bcrypt");
+
+    assert!(server.download(&init.key, &upload).is_ok());
+    let auth = server.access_auth(&init.key);
+    assert_eq!(auth.algorithm, "argon2id");
+    assert_ne!(auth.salt, salt);
+
+    // changed parameters are applied the same way
+    server
+        .reload_config(|c| {
+            c.password_hashing = PasswordHashing::Argon2id {
+                memory_kib: 128,
+                iterations: 2,
+                parallelism: 1,
+            }
+        })
+        .expect("reload failed");
+    assert!(server.download(&init.key, &upload).is_ok());
+    assert_eq!(server.access_auth(&init.key).params, "m=128,t=2,p=1");
+    assert!(server.download(&init.key, &upload).is_ok());
+}
+
+#[test]
+fn hashing_timeout() {
+    let server = TestServer::start();
+    server
+        .reload_config(|c| {
+            c.password_hashing = PasswordHashing::Argon2id {
+                memory_kib: 65536,
+                iterations: 8,
+                parallelism: 1,
+            };
+            c.hash_timeout_ms = 1;
+        })
+        .expect("reload failed");
+    let upload = Upload::new(b"slow content");
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::SERVICE_UNAVAILABLE);
+
+    // a route's own timeout overrides `hash_timeout_ms`
+    server
+        .reload_config(|c| {
+            c.hash_route_timeouts_ms
+                .insert(String::from("/api/upload/init"), 60_000);
+        })
+        .expect("reload failed");
+    let init = server.upload(&upload);
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    );
+
+    server
+        .reload_config(|c| c.hash_timeout_ms = 60_000)
+        .expect("reload failed");
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+    assert!(server.download(&init.key, &upload).is_ok());
+}
+
+#[test]
+fn audit_events() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"audited content");
+    let init = server.upload(&upload);
+    assert!(server.download(&init.key, &upload).is_ok());
+
+    let resp = server.post_json_with_headers(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"nope")}),
+        &[("user-agent", "audit-test")],
+    );
+    assert_eq!(resp.status, StatusCode::UNAUTHORIZED);
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"delete")}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+
+    let events = server.audit_events(&init.key);
+    let summary = events
+        .iter()
+        .map(|e| (e.event.as_str(), e.outcome.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            ("download_init", "success"),
+            ("download", "success"),
+            ("download_confirm", "success"),
+            ("upload_delete", "failure"),
+            ("upload_delete", "success"),
+        ]
+    );
+    assert_eq!(events[3].user_agent.as_deref(), Some("audit-test"));
+    assert!(events
+        .iter()
+        .all(|e| e.upload.is_some() && e.bundle.is_none()));
+
+    // append-only
+    server.block_on(async {
+        let conn = server.ctx.db.get().await.unwrap();
+        assert!(conn
+            .execute("update audit_event set outcome = 'success'", &[])
+            .await
+            .is_err());
+        assert!(conn.execute("delete from audit_event", &[]).await.is_err());
+    });
+}
+
+#[test]
+fn api_token_limits() {
+    let server = TestServer::start();
+    let token = server.create_token(models::NewApiToken {
+        name: String::from("limited"),
+        token_hash: vec![],
+        quota_bytes: Some(100),
+        max_file_bytes: Some(80),
+        max_lifespan_secs: Some(60),
+        max_download_limit: Some(2),
+    });
+
+    let mut upload = Upload::new(&[0u8; 40]);
+    upload.api_token = Some(hex::encode(b"unknown token"));
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::UNAUTHORIZED
+    );
+
+    upload.api_token = Some(token.clone());
+    upload.lifespan = Some(120);
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::BAD_REQUEST
+    );
+    upload.lifespan = None;
+    upload.download_limit = Some(5);
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::BAD_REQUEST
+    );
+    upload.download_limit = None;
+
+    let mut too_large = Upload::new(&[0u8; 100]);
+    too_large.api_token = Some(token.clone());
+    assert_eq!(
+        server.init_upload(&too_large).0.status,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    // the first upload uses most of the quota
+    let init = server.upload(&upload);
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"delete")}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    server.upload(&upload);
+
+    server
+        .reload_config(|c| c.require_api_token = true)
+        .expect("reload failed");
+    upload.api_token = None;
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[test]
+fn list_token_uploads() {
+    let server = TestServer::start();
+    let token = server.create_token(models::NewApiToken {
+        name: String::from("owner"),
+        token_hash: vec![],
+        quota_bytes: None,
+        max_file_bytes: None,
+        max_lifespan_secs: None,
+        max_download_limit: None,
+    });
+    let mut upload = Upload::new(b"listed content");
+    upload.api_token = Some(token.clone());
+    upload.download_limit = Some(2);
+    let listed = server.upload(&upload);
+    assert!(server.download(&listed.key, &upload).is_ok());
+    server.upload(&Upload::new(b"anonymous content"));
+
+    let resp = server.get("/api/uploads");
+    assert_eq!(resp.status, StatusCode::UNAUTHORIZED);
+
+    let authorization = format!("Bearer {}", token);
+    let resp = server.get_with_headers("/api/uploads", &[("authorization", &authorization)]);
+    assert_eq!(resp.status, StatusCode::OK);
+    let uploads = resp.json()["uploads"].clone();
+    assert_eq!(uploads.as_array().map(Vec::len), Some(1));
+    let listed_upload = &uploads[0];
+    assert_eq!(listed_upload["key"], json!(listed.key));
+    assert_eq!(listed_upload["size"], json!(listed.encrypted.len()));
+    assert_eq!(listed_upload["download_count"], json!(1));
+    assert_eq!(listed_upload["remaining_downloads"], json!(1));
+    assert_eq!(listed_upload["deletable"], json!(true));
+    assert!(listed_upload.get("nonce").is_none());
+}
+
+#[test]
+fn update_upload_limits() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"updated content");
+    upload.download_limit = Some(1);
+    let init = server.upload(&upload);
+    let update = |password: &[u8], lifespan: Option<i64>, download_limit: Option<u32>| {
+        server.post_json(
+            "/api/upload/update",
+            &json!({
+                "key": &init.key,
+                "deletion_password": hex::encode(password),
+                "lifespan": lifespan,
+                "download_limit": download_limit,
+            }),
+        )
+    };
+
+    assert_eq!(
+        update(b"nope", Some(60), None).status,
+        StatusCode::UNAUTHORIZED
+    );
+    let resp = update(b"delete", Some(60), Some(3));
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.json()["download_limit"], json!(3));
+
+    server
+        .reload_config(|c| {
+            c.upload_lifespan_secs_default = 100;
+            c.upload_lifespan_secs_max = 100;
+        })
+        .expect("reload failed");
+    assert_eq!(
+        update(b"delete", Some(200), None).status,
+        StatusCode::BAD_REQUEST
+    );
+
+    assert!(server.download(&init.key, &upload).is_ok());
+    // can't drop below the downloads already made
+    assert_eq!(
+        update(b"delete", None, Some(1)).status,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(update(b"delete", None, Some(2)).status, StatusCode::OK);
+    assert!(server.download(&init.key, &upload).is_ok());
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn client_limit_bounds() {
+    let server = TestServer::start();
+    server
+        .reload_config(|c| {
+            c.upload_lifespan_secs_min = 10;
+            c.upload_lifespan_secs_max = 1000;
+            c.upload_lifespan_secs_default = 100;
+            c.download_limit_max = Some(3);
+            c.download_limit_default = Some(2);
+            c.allow_unlimited_downloads = false;
+        })
+        .expect("reload failed");
+
+    let resp = server.get("/api/upload/defaults");
+    assert_eq!(resp.status, StatusCode::OK);
+    let defaults = resp.json();
+    assert_eq!(defaults["upload_lifespan_secs_min"], json!(10));
+    assert_eq!(defaults["upload_lifespan_secs_max"], json!(1000));
+    assert_eq!(defaults["download_limit_max"], json!(3));
+    assert_eq!(defaults["allow_unlimited_downloads"], json!(false));
+
+    let mut upload = Upload::new(b"bounded content");
+    for lifespan in &[5, 1001] {
+        upload.lifespan = Some(*lifespan);
+        assert_eq!(
+            server.init_upload(&upload).0.status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    upload.lifespan = Some(1000);
+    upload.download_limit = None;
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::BAD_REQUEST
+    );
+    upload.download_limit = Some(4);
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::BAD_REQUEST
+    );
+    upload.download_limit = Some(0);
+    assert_eq!(
+        server.init_upload(&upload).0.status,
+        StatusCode::BAD_REQUEST
+    );
+    upload.download_limit = Some(3);
+    assert_eq!(server.init_upload(&upload).0.status, StatusCode::OK);
+
+    // invalid bounds are rejected on reload
+    assert!(server
+        .reload_config(|c| c.download_limit_default = None)
+        .is_err());
+}
+
+#[test]
+fn upload_webhooks() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"webhook content");
+    upload.webhook_url = Some(String::from("http://127.0.0.1:1/hook"));
+    upload.webhook_secret = Some(String::from("secret"));
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+
+    server
+        .reload_config(|c| c.allow_upload_webhooks = true)
+        .expect("reload failed");
+    upload.webhook_secret = None;
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+
+    // internal targets are refused unless their host is allowed
+    upload.webhook_secret = Some(String::from("secret"));
+    for url in &[
+        "http://127.0.0.1:1/hook",
+        "http://localhost/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.1/hook",
+        "http://192.168.1.1/hook",
+        "http://[::1]/hook",
+        "http://[::ffff:172.16.0.1]/hook",
+        "http://[64:ff9b::a9fe:a9fe]/hook",
+        "http://[::127.0.0.1]/hook",
+    ] {
+        upload.webhook_url = Some(url.to_string());
+        let (resp, _) = server.init_upload(&upload);
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST, "{}", url);
+    }
+    server
+        .reload_config(|c| c.webhook_allowed_hosts = vec![String::from("127.0.0.1")])
+        .expect("reload failed");
+    upload.webhook_url = Some(String::from("http://127.0.0.1:1/hook"));
+    let init = server.upload(&upload);
+    assert!(server.download(&init.key, &upload).is_ok());
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"delete")}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+
+    let events = server.webhook_events();
+    let names = events.iter().map(|e| e.event.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["download", "download_confirmed", "deleted"]);
+    let payload: serde_json::Value = serde_json::from_str(&events[0].payload).unwrap();
+    assert_eq!(payload["upload"], json!(&init.key));
+    assert_eq!(events[0].secret, b"secret".to_vec());
+
+    // nothing is listening, so every delivery fails and is rescheduled
+    assert_eq!(server.deliver_webhooks().expect("delivery failed"), 0);
+    for event in server.webhook_events() {
+        assert_eq!(event.attempts, 1);
+        assert!(event.last_error.is_some());
+        assert!(!event.delivered && !event.failed);
+        assert!(event.next_attempt > event.date_created);
+    }
+}
+
+#[test]
+fn download_limit() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"limited content");
+    upload.download_limit = Some(1);
+    let init = server.upload(&upload);
+
+    assert!(server.download(&init.key, &upload).is_ok());
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn download_limit_reached_after_init() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"limited content");
+    upload.download_limit = Some(1);
+    let init = server.upload(&upload);
+
+    // both download keys are initialized before the limit is used up
+    let access_password = hex::encode(upload.access_password.as_bytes());
+    let download_keys = (0..2)
+        .map(|_| {
+            let resp = server.post_json(
+                "/api/download/init",
+                &json!({"key": &init.key, "access_password": &access_password}),
+            );
+            assert_eq!(resp.status, StatusCode::OK);
+            resp.json()["download_key"].clone()
+        })
+        .collect::<Vec<_>>();
+
+    let resp = server.post_json(
+        "/api/download",
+        &json!({"key": &download_keys[0], "access_password": &access_password}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    let resp = server.post_json(
+        "/api/download",
+        &json!({"key": &download_keys[1], "access_password": &access_password}),
+    );
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(resp.json()["code"], "download_limit_reached");
+}
+
+#[test]
+fn expired_upload() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"short lived content");
+    upload.lifespan = Some(1);
+    let init = server.upload(&upload);
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn sweep_unfinished_upload_keeps_status_and_token_totals() {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let mut server = TestServer::start();
+    let token = server.create_token(models::NewApiToken {
+        name: String::from("unfinished"),
+        token_hash: vec![],
+        quota_bytes: Some(1000),
+        max_file_bytes: None,
+        max_lifespan_secs: None,
+        max_download_limit: None,
+    });
+    let mut kept = Upload::new(b"kept content");
+    kept.api_token = Some(token.clone());
+    server.upload(&kept);
+    let mut upload = Upload::new(b"content that never finishes uploading");
+    upload.api_token = Some(token.clone());
+    upload.lifespan = Some(1);
+    let (resp, init) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+    let addr = server.serve();
+    let totals = || {
+        let token_hash = transfer::auth::sha256(&hex::decode(&token).unwrap());
+        let (status, token) = server.block_on(async {
+            let conn = server.ctx.db.get().await.unwrap();
+            let status = models::Status::load(&conn).await.unwrap();
+            let token = models::ApiToken::find_by_hash(&conn, &token_hash)
+                .await
+                .unwrap();
+            (status, token)
+        });
+        (
+            status.upload_count(),
+            status.total_bytes(),
+            token.upload_count,
+            token.total_bytes,
+        )
+    };
+    let before = totals();
+    assert_eq!(before.2, 1);
+
+    let mut stream = TcpStream::connect(addr.trim_start_matches("http://")).unwrap();
+    write!(
+        stream,
+        "POST /api/upload?key={} HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        init.key,
+        init.encrypted.len()
+    )
+    .unwrap();
+    stream.write_all(&init.encrypted[..5]).unwrap();
+    stream.flush().unwrap();
+
+    // the upload is recorded before its bytes arrive, but isn't available
+    let uuid = Uuid::parse_str(&init.key).unwrap();
+    let recorded = (0..50).any(|_| {
+        let found = server.block_on(async {
+            let conn = server.ctx.db.get().await.unwrap();
+            models::Upload::find_unfinalized(&conn, &uuid).await
+        });
+        if found.is_ok() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+        false
+    });
+    assert!(recorded, "unfinished upload wasn't recorded");
+    assert!(!server.upload_exists(&init.key));
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+
+    thread::sleep(Duration::from_millis(1500));
+    server.sweep();
+    let found = server.block_on(async {
+        let conn = server.ctx.db.get().await.unwrap();
+        models::Upload::find_unfinalized(&conn, &uuid).await
+    });
+    match found {
+        Err(ref e) if e.is_does_not_exist() => (),
+        _ => panic!("unfinished upload wasn't swept"),
+    }
+    assert_eq!(totals(), before);
+}
+
+#[test]
+fn sweep_expired_bundle() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"");
+    upload.lifespan = Some(1);
+    let (_, members) = server.upload_bundle(&upload, &[b"first member", b"second member"]);
+
+    server.sweep();
+    assert!(members.iter().all(|m| server.upload_exists(&m.key)));
+
+    thread::sleep(Duration::from_millis(1500));
+    server.sweep();
+    assert!(members.iter().all(|m| !server.upload_exists(&m.key)));
+}
+
+#[test]
+fn sweep_bundle_keeps_active_downloads() {
+    let server = TestServer::start();
+    let mut upload = Upload::new(b"");
+    upload.download_limit = Some(1);
+    let (key, members) = server.upload_bundle(&upload, &[b"first member", b"second member"]);
+
+    let access_password = hex::encode(upload.access_password.as_bytes());
+    let body = json!({"key": &key, "access_password": &access_password});
+    let resp = server.post_json("/api/bundle/download/init", &body);
+    assert_eq!(resp.status, StatusCode::OK);
+    let download_keys = resp.json()["members"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|member| member["download_key"].clone())
+        .collect::<Vec<_>>();
+    let resp = server.post_json("/api/bundle/download/init", &body);
+    assert_eq!(resp.json()["code"], "download_limit_reached");
+
+    // the counted download is still in progress
+    server.sweep();
+    for (member, download_key) in members.iter().zip(&download_keys) {
+        let resp = server.post_json(
+            "/api/download",
+            &json!({"key": download_key, "access_password": &access_password}),
+        );
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.body, member.encrypted);
+    }
+
+    // completed downloads use up their keys
+    server.sweep();
+    assert!(members.iter().all(|m| !server.upload_exists(&m.key)));
+}
+
+#[test]
+fn sweep_orphaned_files() {
+    let server = TestServer::start_with(|c| c.storage = StorageConfig::Sharded { depth: 1 });
+    let upload = Upload::new(b"kept content");
+    let init = server.upload(&upload);
+    let (_, pending) = server.init_upload(&upload);
+    let staged = server.upload_dir.join(&pending.key);
+    fs::write(&staged, b"partial").unwrap();
+
+    let orphan_key = || hex::encode(Uuid::new_v4().as_bytes());
+    let key = orphan_key();
+    let orphans = vec![
+        server.upload_dir.join(&key[..2]).join(&key),
+        server.upload_dir.join(orphan_key()),
+        server.upload_dir.join(format!("{}.enc", orphan_key())),
+    ];
+    fs::create_dir_all(orphans[0].parent().unwrap()).unwrap();
+    for orphan in &orphans {
+        fs::write(orphan, b"orphaned").unwrap();
+    }
+    let unrelated = server.upload_dir.join("notes.txt");
+    fs::write(&unrelated, b"not an upload").unwrap();
+
+    assert_eq!(server.sweep_files(), orphans.len() as u64);
+    assert!(orphans.iter().all(|orphan| !orphan.exists()));
+    assert!(staged.exists());
+    assert!(unrelated.exists());
+    assert_eq!(server.download(&init.key, &upload).unwrap(), upload.data);
+}
+
+#[test]
+fn sharded_storage() {
+    let server = TestServer::start_with(|c| c.storage = StorageConfig::Sharded { depth: 2 });
+    let upload = Upload::new(b"sharded content");
+    let init = server.upload(&upload);
+    let stored = server
+        .upload_dir
+        .join(&init.key[..2])
+        .join(&init.key[2..4])
+        .join(&init.key);
+    assert_eq!(fs::read(&stored).unwrap(), init.encrypted);
+    assert!(!server.upload_dir.join(&init.key).exists());
+
+    let resp = server.download_range(&init.key, &upload, "bytes=2-5");
+    assert_eq!(resp.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.body, &init.encrypted[2..6]);
+    assert_eq!(server.download(&init.key, &upload).unwrap(), upload.data);
+
+    let resp = server.post_json(
+        "/api/upload/delete",
+        &json!({"key": &init.key, "deletion_password": hex::encode(b"delete")}),
+    );
+    assert_eq!(resp.status, StatusCode::OK);
+    assert!(!stored.exists());
+}
+
+#[test]
+fn encrypted_storage() {
+    // large enough to span several encryption segments
+    let server = TestServer::start_with(|c| {
+        c.encryption_key_file = Some(String::from("keys.json"));
+        c.upload_limit_bytes = 200_000;
+        c.max_combined_upload_bytes = 1_000_000;
+    });
+    let data = (0..150_000u32).map(|n| n as u8).collect::<Vec<_>>();
+    let upload = Upload::new(&data);
+    let init = server.upload(&upload);
+    let stored = fs::read(server.upload_dir.join(&init.key)).unwrap();
+    assert!(stored.len() > init.encrypted.len());
+    assert_ne!(&stored[..64], &init.encrypted[..64]);
+
+    // a range crossing the first segment boundary
+    let start = 64 * 1024 - 10;
+    let resp = server.download_range(
+        &init.key,
+        &upload,
+        &format!("bytes={}-{}", start, start + 19),
+    );
+    assert_eq!(resp.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.body, &init.encrypted[start..start + 20]);
+    assert_eq!(server.download(&init.key, &upload).unwrap(), upload.data);
+}
+
+#[test]
+fn client_upload_download() {
+    let mut server = TestServer::start();
+    let client = Client::new(&server.serve());
+    let opts = UploadOptions {
+        access_password: String::from("access"),
+        encrypt_password: String::from("encrypt"),
+        deletion_password: None,
+        download_limit: Some(1),
+        lifespan: None,
+        api_token: None,
+    };
+    let uploaded = client
+        .upload("notes.txt", b"client content", &opts)
+        .expect("upload failed");
+
+    let err = client
+        .download(&uploaded.key, "wrong", "encrypt")
+        .err()
+        .expect("download with a wrong password succeeded");
+    assert!(matches!(err.kind(), ErrorKind::InvalidAuth(_)));
+
+    let downloaded = client
+        .download(&uploaded.key, "access", "encrypt")
+        .expect("download failed");
+    assert_eq!(downloaded.file_name, "notes.txt");
+    assert_eq!(downloaded.data, b"client content");
+
+    let err = client
+        .download(&uploaded.key, "access", "encrypt")
+        .err()
+        .expect("download over the limit succeeded");
+    assert!(matches!(err.kind(), ErrorKind::DownloadLimitReached(_)));
+}
+
+#[test]
+fn error_codes() {
+    let server = TestServer::start();
+    let download_init = |key: &str, pass: &str| {
+        server.post_json(
+            "/api/download/init",
+            &json!({"key": key, "access_password": hex::encode(pass.as_bytes())}),
+        )
+    };
+
+    let resp = download_init("00112233445566778899aabbccddeeff", "access");
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(resp.json()["code"], "not_found");
+
+    let mut upload = Upload::new(b"coded content");
+    upload.download_limit = Some(1);
+    let init = server.upload(&upload);
+    let resp = download_init(&init.key, "nope");
+    assert_eq!(resp.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.json()["code"], "wrong_password");
+
+    assert!(server.download(&init.key, &upload).is_ok());
+    let resp = download_init(&init.key, &upload.access_password);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(resp.json()["code"], "download_limit_reached");
+    // without the password, a used up upload is indistinguishable from a missing one
+    let resp = download_init(&init.key, "nope");
+    assert_eq!(resp.json()["code"], "wrong_password");
+
+    let mut upload = Upload::new(b"short lived content");
+    upload.lifespan = Some(1);
+    let init = server.upload(&upload);
+    thread::sleep(Duration::from_millis(1500));
+    let resp = download_init(&init.key, &upload.access_password);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(resp.json()["code"], "expired");
+
+    server
+        .reload_config(|c| c.auth_rate_limit_per_minute = Some(1))
+        .expect("reload failed");
+    let upload = Upload::new(b"rate limited content");
+    let init = server.upload(&upload);
+    assert_eq!(
+        download_init(&init.key, "nope").status,
+        StatusCode::UNAUTHORIZED
+    );
+    let resp = download_init(&init.key, "nope");
+    assert_eq!(resp.status, StatusCode::TOO_MANY_REQUESTS);
+    let body = resp.json();
+    assert_eq!(body["code"], "rate_limited");
+    let retry_after = body["retry_after"].as_u64().expect("missing retry_after");
+    assert_eq!(
+        resp.headers["retry-after"].to_str().unwrap(),
+        retry_after.to_string()
+    );
+}
+
+#[test]
+fn openapi_spec() {
+    let server = TestServer::start();
+    let resp = server.get("/api/openapi.json");
+    assert_eq!(resp.status, StatusCode::OK);
+    let spec = resp.json();
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    for (path, method) in &[
+        ("/api/upload/init", "post"),
+        ("/api/upload", "post"),
+        ("/api/upload/chunk", "put"),
+        ("/api/upload/progress", "get"),
+        ("/api/uploads", "get"),
+        ("/api/upload/delete", "post"),
+        ("/api/upload/update", "post"),
+        ("/api/download/init", "post"),
+        ("/api/download", "post"),
+        ("/api/download/confirm", "post"),
+        ("/api/bundle/init", "post"),
+        ("/api/bundle/delete", "post"),
+        ("/api/bundle/download/init", "post"),
+        ("/status/ready", "get"),
+    ] {
+        assert!(
+            spec["paths"][path][method].is_object(),
+            "{} {}",
+            method,
+            path
+        );
+    }
+    let metrics = String::from_utf8(server.get("/metrics").body).unwrap();
+    assert!(metrics.contains(r#"route="/api/openapi.json""#));
+
+    // documented fields match the served responses
+    let init_schema = &spec["paths"]["/api/download/init"]["post"]["responses"]["200"]["content"]
+        ["application/json"]["schema"];
+    let mut documented = init_schema["properties"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    let upload = Upload::new(b"documented content");
+    let init = server.upload(&upload);
+    let resp = server.post_json(
+        "/api/download/init",
+        &json!({"key": &init.key, "access_password": hex::encode(upload.access_password.as_bytes())}),
+    );
+    let mut served = resp
+        .json()
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    documented.sort();
+    served.sort();
+    assert_eq!(documented, served);
+
+    let body_schema = &spec["paths"]["/api/upload/init"]["post"]["requestBody"]["content"]
+        ["application/json"]["schema"];
+    let required = body_schema["required"].as_array().unwrap();
+    assert!(required.contains(&json!("access_password")));
+    assert!(!required.contains(&json!("deletion_password")));
+    let errors = &spec["paths"]["/api/download/init"]["post"]["responses"]["404"];
+    assert!(errors["description"]
+        .as_str()
+        .unwrap()
+        .contains("`expired`"));
+}
+
+#[test]
+fn upload_too_large() {
+    let server = TestServer::start();
+    let upload = Upload::new(&vec![1; UPLOAD_LIMIT_BYTES + 1]);
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn upload_larger_than_declared() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"content");
+    let (resp, init) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+
+    let mut bytes = init.encrypted.clone();
+    bytes.extend_from_slice(b"extra");
+    let resp = server.post_bytes(&format!("/api/upload?key={}", init.key), &bytes);
+    assert_eq!(resp.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // the failed upload is cleaned up
+    assert_eq!(
+        server.download(&init.key, &upload),
+        Err(StatusCode::NOT_FOUND)
+    );
+}
+
+#[test]
+fn out_of_space() {
+    let server = TestServer::start();
+    let size = MAX_COMBINED_UPLOAD_BYTES / 3;
+    let upload = Upload::new(&vec![1; size]);
+    server.upload(&upload);
+    server.upload(&upload);
+
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn reload_config() {
+    let server = TestServer::start();
+    let changes = server
+        .reload_config(|config| config.upload_limit_bytes = 512)
+        .expect("reload failed");
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].setting, "upload_limit_bytes");
+    assert_eq!(changes[0].new, "512");
+
+    let resp = server.get("/api/upload/defaults");
+    assert_eq!(resp.json()["upload_limit_bytes"], json!(512));
+    let upload = Upload::new(&vec![1; 600]);
+    let (resp, _) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // invalid configs are rejected, keeping the current config
+    assert!(server
+        .reload_config(|config| config.upload_limit_bytes = 0)
+        .is_err());
+    assert_eq!(server.ctx.config.get().upload_limit_bytes, 512);
+
+    // startup settings are ignored
+    let changes = server
+        .reload_config(|config| config.port = 4000)
+        .expect("reload failed");
+    assert!(changes.is_empty());
+    assert_eq!(server.ctx.config.get().port, 0);
+
+    // and aren't validated, which would create the new upload directory
+    let moved = server.upload_dir.with_extension("moved");
+    let changes = server
+        .reload_config(|config| config.upload_directory = moved.to_string_lossy().into_owned())
+        .expect("reload failed");
+    assert!(changes.is_empty());
+    assert!(!moved.exists());
+}
+
+#[test]
+fn shutdown_refuses_transfers() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"content");
+    let done = server.upload(&upload);
+    let (resp, init) = server.init_upload(&upload);
+    assert_eq!(resp.status, StatusCode::OK);
+
+    server.ctx.shutdown.request();
+    let resp = server.post_bytes(&format!("/api/upload?key={}", init.key), &init.encrypted);
+    assert_eq!(resp.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        server.download(&done.key, &upload),
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    );
+    assert!(server
+        .block_on(server.ctx.shutdown.wait_idle(Duration::from_secs(1)))
+        .is_empty());
+}
+
+#[test]
+fn metrics() {
+    let server = TestServer::start();
+    let upload = Upload::new(b"measured content");
+    let init = server.upload(&upload);
+    let mut wrong = Upload::new(b"measured content");
+    wrong.access_password = String::from("wrong");
+    assert_eq!(
+        server.download(&init.key, &wrong),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+
+    let resp = server.get("/metrics");
+    assert_eq!(resp.status, StatusCode::OK);
+    let body = String::from_utf8(resp.body).unwrap();
+    assert!(body.contains("transfer_stored_uploads 1"));
+    assert!(body.contains(&format!(
+        "transfer_max_stored_bytes {}",
+        MAX_COMBINED_UPLOAD_BYTES
+    )));
+    assert!(body.contains(r#"transfer_auth_failures_total{route="/api/download/init"}"#));
+    assert!(body.contains("transfer_uploaded_bytes_total"));
+}
+
+#[test]
+fn status_live_and_ready() {
+    let server = TestServer::start();
+    let resp = server.get("/status/live");
+    assert_eq!(resp.status, StatusCode::OK);
+    assert_eq!(resp.json()["status"], json!("ok"));
+
+    server.sweep();
+    let resp = server.get("/status/ready");
+    let checks = resp.json()["checks"].clone();
+    assert_eq!(checks["database"]["ok"], json!(true));
+    assert_eq!(checks["storage"]["ok"], json!(true));
+    assert_eq!(checks["sweeper"]["ok"], json!(true));
+    assert_eq!(checks["storage"]["details"]["backend"], json!("local"));
+    // migrations are checked against `Migrant.toml`, which the harness doesn't manage
+    let ready = checks
+        .as_object()
+        .unwrap()
+        .values()
+        .all(|check| check["ok"] == json!(true));
+    assert_eq!(resp.status == StatusCode::OK, ready);
+}